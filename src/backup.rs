@@ -1,20 +1,26 @@
-use crate::config::Config;
+use crate::config::{BackupOptions, Config, HistoryMode};
+use crate::copy::{self, CopyMethod, CopyStats, Extents};
+use crate::engine::BackupEngine;
+use crate::error::{is_out_of_space, Error, IoResultExt, Result};
+use crate::exclude;
+use crate::history::{self, DeltaOptions};
 use crate::journal;
-use crate::utils::normalize_path;
-use crate::state::load_or_init_state;
+use crate::observer::{BackupObserver, BackupPlan};
+use crate::roots;
+use crate::utils::{parse_history_name, parse_snapshot_name};
+use crate::state::{BackupState, LatestBackup, LinkGroup};
+use crate::storage::{self, StorageBackend};
+use crate::logging;
 use serde::{Deserialize, Serialize};
-use anyhow::{bail, Context, Result};
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::time::Instant;
 use std::time::SystemTime;
 use std::time::Duration;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use chrono::{DateTime, Local};
-use chrono::{NaiveDateTime};
-use chrono::TimeZone;
-use indicatif::{ProgressBar, ProgressStyle};
-use regex::Regex;
+use log::{debug, error, info, warn};
 use walkdir::WalkDir;
 
 
@@ -23,7 +29,7 @@ pub struct Status {
     pub state: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct FileList {
    pub files: Vec<PathBuf>,
 }
@@ -32,11 +38,6 @@ impl FromIterator<PathBuf> for FileList {
         FileList { files: iter.into_iter().collect() }
     }
 }
-impl Default for FileList {
-    fn default() -> Self {
-        FileList { files: Vec::new() }
-    }
-}
 #[derive(Serialize, Deserialize)]
 pub struct TempBackup {
     pub status: Status,
@@ -47,11 +48,136 @@ pub struct TempBackup {
     pub removed: FileList,
     #[serde(default)]
     pub bytes_copied: u64,
+    /// Length of the copied files including holes
+    #[serde(default)]
+    pub logical_bytes: u64,
+    /// Bytes not sent thanks to delta transfers
+    #[serde(default)]
+    pub bytes_saved: u64,
+    /// Files copied with each copy method, across resumed runs
+    #[serde(default)]
+    pub copy_methods: CopyStats,
+    /// Link groups carried over from earlier runs and extended by this one
+    #[serde(default)]
+    pub links: Vec<LinkGroup>,
+    /// Extents of the sparse files stored without holes, updated as files
+    /// are stored, moved to `History` and deleted
+    #[serde(default)]
+    pub sparse: BTreeMap<PathBuf, Extents>,
     pub duration:  Duration,
     pub timestamp: DateTime<Local>,
     pub snapshot_id: u64,
 }
 
+/// A file discovered by `scan`.
+#[derive(Debug, Serialize)]
+pub struct ScannedFile {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Result of a `scan` run.
+#[derive(Debug, Serialize)]
+pub struct ScanReport {
+    pub since: DateTime<Local>,
+    pub files: Vec<ScannedFile>,
+    pub total_files: u64,
+    pub total_bytes: u64,
+    /// Files and directories left out by exclude rules
+    pub skipped_files: u64,
+    /// Bytes the skipped files and directories would have taken
+    pub skipped_bytes: u64,
+    /// Skipped entries per rule source, largest first
+    pub skipped_by_source: Vec<SkipSummary>,
+    /// Mount points not entered because of `one_file_system`
+    pub mount_points: Vec<PathBuf>,
+}
+
+/// What one source of exclude rules (`paths.exclude`, `paths.max_file_size`,
+/// an ignore file, ...) left out during a scan.
+#[derive(Debug, Serialize)]
+pub struct SkipSummary {
+    pub source: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// What happened to a single file during a backup run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileAction {
+    /// The file was copied to the destination
+    Copied,
+    /// The file was already completed by an interrupted run
+    Skipped,
+    /// The file is another hard link to a source inode that is already stored
+    /// and was linked to that copy, or only recorded where links are not possible
+    Linked,
+    /// The file could not be backed up
+    Failed,
+    /// The file no longer exists in the source and was moved to `History`
+    Removed,
+}
+
+/// Per-file outcome of a backup run.
+#[derive(Debug, Serialize)]
+pub struct FileResult {
+    pub path: PathBuf,
+    pub action: FileAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Result of a backup run.
+#[derive(Debug, Serialize)]
+pub struct BackupReport {
+    pub snapshot_id: u64,
+    pub resumed: bool,
+    pub files: Vec<FileResult>,
+    pub files_copied: u64,
+    /// Files stored as hard links to another stored copy
+    pub files_linked: u64,
+    pub files_failed: u64,
+    pub files_removed: u64,
+    /// Data moved, without the holes of sparse files
+    pub bytes_copied: u64,
+    /// Length of the copied files including holes
+    pub logical_bytes: u64,
+    /// Bytes of modified files rebuilt from their earlier copy instead of sent
+    pub bytes_saved: u64,
+    /// Files copied with each copy method
+    pub copy_methods: CopyStats,
+    pub duration_ms: u64,
+    pub history_mode: HistoryMode,
+    /// Mode of the previous run if it differs from `history_mode`
+    pub previous_history_mode: Option<HistoryMode>,
+    /// Snapshot tree created by [`HistoryMode::HardlinkSnapshots`]
+    pub snapshot_path: Option<PathBuf>,
+}
+
+/// Result of a vacuum run.
+#[derive(Debug, Default, Serialize)]
+pub struct VacuumReport {
+    pub pruned: Vec<PathBuf>,
+    pub files_removed: u64,
+    /// Snapshot trees deleted as a whole
+    pub snapshots_removed: Vec<PathBuf>,
+    /// Unfinished uploads of crashed runs that were aborted
+    pub uploads_discarded: Vec<PathBuf>,
+}
+
+/// Result of the `status` command.
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    pub state: String,
+    pub latest: Option<LatestBackup>,
+    /// Trailing lines of the latest run's log file
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub log_tail: Vec<String>,
+}
+
 impl TempBackup {
     fn new(files: Vec<PathBuf>, removed: Vec<PathBuf>) -> Self {
         Self {
@@ -63,123 +189,207 @@ impl TempBackup {
             failed: FileList { files: Vec::new() },
             removed: FileList { files: removed },
             bytes_copied: 0,
+            logical_bytes: 0,
+            bytes_saved: 0,
+            copy_methods: CopyStats::default(),
+            links: Vec::new(),
+            sparse: BTreeMap::new(),
             duration: Duration::ZERO,
             timestamp: DateTime::<Local>::from(SystemTime::UNIX_EPOCH),
             snapshot_id: 0,
         }
     }
 
-    fn load(path: &Path) -> anyhow::Result<Self> {
-        let data = fs::read_to_string(path)?;
-        Ok(toml::from_str(&data)?)
+    fn save(&self, storage: &dyn StorageBackend, key: &Path) -> Result<()> {
+        storage::write_toml(storage, key, self)
+    }
+}
+
+/// Key of `state.toml` in the destination.
+pub(crate) const STATE_KEY: &str = "state.toml";
+/// Lock held by a running backup, so two runs never write one destination.
+const LOCK_KEY: &str = ".lock";
+
+/// Progress file of an interrupted backup. Each named job gets its own file so
+/// jobs sharing a destination never resume each other's runs.
+pub fn progress_file(dest: &Path, job: Option<&str>) -> PathBuf {
+    dest.join(progress_key(job))
+}
+
+fn progress_key(job: Option<&str>) -> PathBuf {
+    match job {
+        Some(name) => PathBuf::from(format!(".incomplete-{}", name)),
+        None => PathBuf::from(".incomplete"),
     }
+}
 
-    fn save(&self, path: &Path) -> anyhow::Result<()> {
-        let data = toml::to_string_pretty(self)?;
-        fs::write(path, data)?;
-        Ok(())
+/// Load `state.toml` from `storage`, writing an empty state if there is none.
+fn load_state(storage: &dyn StorageBackend) -> Result<BackupState> {
+    let key = Path::new(STATE_KEY);
+    if let Some(state) = storage::read_toml(storage, key)? {
+        return Ok(state);
     }
+    let state = BackupState::default();
+    storage::write_toml(storage, key, &state)?;
+    Ok(state)
 }
 
-/// Scan and print all files under the configured include paths.
+/// Scan all files under the configured include paths for changes.
 ///
 /// Entries matching any of the configured exclude patterns will be skipped.
-pub fn scan(config: &Config, fullscan: bool) -> anyhow::Result<()> {
-    eprintln!("scanning directories...");
+pub fn scan(config: &Config, fullscan: bool) -> Result<ScanReport> {
+    BackupEngine::new(config).scan(fullscan)
+}
+
+pub(crate) fn execute_scan(engine: &BackupEngine, fullscan: bool) -> Result<ScanReport> {
+    let config = engine.config();
+    info!("scanning directories...");
         
-    let includes: Vec<PathBuf> = config
-        .paths
-        .include
-        .iter()
-        .map(PathBuf::from)
+    let storage = engine.storage(false)?;
+    let mut state = load_state(&*storage)?;
+    let last_backup = state.namespace_mut(config.job.as_deref()).latest.timestamp;
+    let since: SystemTime = last_backup.into();
+
+    let dest_root = storage.location().to_path_buf();
+    let roots = roots::map_roots_in(&*storage, &config.paths.include, false)?;
+    let stored = fullscan.then_some(&*storage);
+    let changed = journal::changed_files_observed(since, &roots, &config.paths, &dest_root, stored, engine.observer(), engine.cancellation())?;
+    let files: Vec<ScannedFile> = changed
+        .files
+        .into_iter()
+        .map(|path| {
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            ScannedFile { path, size }
+        })
         .collect();
-    
-    let state_path = PathBuf::from(&config.backup.destination).join("state.toml");
-    let state = load_or_init_state(&state_path)?;
-    let since: SystemTime = state.latest.timestamp.into();
-
-    let dest_root = PathBuf::from(&config.backup.destination);
-    let files = journal::changed_files(since, &includes, &config.paths.exclude, &dest_root, fullscan)?;
-    let total_bytes: u64 = files
-        .iter()
-        .filter_map(|p| fs::metadata(p).ok().map(|m| m.len()))
-        .sum();
-    let total_mb = total_bytes as f64 / (1024.0 * 1024.0);
-
-
-
-    for f in &files {
-        println!("{}", f.display());
+    let total_bytes: u64 = files.iter().map(|f| f.size).sum();
+
+    let mut by_source: BTreeMap<String, SkipSummary> = BTreeMap::new();
+    let mut mount_points = Vec::new();
+    for skipped in &changed.skipped {
+        let source = skipped.rule.as_ref().map_or_else(String::new, |r| r.source.clone());
+        // A nested root is scanned on its own, so nothing is actually skipped
+        if source == exclude::NESTED_ROOT {
+            continue;
+        }
+        // Never walk another file system or the backup itself just to size it
+        let size = if source == exclude::ONE_FILE_SYSTEM {
+            mount_points.push(skipped.path.clone());
+            0
+        } else if source == exclude::DESTINATION {
+            0
+        } else if skipped.is_dir {
+            exclude::dir_size(&skipped.path)
+        } else {
+            skipped.size
+        };
+        let summary = by_source.entry(source.clone()).or_insert(SkipSummary { source, files: 0, bytes: 0 });
+        summary.files += 1;
+        summary.bytes += size;
     }
-    println!(
-        "Scan complete: Found {} files / ({:.2} MB) changed since {}",
-        files.len(),total_mb,
-        state.latest.timestamp
-    );
-
-    Ok(())
+    let mut skipped_by_source: Vec<SkipSummary> = by_source.into_values().collect();
+    skipped_by_source.sort_by_key(|s| std::cmp::Reverse(s.bytes));
+
+    Ok(ScanReport {
+        since: last_backup,
+        total_files: files.len() as u64,
+        total_bytes,
+        files,
+        skipped_files: skipped_by_source.iter().map(|s| s.files).sum(),
+        skipped_bytes: skipped_by_source.iter().map(|s| s.bytes).sum(),
+        skipped_by_source,
+        mount_points,
+    })
 }
 
 
 /// Run the backup operation.
-pub fn run_backup(config: &Config) -> Result<()> {
+pub fn run_backup(config: &Config) -> Result<BackupReport> {
+    BackupEngine::new(config).backup()
+}
+
+pub(crate) fn execute_backup(engine: &BackupEngine) -> Result<BackupReport> {
+    let config = engine.config();
+    let observer = engine.observer();
+    let cancel = engine.cancellation();
     let start_time = Instant::now();
-    let dest = PathBuf::from(&config.backup.destination);
-     eprintln!("Starting backup...");
+    info!("Starting backup...");
         
-    // Ensure the destination directory exists or create it
-    if !dest.exists() {
-        fs::create_dir_all(&dest)
-            .with_context(|| format!("Failed to create or access backup destination. Is the network drive mounted? Path: {}", dest.display()))?;
-    }
-
-    // Now canonicalize the existing directory
-    let dest = dest.canonicalize().context("Invalid or inaccessible backup destination path")?;
-    if !dest.is_dir() {
-        bail!("Backup destination must be a directory: {}", dest.display());
+    // Ensure the destination exists; a local directory is created and canonicalized
+    let storage = engine.storage(true)?;
+    let store = &*storage;
+    let dest = store.location().to_path_buf();
+    // Hard links, reflinks and deltas need the destination on a local file system
+    let local = store.local_root().is_some();
+    let _lock = store.lock(Path::new(LOCK_KEY)).map_err(|source| {
+        let path = store.display_path(Path::new(LOCK_KEY));
+        match source.kind() {
+            std::io::ErrorKind::WouldBlock => Error::DestinationLocked { path },
+            _ => Error::DestinationUnavailable { path, source },
+        }
+    })?;
+
+    let mode = config.backup.history_mode();
+    if mode == HistoryMode::HardlinkSnapshots && !local {
+        return Err(Error::InvalidArgument(format!(
+            "history_mode = \"{}\" needs a destination on a local file system, {} is not",
+            mode,
+            dest.display()
+        )));
     }
 
     // Determine files that no longer exist in the source and need to be moved
     // to the History folder. The actual moving is done later so we can include
     // them in the progress bar and statistics.
-    let current_removed = journal::find_removed_files(&dest, config)?;
+    let roots = roots::map_roots_in(store, &config.paths.include, true)?;
+    let job = config.job.as_deref();
+    let mut state = load_state(store)?;
+    let adopted = adopt_nested_copies(store, &roots)?;
+    let previous = state.namespace_mut(job);
+    if adopted.rekey(&mut previous.sparse, &mut previous.links) {
+        storage::write_toml(store, Path::new(STATE_KEY), &state)?;
+    }
+    let current_removed: Vec<PathBuf> = journal::removed_files(store, &roots)?
+        .into_iter()
+        .map(|key| dest.join(key))
+        .collect();
 
-    let state_file = dest.join("state.toml");
-    let mut state = load_or_init_state(&state_file)?;
-    let since: SystemTime = state.latest.timestamp.into();
+    let since: SystemTime = state.namespace_mut(job).latest.timestamp.into();
+
+    let copy_options = config.backup.copy_options();
+    let delta_options = config.backup.delta_options().filter(|_| local);
+    let delta_transfer_min = config.backup.delta_transfer_min_size().filter(|_| local);
+    let signatures = config.backup.signature_cache().filter(|_| delta_transfer_min.is_some());
+    let previous_mode = state.namespace_mut(job).history_mode.filter(|m| *m != mode);
+    if let Some(previous) = previous_mode {
+        let kept = match previous {
+            HistoryMode::None => "nothing was kept before",
+            HistoryMode::Versions => "the existing History is kept and still pruned by vacuum",
+            HistoryMode::HardlinkSnapshots => "the existing snapshots are kept",
+        };
+        warn!("History mode changed from {} to {}; {}", previous, mode, kept);
+    }
 
     // Create path to progress file
-    let temp_state_file = dest.join(".incomplete");
-
-    let mut progress = if temp_state_file.exists() {
-        let tmp = TempBackup::load(&temp_state_file)?;
-        if tmp.status.state == "in_progress" {
-            println!(
-                "Resuming previous backup from {}",
-                temp_state_file.display()
-            );
+    let progress_key = progress_key(job);
+    let temp_state_file = dest.join(&progress_key);
+
+    let mut resumed = false;
+    let mut progress = match storage::read_toml::<TempBackup>(store, &progress_key)? {
+        Some(mut tmp) if tmp.status.state == "in_progress" => {
+            resumed = true;
+            info!("Resuming previous backup from {}", temp_state_file.display());
+            adopted.rekey(&mut tmp.sparse, &mut tmp.links);
             tmp
-        } else {
-            let includes: Vec<PathBuf> = config
-                .paths
-                .include
-                .iter()
-                .map(PathBuf::from)
-                .collect();
-            let dest_root = PathBuf::from(&config.backup.destination);
-            let changed = journal::changed_files(since, &includes, &config.paths.exclude, &dest_root, true)?;
-            TempBackup::new(changed, current_removed.clone())
         }
-    } else {
-        let includes: Vec<PathBuf> = config
-            .paths
-            .include
-            .iter()
-            .map(PathBuf::from)
-            .collect();
-        let dest_root = PathBuf::from(&config.backup.destination);
-        let  changed = journal::changed_files(since, &includes, &config.paths.exclude, &dest_root, true)?;
-        TempBackup::new(changed, current_removed.clone())
+        _ => {
+            let mut changed = journal::changed_files_observed(since, &roots, &config.paths, &dest, Some(store), observer, cancel)?.files;
+            let links = carry_over_links(&state.namespace_mut(job).links, &roots, local, &mut changed);
+            let mut progress = TempBackup::new(changed, current_removed.clone());
+            progress.links = links;
+            progress.sparse = state.namespace_mut(job).sparse.clone();
+            progress
+        }
     };
 
     // Merge any newly detected removed files with ones already in progress
@@ -192,32 +402,37 @@ pub fn run_backup(config: &Config) -> Result<()> {
     // Set or increment snapshot id based on the previously stored state
     if progress.snapshot_id == 0 {
         let last_id = state
+            .namespace_mut(job)
             .latest
             .snapshot_id
             .parse::<u64>()
             .unwrap_or(0);
         progress.snapshot_id = last_id.saturating_add(1);
     }
-    // Log collected paths before starting any file operations
-    println!("files to update: ");
-    for p in &progress.incomplete.files {
-        println!("{}", p.display());
-    }
-    println!("removed files: ");
-    for removed in &progress.removed.files {
-        println!("{}", removed.display());
-    }
-    println!(
-        "Summary -> files changed: {} removed files: {}",
+
+    let _run_log = if config.logging.file {
+        Some(logging::start_run_log(&storage, job, progress.snapshot_id, &config.logging)?)
+    } else {
+        None
+    };
+    info!(
+        "Snapshot {}: {} changed, {} removed (resumed: {})",
+        progress.snapshot_id,
         progress.incomplete.files.len(),
-        progress.removed.files.len()
+        progress.removed.files.len(),
+        resumed
     );
-    println!("Progress file: {}", temp_state_file.display());
-
-
+    // Report collected paths before starting any file operations
+    observer.backup_started(&BackupPlan {
+        snapshot_id: progress.snapshot_id,
+        resumed,
+        changed: &progress.incomplete.files,
+        removed: &progress.removed.files,
+        progress_file: &temp_state_file,
+    });
 
     // Persist initial progress state so the .incomplete file exists immediately
-    progress.save(&temp_state_file)?;
+    progress.save(store, &progress_key)?;
 
     // todo: build table / dict of all paths so that we don't have to do it while iterating?
 
@@ -226,34 +441,65 @@ pub fn run_backup(config: &Config) -> Result<()> {
     let mut failed: HashSet<PathBuf> = progress.failed.files.iter().cloned().collect();
 
 
-    let total_files = progress.incomplete.files.len() as u64 + progress.removed.files.len() as u64;
-    let pb = ProgressBar::new(total_files);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} files ({eta})")
-            .unwrap()
-            .progress_chars("##-")
-    );
-
+    let mut results: Vec<FileResult> = Vec::new();
+    // Index into `progress.links` of each multiply linked source inode whose
+    // data is already stored
+    let mut stored_links: HashMap<(u64, u64), usize> =
+        progress.links.iter().enumerate().map(|(idx, group)| (group.id(), idx)).collect();
     let mut removed_count = 0u64;
     let mut remaining_removed: Vec<PathBuf> = Vec::new();
-    for removed in std::mem::take(&mut progress.removed.files) {
-        pb.inc(1);
-        pb.set_message(removed.display().to_string());
+    let mut pending_removed = std::mem::take(&mut progress.removed.files).into_iter();
+    while let Some(removed) = pending_removed.next() {
+        if cancel.is_cancelled() {
+            // Keep the unprocessed entries so the resumed run handles them
+            remaining_removed.push(removed);
+            remaining_removed.extend(pending_removed);
+            progress.removed.files = remaining_removed;
+            progress.save(store, &progress_key)?;
+            warn!("Backup cancelled, progress saved to {}", temp_state_file.display());
+            return Err(Error::Cancelled);
+        }
+        observer.file_started(&removed);
 
+        // Without History the file is simply deleted; a snapshot tree still
+        // links it if it was part of an earlier snapshot
         let rel = removed.strip_prefix(&dest).unwrap();
+        if mode != HistoryMode::Versions {
+            match store.delete(rel) {
+                Ok(_) => {
+                    debug!("Deleted removed file {}", removed.display());
+                    progress.sparse.remove(rel);
+                    removed_count += 1;
+                    record(observer, &mut results, FileResult {
+                        path: removed,
+                        action: FileAction::Removed,
+                        bytes: None,
+                        error: None,
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to delete removed file {}: {e}", removed.display());
+                    record(observer, &mut results, FileResult {
+                        path: removed.clone(),
+                        action: FileAction::Failed,
+                        bytes: None,
+                        error: Some(format!("Failed to delete: {e}")),
+                    });
+                    remaining_removed.push(removed);
+                }
+            }
+            progress.removed.files = remaining_removed.clone();
+            progress.save(store, &progress_key)?;
+            continue;
+        }
+
         let mut comps = rel.components();
         let label = comps.next().unwrap().as_os_str();
         let relative = comps.as_path();
 
-        let history_dir = dest
-            .join("History")
+        let history_dir = Path::new("History")
             .join(label)
             .join(relative.parent().unwrap_or_else(|| Path::new("")));
-        if let Err(e) = fs::create_dir_all(&history_dir) {
-            eprintln!("Failed to create history directory: {}: {e}", history_dir.display());
-            continue;
-        }
 
         let timestamp = Local::now().format("%Y-%m-%dT%H-%M-%S");
         let file_stem = removed.file_stem().and_then(|s| s.to_str()).unwrap_or("file");
@@ -262,68 +508,102 @@ pub fn run_backup(config: &Config) -> Result<()> {
             Some(ext) => format!("{}_{}.{}", file_stem, timestamp, ext),
             None => format!("{}_{}", file_stem, timestamp),
         };
-        let history_path = history_dir.join(filename);
-        match fs::rename(&removed, &history_path) {
-            Ok(_) => removed_count += 1,
+        let history_key = history_dir.join(filename);
+        let history_path = dest.join(&history_key);
+        match store.rename(rel, &history_key) {
+            Ok(_) => {
+                debug!("Moved removed file {} to {}", removed.display(), history_path.display());
+                if let Some(extents) = progress.sparse.remove(rel) {
+                    progress.sparse.insert(history_key.clone(), extents);
+                }
+                observer.history_moved(&removed, &history_path);
+                compact_history(&history_path, delta_options.as_ref());
+                removed_count += 1;
+                record(observer, &mut results, FileResult {
+                    path: removed,
+                    action: FileAction::Removed,
+                    bytes: None,
+                    error: None,
+                });
+            }
             Err(e) => {
-                eprintln!("Failed to move removed file to history {}: {e}", removed.display());
+                error!("Failed to move removed file to history {}: {e}", removed.display());
+                record(observer, &mut results, FileResult {
+                    path: removed.clone(),
+                    action: FileAction::Failed,
+                    bytes: None,
+                    error: Some(format!("Failed to move to history: {e}")),
+                });
                 remaining_removed.push(removed);
             }
         }
         progress.removed.files = remaining_removed.clone();
-        progress.save(&temp_state_file)?;
+        progress.save(store, &progress_key)?;
     }
 
 
     for path in &progress.incomplete.files {
-        pb.inc(1);
-        pb.set_message(path.display().to_string());
+        if cancel.is_cancelled() {
+            warn!("Backup cancelled, progress saved to {}", temp_state_file.display());
+            return Err(Error::Cancelled);
+        }
+        observer.file_started(path);
+
+        // Create relative path and normalized root from the first matching include path
+        let (normalized_root, relative) = roots
+            .locate(path)
+            .map(|(m, rel)| (m.folder.as_str(), rel))
+            .unwrap_or(("UnknownSource", path));
+
+        let final_key = Path::new(normalized_root).join(relative);
+        // Backends that only replace an object once its upload completed are
+        // written directly, sparing the rename, a server-side copy on S3
+        let temp_key = match store.atomic_put() {
+            true => final_key.clone(),
+            false => final_key.with_extension("part"),
+        };
+        let final_file = dest.join(&final_key);
+        let temp_file = dest.join(&temp_key);
+        let source_metadata = fs::metadata(path).ok();
+        let link_id = source_metadata.as_ref().and_then(copy::link_id);
+
         if completed.contains(path) {
+            record(observer, &mut results, FileResult {
+                path: path.clone(),
+                action: FileAction::Skipped,
+                bytes: None,
+                error: None,
+            });
             continue;
         }
 
         // Validate source file
         if !path.exists() || !path.is_file() {
-            eprintln!("Skipping invalid path: {}", path.display());
+            warn!("Skipping invalid path: {}", path.display());
             failed.insert(path.clone());
+            record(observer, &mut results, FileResult {
+                path: path.clone(),
+                action: FileAction::Failed,
+                bytes: None,
+                error: Some("Source file is missing or not a regular file".to_string()),
+            });
             continue;
         }
 
-        // Create relative path and normalized root from the first matching include path
-        let (normalized_root, relative) = config
-            .paths
-            .include
-            .iter()
-            .find_map(|root| {
-                let root_path = Path::new(root);
-                path.strip_prefix(root_path).ok().map(|rel| {
-                    let root_label = root_path
-                        .to_string_lossy()
-                        .replace(':', "")
-                        .replace('\\', "-")
-                        .replace('/', "-");
-                    (root_label, rel)
-                })
-            })
-            .unwrap_or_else(|| ("UnknownSource".into(), path));
-
-       let final_file = dest.join(&normalized_root).join(relative);
-        let temp_file = final_file.with_extension("part");
-
         // Ensure parent directory exists
-        if let Some(parent) = final_file.parent() {
-            fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        if let Some(parent) = final_file.parent().filter(|_| local) {
+            fs::create_dir_all(parent).at(parent)?;
         }
 
         
-        // if the target file already exists, move it to history folder, and postfix it with a timestamp before the extension
-        if final_file.exists() {
-            let history_dir = dest
-                .join("History")
-                .join(&normalized_root)
+        // if the target file already exists, move it to history folder, and postfix it with a timestamp before the extension.
+        // In the other modes the rename below replaces it.
+        let stored = store.stat(&final_key).at(&final_file)?.is_some();
+        let mut previous_copy = stored.then(|| final_file.clone());
+        if mode == HistoryMode::Versions && stored {
+            let history_dir = Path::new("History")
+                .join(normalized_root)
                 .join(relative.parent().unwrap_or_else(|| Path::new("")));
-            fs::create_dir_all(&history_dir)
-                .with_context(|| format!("Failed to create history directory: {}", history_dir.display()))?;
 
             let timestamp = Local::now().format("%Y-%m-%dT%H-%M-%S");
 
@@ -339,150 +619,643 @@ pub fn run_backup(config: &Config) -> Result<()> {
                 None => format!("{}_{}", file_stem, timestamp),
             };
 
-            let history_path = history_dir.join(filename);
-            fs::rename(&final_file, &history_path)
-                .with_context(|| format!("Failed to move existing file to history: {}", history_path.display()))?;
+            let history_key = history_dir.join(filename);
+            let history_path = dest.join(&history_key);
+            store.rename(&final_key, &history_key).at(&history_path)?;
+            if let Some(extents) = progress.sparse.remove(&final_key) {
+                progress.sparse.insert(history_key.clone(), extents);
+            }
+            observer.history_moved(&final_file, &history_path);
+            compact_history(&history_path, delta_options.as_ref());
+            previous_copy = Some(history_path);
         }
 
-        // Perform the copy
-        match fs::copy(&path, &temp_file) {
-            Ok(size) => {
+        // Another name of an inode that is already stored becomes a hard link
+        // to that copy instead of a second copy of the data. Other backends
+        // only record the name, and `restore` links it.
+        if let Some(idx) = link_id.and_then(|id| stored_links.get(&id)).copied() {
+            let first = dest.join(&progress.links[idx].files[0]);
+            let linked = if local {
+                fs::hard_link(&first, &temp_file).and_then(|_| fs::rename(&temp_file, &final_file))
+            } else if stored && mode != HistoryMode::Versions {
+                progress.sparse.remove(&final_key);
+                store.delete(&final_key)
+            } else {
+                Ok(())
+            };
+            match linked {
+                Ok(()) => {
+                    debug!("Linked {} to {}", final_file.display(), first.display());
+                    let group = &mut progress.links[idx];
+                    if !group.files.contains(&final_key) {
+                        group.files.push(final_key.clone());
+                    }
+                    completed.insert(path.clone());
+                    record(observer, &mut results, FileResult {
+                        path: path.clone(),
+                        action: FileAction::Linked,
+                        bytes: None,
+                        error: None,
+                    });
+                    progress.completed = completed.iter().cloned().collect();
+                    progress.save(store, &progress_key)?;
+                    continue;
+                }
+                Err(e) => {
+                    fs::remove_file(&temp_file).ok();
+                    debug!("Cannot link {} to {}, copying instead: {e}", final_file.display(), first.display());
+                }
+            }
+        }
+
+        // Perform the copy. Large files are sent as deltas against their
+        // earlier copy if its signature was kept by the last run.
+        let signatures = signatures.as_ref().filter(|_| {
+            delta_transfer_min.is_some_and(|min| source_metadata.as_ref().is_some_and(|m| m.len() >= min))
+        });
+        let mut new_signature = None;
+        let mut extents = None;
+        let result = match signatures {
+            Some(cache) => {
+                let base = previous_copy.as_ref().and_then(|base| Some((base, cache.load(&final_file, base)?)));
+                let base = base.as_ref().map(|(path, signature)| (path.as_path(), signature));
+                copy::delta_copy(path, base, &temp_file, &copy_options, cancel).map(|(copied, signature)| {
+                    new_signature = signature;
+                    copied
+                })
+            }
+            None if local => copy::copy_file(path, &temp_file, &copy_options, cancel),
+            None => copy::upload(path, store, &temp_key, &copy_options, cancel).map(|(copied, sparse)| {
+                extents = sparse;
+                copied
+            }),
+        };
+        match result {
+            Ok(copied) => {
+                let size = copied.bytes;
                 progress.bytes_copied = progress.bytes_copied.saturating_add(size);
-                fs::rename(&temp_file, &final_file)
-                    .with_context(|| format!("Failed to rename: {}", final_file.display()))?;
+                progress.logical_bytes = progress.logical_bytes.saturating_add(copied.logical_bytes);
+                if copied.method == CopyMethod::Delta {
+                    progress.bytes_saved = progress.bytes_saved.saturating_add(copied.logical_bytes - size);
+                }
+                progress.copy_methods.record(copied.method);
+                if temp_key != final_key {
+                    store.rename(&temp_key, &final_key).at(&final_file)?;
+                }
+                // The stored copy carries the source's modification time, which
+                // `find` filters on; object stores set the upload time instead
+                if let Some(modified) = source_metadata.as_ref().and_then(|m| m.modified().ok()) {
+                    match store.set_modified(&final_key, modified) {
+                        Err(e) if e.kind() != io::ErrorKind::Unsupported => {
+                            warn!("Cannot set the modification time of {}: {e}", final_file.display());
+                        }
+                        _ => {}
+                    }
+                }
+                match extents.take() {
+                    Some(extents) => progress.sparse.insert(final_key.clone(), extents),
+                    None => progress.sparse.remove(&final_key),
+                };
+                if let (Some(cache), Some(signature)) = (signatures, &new_signature) {
+                    if let Err(e) = cache.save(&final_file, signature) {
+                        warn!("Cannot keep the block signature of {}: {e}", final_file.display());
+                    }
+                }
+                // A copy made because linking failed stands on its own
+                if let Some((device, inode)) = link_id.filter(|id| !stored_links.contains_key(id)) {
+                    stored_links.insert((device, inode), progress.links.len());
+                    progress.links.push(LinkGroup { device, inode, files: vec![final_key.clone()] });
+                }
+                debug!(
+                    "Copied {} ({} of {} bytes, {:?})",
+                    path.display(),
+                    size,
+                    copied.logical_bytes,
+                    copied.method
+                );
+                observer.bytes_copied(path, size);
                 completed.insert(path.clone());
+                record(observer, &mut results, FileResult {
+                    path: path.clone(),
+                    action: FileAction::Copied,
+                    bytes: Some(size),
+                    error: None,
+                });
+            }
+            Err(_) if cancel.is_cancelled() => {
+                // The file stays in `incomplete` and is copied again on resume
+                if temp_key != final_key {
+                    store.delete(&temp_key).ok();
+                }
+                progress.completed = completed.iter().cloned().collect();
+                progress.failed = failed.iter().cloned().collect();
+                progress.save(store, &progress_key)?;
+                warn!(
+                    "Backup cancelled while copying {}, progress saved to {}",
+                    path.display(),
+                    temp_state_file.display()
+                );
+                return Err(Error::Cancelled);
+            }
+            Err(e) if is_out_of_space(&e) => {
+                // Every further copy would fail as well, so stop and keep the progress
+                if temp_key != final_key {
+                    store.delete(&temp_key).ok();
+                }
+                progress.completed = completed.iter().cloned().collect();
+                progress.failed = failed.iter().cloned().collect();
+                progress.save(store, &progress_key)?;
+                error!("Destination is full while copying {}", path.display());
+                return Err(Error::OutOfSpace { path: temp_file, source: e });
             }
             Err(e) => {
-                eprintln!("Failed to copy {}: {e}", path.display());
+                error!("Failed to copy {}: {e}", path.display());
                 failed.insert(path.clone());
+                record(observer, &mut results, FileResult {
+                    path: path.clone(),
+                    action: FileAction::Failed,
+                    bytes: None,
+                    error: Some(e.to_string()),
+                });
             }
         }
 
         // Update progress after each file
         progress.completed = completed.iter().cloned().collect();
         progress.failed = failed.iter().cloned().collect();
-        progress.save(&temp_state_file)?;
+        progress.save(store, &progress_key)?;
     }
     // Finalize backup status
     progress.status = Status { state: "in_progress".to_string(),            };
     progress.duration = start_time.elapsed(); // todo add field to progress
     progress.timestamp = Local::now();
-    progress.save(&temp_state_file)?;
+    progress.save(store, &progress_key)?;
+
+    let snapshot_path = if mode == HistoryMode::HardlinkSnapshots {
+        Some(create_snapshot(&dest, job, progress.snapshot_id, &roots)?)
+    } else {
+        None
+    };
+
+    // Names deleted from the source leave their group, and a group whose
+    // stored copy is gone is dropped, so its names are stored again if needed
+    progress.links.retain(|group| roots.source_of(&group.files[0]).is_some_and(|source| source.exists()));
+    for group in &mut progress.links {
+        group.files.retain(|key| roots.source_of(key).is_some_and(|source| source.exists()));
+    }
+    progress.links.retain(|group| group.files.len() > 1);
 
     // Update global state
-    state.record_backup(&progress, &config, removed_count);
-    state.save(&state_file)?;
+    state.namespace_mut(job).record_backup(&progress, config, removed_count);
+    storage::write_toml(store, Path::new(STATE_KEY), &state)?;
 
     // Remove .incomplete marker
-    fs::remove_file(&temp_state_file).ok();
-    
-    pb.finish_with_message("Backup completed.");
-    //println!("Backup completed.");
-    Ok(())
+    store.delete(&progress_key).ok();
+
+    let count = |action: FileAction| results.iter().filter(|r| r.action == action).count() as u64;
+    info!(
+        "Backup {} completed: {} copied, {} failed, {} removed, {} bytes in {} ms",
+        progress.snapshot_id,
+        count(FileAction::Copied),
+        count(FileAction::Failed),
+        removed_count,
+        progress.bytes_copied,
+        progress.duration.as_millis()
+    );
+    if config.logging.file {
+        match logging::rotate_logs(store, job, &config.logging) {
+            Ok(0) => {}
+            Ok(n) => debug!("Deleted {} old run log(s)", n),
+            Err(e) => warn!("Failed to rotate run logs: {e}"),
+        }
+    }
+    let report = BackupReport {
+        snapshot_id: progress.snapshot_id,
+        resumed,
+        files_copied: count(FileAction::Copied),
+        files_linked: count(FileAction::Linked),
+        files_failed: count(FileAction::Failed),
+        files_removed: removed_count,
+        bytes_copied: progress.bytes_copied,
+        logical_bytes: progress.logical_bytes,
+        bytes_saved: progress.bytes_saved,
+        copy_methods: progress.copy_methods.clone(),
+        duration_ms: progress.duration.as_millis() as u64,
+        files: results,
+        history_mode: mode,
+        previous_history_mode: previous_mode,
+        snapshot_path,
+    };
+    observer.backup_finished(&report);
+    Ok(report)
 }
 
+/// Move the copies an outer include root stored of files below a root nested
+/// inside it, made before the nested root was added, to the nested root's
+/// folder, where runs now store them.
+///
+/// A copy that the nested root already holds is newer and stays; the outer
+/// one is left in place with a warning naming both.
+fn adopt_nested_copies(store: &dyn StorageBackend, roots: &roots::RootMap) -> Result<Adopted> {
+    // Outer roots first, so a root nested twice ends up in the innermost folder
+    let mut outers: Vec<&roots::MappedRoot> = roots.roots.iter().collect();
+    outers.sort_by_key(|m| Path::new(&m.root.path).components().count());
+    let mut adopted = Adopted::default();
+    for outer in outers {
+        for inner in &roots.roots {
+            let Ok(rel) = Path::new(&inner.root.path).strip_prefix(&outer.root.path) else {
+                continue;
+            };
+            if rel.as_os_str().is_empty() {
+                continue;
+            }
+            let prefix = Path::new(&outer.folder).join(rel);
+            adopted.prefixes.push((prefix.clone(), PathBuf::from(&inner.folder)));
+            for object in store.list(&prefix).at(store.display_path(&prefix))? {
+                let Ok(rest) = object.key.strip_prefix(&prefix) else {
+                    continue;
+                };
+                let target = Path::new(&inner.folder).join(rest);
+                if store.stat(&target).at(store.display_path(&target))?.is_some() {
+                    warn!(
+                        "{} is stored by the nested root {} as {} and left in place; delete it to reclaim the space",
+                        store.display_path(&object.key).display(),
+                        inner.root.path,
+                        store.display_path(&target).display()
+                    );
+                    adopted.left.insert(object.key);
+                    continue;
+                }
+                store.rename(&object.key, &target).at(store.display_path(&object.key))?;
+                info!(
+                    "Moved {} to {}, the folder of the nested root {}",
+                    store.display_path(&object.key).display(),
+                    store.display_path(&target).display(),
+                    inner.root.path
+                );
+                adopted.moved.push((object.key, target));
+            }
+        }
+    }
+    Ok(adopted)
+}
 
-/// Vacuum old versions from the history folder, keeping only the most recent `max_versions`.
+/// Keys moved by [`adopt_nested_copies`].
+#[derive(Default)]
+struct Adopted {
+    /// Moved objects as `(from, to)`
+    moved: Vec<(PathBuf, PathBuf)>,
+    /// Folder of each nested root below an outer root's folder, and the nested
+    /// root's own folder
+    prefixes: Vec<(PathBuf, PathBuf)>,
+    /// Objects left in place because the nested root already stores them
+    left: HashSet<PathBuf>,
+}
 
+impl Adopted {
+    /// Follow the moves in recorded extents and link groups, returning
+    /// whether anything changed. Linked names without an object of their own,
+    /// outside local destinations, move with their folder.
+    fn rekey(&self, sparse: &mut BTreeMap<PathBuf, Extents>, links: &mut [LinkGroup]) -> bool {
+        let mut changed = false;
+        for (from, to) in &self.moved {
+            if let Some(extents) = sparse.remove(from) {
+                sparse.insert(to.clone(), extents);
+                changed = true;
+            }
+        }
+        for name in links.iter_mut().flat_map(|group| group.files.iter_mut()) {
+            if self.left.contains(name) {
+                continue;
+            }
+            // In the order the objects were moved, so a name below a root
+            // nested twice ends up in the innermost folder as well
+            for (prefix, folder) in &self.prefixes {
+                if let Ok(rest) = name.strip_prefix(prefix) {
+                    *name = folder.join(rest);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
 
-pub fn vacuum(config: &Config) -> Result<()> {
-    println!("Vacuuming old backups...");
+/// Link groups of earlier runs that still hold for this one. A group is kept
+/// if the name holding its data is unchanged and still has the recorded inode;
+/// its other names are then up to date even where they are not stored, so
+/// they are taken out of `changed`. Names linked to another inode since leave
+/// the group. Outside local destinations those names and all names of a group
+/// that is not kept have no stored copy of their own, so they are added to
+/// `changed`.
+fn carry_over_links(previous: &[LinkGroup], roots: &roots::RootMap, local: bool, changed: &mut Vec<PathBuf>) -> Vec<LinkGroup> {
+    let mut queued: HashSet<PathBuf> = changed.iter().cloned().collect();
+    let mut current = HashSet::new();
+    let mut requeue = Vec::new();
+    let mut kept = Vec::new();
+    for group in previous {
+        let names: Vec<_> = group
+            .files
+            .iter()
+            .filter_map(|key| {
+                let source = roots.source_of(key)?;
+                let id = fs::metadata(&source).ok().as_ref().and_then(copy::link_id);
+                Some((key, source, id))
+            })
+            .collect();
+        let valid = group.files.first().zip(names.first()).is_some_and(|(first, (key, source, id))| {
+            first == *key && *id == Some(group.id()) && !queued.contains(source)
+        });
+        let mut files = Vec::new();
+        for (key, source, id) in names {
+            if valid && id == Some(group.id()) {
+                files.push(key.clone());
+                current.insert(source);
+            } else if !local && source.exists() {
+                requeue.push(source);
+            }
+        }
+        if valid {
+            kept.push(LinkGroup { files, ..group.clone() });
+        }
+    }
+    changed.retain(|path| !current.contains(path));
+    for path in requeue {
+        if queued.insert(path.clone()) {
+            changed.push(path);
+        }
+    }
+    kept
+}
 
-    let history_root = PathBuf::from(&config.backup.destination).join("History");
-    if !history_root.exists() {
-        println!("No history folder found.");
-        return Ok(());
+/// Folder holding the snapshot trees of `job`.
+pub fn snapshots_dir(dest: &Path, job: Option<&str>) -> PathBuf {
+    match job {
+        Some(job) => dest.join("snapshots").join(job),
+        None => dest.join("snapshots"),
     }
+}
 
-    // Collect all file entries so we know the scan length for the progress bar
-    let entries: Vec<_> = WalkDir::new(&history_root)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
+/// Create `snapshots/<id>-<timestamp>/<folder>/…` with a hard link to every
+/// file of the mirror. Files replaced later get a new inode in the mirror, so
+/// the snapshot keeps the content of this run.
+fn create_snapshot(dest: &Path, job: Option<&str>, snapshot_id: u64, roots: &roots::RootMap) -> Result<PathBuf> {
+    let name = format!("{}-{}", snapshot_id, Local::now().format("%Y-%m-%dT%H-%M-%S"));
+    let snapshot = snapshots_dir(dest, job).join(name);
+    for mapped in &roots.roots {
+        let mirror = dest.join(&mapped.folder);
+        if !mirror.exists() {
+            continue;
+        }
+        for entry in WalkDir::new(&mirror).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let rel = entry.path().strip_prefix(dest).expect("walked below dest");
+            let target = snapshot.join(rel);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).at(parent)?;
+            }
+            fs::hard_link(entry.path(), &target).at(&target)?;
+        }
+    }
+    info!("Created snapshot {}", snapshot.display());
+    Ok(snapshot)
+}
+
+/// With `backup.delta_history`, store the version before `newest` as a delta.
+/// A failure only costs space, so it is logged and the full copy is kept.
+fn compact_history(newest: &Path, options: Option<&DeltaOptions>) {
+    let Some(options) = options else {
+        return;
+    };
+    match history::compact_previous(newest, options) {
+        Ok(Some(saved)) => debug!("Delta for the version before {} saved {} bytes", newest.display(), saved),
+        Ok(None) => {}
+        Err(e) => warn!("Keeping the previous version of {} in full: {e}", newest.display()),
+    }
+}
+
+/// Notify the observer about a finished file and keep its result for the report.
+fn record(observer: &dyn BackupObserver, results: &mut Vec<FileResult>, result: FileResult) {
+    observer.file_finished(&result);
+    results.push(result);
+}
+
+
+/// Vacuum old versions from the history folder, keeping only the most recent
+/// `max_versions`, and delete snapshot trees beyond `keep_snapshots` or
+/// `max_snapshot_age_days`.
+pub fn vacuum(config: &Config) -> Result<VacuumReport> {
+    BackupEngine::new(config).vacuum()
+}
+
+pub(crate) fn execute_vacuum(engine: &BackupEngine) -> Result<VacuumReport> {
+    let config = engine.config();
+    let observer = engine.observer();
+    info!("Vacuuming old backups...");
+
+    let storage = engine.storage(false)?;
+    let store = &*storage;
+    // Uploads a run is still sending are younger than a stale lock
+    let uploads_discarded: Vec<PathBuf> = store
+        .discard_stale_uploads(storage::STALE_LOCK)
+        .at(store.location())?
+        .iter()
+        .map(|key| store.display_path(key))
         .collect();
+    for path in &uploads_discarded {
+        info!("Aborted the unfinished upload of {}", path.display());
+    }
+    let version_keys = history_candidates(engine, store)?;
+    // Snapshot trees are hard links, so they only exist in local destinations
+    let snapshots = match store.local_root() {
+        Some(dest) => snapshot_candidates(&config.backup, &snapshots_dir(dest, config.job.as_deref()))?,
+        None => Vec::new(),
+    };
+    if version_keys.is_empty() && snapshots.is_empty() {
+        info!("Nothing to vacuum.");
+        return Ok(VacuumReport {
+            uploads_discarded,
+            ..Default::default()
+        });
+    }
 
-    let scan_pb = ProgressBar::new(entries.len() as u64);
-    scan_pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} files ({eta})")
-            .unwrap()
-            .progress_chars("##-")
-    );
+    let versions: Vec<PathBuf> = version_keys.iter().map(|key| store.display_path(key)).collect();
+    let candidates: Vec<PathBuf> = versions.iter().chain(&snapshots).cloned().collect();
+    observer.vacuum_started(&candidates);
+    // Deltas only exist in local destinations; pruning one of their bases
+    // rebases them so the versions that are kept can still be rebuilt
+    let delta_options = config.backup.delta_options();
+    for (key, path) in version_keys.iter().zip(&versions) {
+        engine.cancellation().check()?;
+        match store.local_root() {
+            Some(dest) => history::prune_version(&dest.join(key), delta_options.as_ref())?,
+            None => store.delete(key).at(path)?,
+        }
+        observer.version_pruned(path);
+    }
+    // Forget the extents of pruned sparse versions
+    if store.local_root().is_none() && !version_keys.is_empty() {
+        let mut state = load_state(store)?;
+        let sparse = &mut state.namespace_mut(config.job.as_deref()).sparse;
+        let before = sparse.len();
+        for key in &version_keys {
+            sparse.remove(key);
+        }
+        if sparse.len() != before {
+            storage::write_toml(store, Path::new(STATE_KEY), &state)?;
+        }
+    }
+    for path in &snapshots {
+        engine.cancellation().check()?;
+        fs::remove_dir_all(path).at(path)?;
+        observer.snapshot_pruned(path);
+    }
+
+    info!("Removed {} outdated file(s) and {} snapshot(s).", versions.len(), snapshots.len());
+
+    let report = VacuumReport {
+        files_removed: versions.len() as u64,
+        pruned: versions,
+        snapshots_removed: snapshots,
+        uploads_discarded,
+    };
+    observer.vacuum_finished(&report);
+    Ok(report)
+}
+
+/// Keys of History versions beyond the `max_versions` of their include root,
+/// oldest first so that deltas never need rebasing onto a version pruned next.
+fn history_candidates(engine: &BackupEngine, store: &dyn StorageBackend) -> Result<Vec<PathBuf>> {
+    let config = engine.config();
+    let observer = engine.observer();
+    let history_key = Path::new("History");
+    let history_root = store.display_path(history_key);
+    let roots = roots::map_roots_in(store, &config.paths.include, false)?;
+
+    // A named job may share the destination with other jobs, so it only prunes
+    // the History of its own include roots
+    let walk_roots: Vec<PathBuf> = match config.job {
+        Some(_) => roots.roots.iter().map(|m| history_key.join(&m.folder)).collect(),
+        None => vec![history_key.to_path_buf()],
+    };
+
+    // Collect all stored versions so we know the scan length for the progress bar
+    let mut entries = Vec::new();
+    for root in &walk_roots {
+        entries.extend(store.list(root).at(store.display_path(root))?);
+    }
+    if entries.is_empty() {
+        info!("No history folder found.");
+        return Ok(Vec::new());
+    }
+
+    observer.scan_started(&history_root, entries.len() as u64);
 
     let mut file_versions: HashMap<PathBuf, Vec<(DateTime<Local>, PathBuf)>> = HashMap::new();
-    let re = Regex::new(r"^(.*)_((?:\d{4}-\d{2}-\d{2}T\d{2}-\d{2}-\d{2}))(\..+)?$").unwrap();
 
     for entry in entries {
-        scan_pb.inc(1);
-        scan_pb.set_message(entry.path().display().to_string());
-        let full_path = normalize_path(entry.path()).to_path_buf();
-        let filename = full_path.file_name().unwrap().to_string_lossy();
-        if let Some(caps) = re.captures(&filename) {
-            let base = caps.get(1).unwrap().as_str();
-            let ts_str = caps.get(2).unwrap().as_str();
-            let ext = caps.get(3).map(|e| e.as_str()).unwrap_or("");
-
-            if let Ok(naive) = NaiveDateTime::parse_from_str(ts_str, "%Y-%m-%dT%H-%M-%S") {
-                let mut canonical = full_path.clone();
-                let local_dt = Local.from_local_datetime(&naive).unwrap();
-                canonical.set_file_name(format!("{}{}", base, ext));
-                let canonical = normalize_path(&canonical);
-
-                file_versions
-                    .entry(canonical)
-                    .or_default()
-                    .push((local_dt, full_path.clone()));
-            }
+        observer.scan_progress(&store.display_path(&entry.key));
+        let filename = entry.key.file_name().unwrap().to_string_lossy();
+        if let Some((original_name, local_dt)) = parse_history_name(&filename) {
+            let mut canonical = entry.key.clone();
+            canonical.set_file_name(original_name);
+
+            file_versions
+                .entry(canonical)
+                .or_default()
+                .push((local_dt, entry.key.clone()));
         }
     }
-    scan_pb.finish_with_message("Scan complete.");
+    observer.scan_finished(&history_root);
+
+    // Include roots may override the global max_versions for their History
+    let keep_by_label: HashMap<&str, Option<u32>> = roots
+        .roots
+        .iter()
+        .map(|m| (m.folder.as_str(), m.root.max_versions(config.backup.max_versions)))
+        .collect();
 
-    let mut total_candidates = 0;
     let mut delete_candidates: Vec<PathBuf> = Vec::new();
     for (base_path, mut versions) in file_versions {
         versions.sort_by_key(|(ts, _)| std::cmp::Reverse(*ts));
-        let keep = config.backup.max_versions.unwrap_or(0) as usize;
+        let label = base_path
+            .strip_prefix(history_key)
+            .ok()
+            .and_then(|rel| rel.components().next())
+            .map(|c| c.as_os_str().to_string_lossy().to_string());
+        let max_versions = label
+            .and_then(|l| keep_by_label.get(l.as_str()).copied())
+            .unwrap_or(config.backup.max_versions);
+        let keep = max_versions.unwrap_or(0) as usize;
         let to_prune = &versions[keep.min(versions.len())..];
         if !to_prune.is_empty() {
-            println!("Found {} prune candidates for {}:", to_prune.len(), base_path.display());
-            for (_ts, path) in to_prune {
-                println!("{} ", path.display());
+            debug!("Found {} prune candidates for {}", to_prune.len(), base_path.display());
+            for (_ts, path) in to_prune.iter().rev() {
                 delete_candidates.push(path.clone());
-                total_candidates += 1;
             }
         }
     }
+    Ok(delete_candidates)
+}
 
-    if delete_candidates.is_empty() {
-        println!("Nothing to vacuum.");
-        return Ok(());
+/// Snapshot trees in `dir` beyond `keep_snapshots` or older than
+/// `max_snapshot_age_days`, oldest last. The newest snapshot is always kept.
+fn snapshot_candidates(options: &BackupOptions, dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() || (options.keep_snapshots.is_none() && options.max_snapshot_age_days.is_none()) {
+        return Ok(Vec::new());
     }
+    let mut snapshots: Vec<(u64, DateTime<Local>, PathBuf)> = fs::read_dir(dir)
+        .at(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .filter_map(|p| {
+            let (id, taken) = parse_snapshot_name(&p.file_name()?.to_string_lossy())?;
+            Some((id, taken, p))
+        })
+        .collect();
+    snapshots.sort_by_key(|(id, _, _)| std::cmp::Reverse(*id));
 
-    let delete_pb = ProgressBar::new(delete_candidates.len() as u64);
-    delete_pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} files ({eta})")
-            .unwrap()
-            .progress_chars("##-")
-    );
-
-    for path in delete_candidates {
-        delete_pb.inc(1);
-        delete_pb.set_message(path.display().to_string());
-        fs::remove_file(&path).with_context(|| format!("Failed to delete {}", path.display()))?;
-    }
-    delete_pb.finish_with_message("Vacuum complete.");
+    let cutoff = options
+        .max_snapshot_age_days
+        .map(|days| Local::now() - chrono::Duration::days(days as i64));
+    Ok(snapshots
+        .into_iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(idx, (_, taken, _))| {
+            options.keep_snapshots.is_some_and(|keep| *idx >= keep) || cutoff.is_some_and(|c| *taken < c)
+        })
+        .map(|(_, (_, _, path))| path)
+        .collect())
+}
 
-    println!("Removed {} outdated file(s).", total_candidates);
 
-    Ok(())
+/// Report the latest backup and, if `log_lines` is non-zero, the tail of its run log.
+pub fn status(config: &Config, log_lines: usize) -> Result<StatusReport> {
+    BackupEngine::new(config).status(log_lines)
 }
 
+pub(crate) fn execute_status(engine: &BackupEngine, log_lines: usize) -> Result<StatusReport> {
+    let storage = engine.storage(false)?;
+    let job = engine.config().job.as_deref();
+    let latest = storage::read_toml::<BackupState>(&*storage, Path::new(STATE_KEY))?
+        .and_then(|state| state.namespace(job).map(|s| s.latest.clone()));
+
+    let mut log_tail = Vec::new();
+    if log_lines > 0 {
+        if let Some(id) = latest.as_ref().and_then(|l| l.snapshot_id.parse::<u64>().ok()) {
+            log_tail = logging::tail(&*storage, job, id, log_lines)?;
+        }
+    }
 
-/// Placeholder status implementation.
-pub fn status(_config: &Config) -> anyhow::Result<()> {
-    println!("Backup status: OK");
-    Ok(())
+    Ok(StatusReport {
+        state: "ok".to_string(),
+        latest,
+        log_tail,
+    })
 }
 
 
@@ -0,0 +1,548 @@
+use std::cell::Cell;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use globset::Glob;
+use ignore::gitignore::GitignoreBuilder;
+use serde::de::{DeserializeOwned, Deserializer, Visitor};
+use serde::Serialize;
+use toml_edit::{ImDocument, Item, TableLike, Value};
+use crate::config::{BackupOptions, BackupPaths, Config, IncludeTable, Job, LoggingOptions};
+use crate::error::{Error, Result};
+use crate::exclude;
+use crate::s3::S3Options;
+use crate::sftp::SftpOptions;
+use crate::storage;
+
+/// How serious a [`Problem`] is. Errors stop [`Config::load`], warnings are
+/// only logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// One finding of a config check.
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Key the problem refers to, e.g. `backup.max_versions` or
+    /// `job[1].paths.include[0]`; `None` for the file as a whole
+    pub key: Option<String>,
+    /// 1-based line in the config file
+    pub line: Option<usize>,
+    /// 1-based column in the config file
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, "{}: ", key)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Result of [`check_file`].
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckReport {
+    pub path: PathBuf,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn errors(&self) -> usize {
+        self.problems.iter().filter(|p| p.severity == Severity::Error).count()
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.problems.len() - self.errors()
+    }
+}
+
+/// Check the config file at `path` and report every problem found: syntax
+/// and type errors, unknown keys with suggestions, invalid patterns, missing
+/// include paths, an unusable destination and overlapping paths.
+pub fn check_file(path: &Path) -> Result<CheckReport> {
+    let data = std::fs::read_to_string(path).map_err(|source| Error::ConfigRead {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(CheckReport {
+        path: path.to_path_buf(),
+        problems: analyze(&data, true).problems,
+    })
+}
+
+pub(crate) struct Analysis {
+    /// The parsed config, unless it has syntax or type errors
+    pub config: Option<Config>,
+    pub problems: Vec<Problem>,
+    /// The syntax or type error, if parsing failed
+    pub parse_error: Option<toml::de::Error>,
+}
+
+/// Parse `data` and collect its problems; with `filesystem` set, also check
+/// the configured paths on disk.
+pub(crate) fn analyze(data: &str, filesystem: bool) -> Analysis {
+    let doc = match ImDocument::parse(data) {
+        Ok(doc) => doc,
+        Err(_) => {
+            let mut checker = Checker { data, doc: None, problems: Vec::new() };
+            let error = toml::from_str::<toml::Table>(data).expect_err("toml_edit rejected the document");
+            checker.parse_problem(&error, true);
+            return Analysis { config: None, problems: checker.problems, parse_error: Some(error) };
+        }
+    };
+    let mut checker = Checker { data, doc: Some(&doc), problems: Vec::new() };
+
+    let unknown = checker.unknown_keys();
+    // With unknown keys present, parse without them to report the remaining problems
+    let parsed = if unknown.is_empty() {
+        toml::from_str::<Config>(data)
+    } else {
+        let mut table: toml::Table = toml::from_str(data).expect("the document parsed before");
+        for key in &unknown {
+            remove(&mut table, key);
+        }
+        table.try_into::<Config>()
+    };
+    let config = match parsed {
+        Ok(config) => config,
+        Err(error) => {
+            checker.parse_problem(&error, unknown.is_empty());
+            let parse_error = unknown.is_empty().then_some(error);
+            return Analysis { config: None, problems: checker.problems, parse_error };
+        }
+    };
+
+    checker.semantics(&config, filesystem);
+    checker.problems.sort_by_key(|p| p.line.unwrap_or(usize::MAX));
+    Analysis { config: Some(config), problems: checker.problems, parse_error: None }
+}
+
+/// Segment of a key path.
+#[derive(Debug, Clone)]
+pub(crate) enum Seg {
+    Key(String),
+    Index(usize),
+}
+
+pub(crate) fn key(name: &str) -> Seg {
+    Seg::Key(name.to_string())
+}
+
+fn display(path: &[Seg]) -> String {
+    let mut out = String::new();
+    for seg in path {
+        match seg {
+            Seg::Key(k) if out.is_empty() => out.push_str(k),
+            Seg::Key(k) => {
+                out.push('.');
+                out.push_str(k);
+            }
+            Seg::Index(i) => out.push_str(&format!("[{}]", i)),
+        }
+    }
+    out
+}
+
+/// A position in the parsed document while following a key path.
+#[derive(Clone, Copy)]
+enum Node<'a> {
+    Item(&'a Item),
+    Value(&'a Value),
+    Table(&'a toml_edit::Table),
+}
+
+impl<'a> Node<'a> {
+    fn table(self) -> Option<&'a dyn TableLike> {
+        match self {
+            Node::Item(item) => item.as_table_like(),
+            Node::Value(value) => value.as_inline_table().map(|t| t as &dyn TableLike),
+            Node::Table(table) => Some(table),
+        }
+    }
+
+    /// Element `i` of an array or array of tables, with its span.
+    fn index(self, i: usize) -> Option<(Node<'a>, Option<Range<usize>>)> {
+        let array = match self {
+            Node::Item(Item::ArrayOfTables(tables)) => {
+                let table = tables.get(i)?;
+                return Some((Node::Table(table), table.span()));
+            }
+            Node::Item(item) => item.as_array()?,
+            Node::Value(value) => value.as_array()?,
+            Node::Table(_) => return None,
+        };
+        let value = array.get(i)?;
+        Some((Node::Value(value), value.span()))
+    }
+}
+
+struct Checker<'a> {
+    data: &'a str,
+    doc: Option<&'a ImDocument<&'a str>>,
+    problems: Vec<Problem>,
+}
+
+impl Checker<'_> {
+    fn push(&mut self, severity: Severity, path: Option<&[Seg]>, span: Option<Range<usize>>, message: String) {
+        let span = span.or_else(|| path.and_then(|p| self.span_of(p)));
+        let (line, column) = match span {
+            Some(span) => {
+                let (line, column) = self.position(span.start);
+                (Some(line), Some(column))
+            }
+            None => (None, None),
+        };
+        self.problems.push(Problem {
+            severity,
+            key: path.map(display),
+            line,
+            column,
+            message,
+        });
+    }
+
+    fn error(&mut self, path: &[Seg], message: String) {
+        self.push(Severity::Error, Some(path), None, message);
+    }
+
+    fn warning(&mut self, path: &[Seg], message: String) {
+        self.push(Severity::Warning, Some(path), None, message);
+    }
+
+    fn parse_problem(&mut self, error: &toml::de::Error, located: bool) {
+        let span = if located { error.span() } else { None };
+        self.push(Severity::Error, None, span, error.message().trim().to_string());
+    }
+
+    /// 1-based line and column of the byte `offset`.
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let before = &self.data[..offset.min(self.data.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        (line, column)
+    }
+
+    /// Span of the key or array element at `path`.
+    fn span_of(&self, path: &[Seg]) -> Option<Range<usize>> {
+        let mut node = Node::Item(self.doc?.as_item());
+        let mut span = None;
+        for seg in path {
+            match seg {
+                Seg::Key(k) => {
+                    let (key, item) = node.table()?.get_key_value(k)?;
+                    span = key.span();
+                    node = Node::Item(item);
+                }
+                Seg::Index(i) => {
+                    let (next, next_span) = node.index(*i)?;
+                    span = next_span;
+                    node = next;
+                }
+            }
+        }
+        span
+    }
+
+    /// Report keys no config struct accepts and return their paths.
+    fn unknown_keys(&mut self) -> Vec<Vec<Seg>> {
+        let Some(doc) = self.doc else {
+            return Vec::new();
+        };
+        let mut unknown = Vec::new();
+        let root = Node::Item(doc.as_item());
+        self.walk(root, &mut Vec::new(), fields::<Config>(), &mut unknown);
+        if let Some(paths) = node_at(root, "paths") {
+            self.walk_paths(paths, &mut vec![key("paths")], &mut unknown);
+        }
+        self.walk_options(root, &mut Vec::new(), &mut unknown);
+        if let Some(jobs) = node_at(root, "job") {
+            let mut i = 0;
+            while let Some((job, _)) = jobs.index(i) {
+                let mut path = vec![key("job"), Seg::Index(i)];
+                self.walk(job, &mut path, fields::<Job>(), &mut unknown);
+                if let Some(paths) = node_at(job, "paths") {
+                    path.push(key("paths"));
+                    self.walk_paths(paths, &mut path, &mut unknown);
+                    path.pop();
+                }
+                self.walk_options(job, &mut path, &mut unknown);
+                i += 1;
+            }
+        }
+        unknown
+    }
+
+    fn walk_options(&mut self, parent: Node, path: &mut Vec<Seg>, unknown: &mut Vec<Vec<Seg>>) {
+        for (name, table_fields) in [("backup", fields::<BackupOptions>()), ("logging", fields::<LoggingOptions>())] {
+            if let Some(node) = node_at(parent, name) {
+                path.push(key(name));
+                self.walk(node, path, table_fields, unknown);
+                if name == "backup" {
+                    for (table, table_fields) in [("s3", fields::<S3Options>()), ("sftp", fields::<SftpOptions>())] {
+                        if let Some(options) = node_at(node, table) {
+                            path.push(key(table));
+                            self.walk(options, path, table_fields, unknown);
+                            path.pop();
+                        }
+                    }
+                }
+                path.pop();
+            }
+        }
+    }
+
+    fn walk_paths(&mut self, paths: Node, path: &mut Vec<Seg>, unknown: &mut Vec<Vec<Seg>>) {
+        self.walk(paths, path, fields::<BackupPaths>(), unknown);
+        let Some(include) = node_at(paths, "include") else {
+            return;
+        };
+        let mut i = 0;
+        while let Some((entry, _)) = include.index(i) {
+            path.extend([key("include"), Seg::Index(i)]);
+            self.walk(entry, path, fields::<IncludeTable>(), unknown);
+            path.truncate(path.len() - 2);
+            i += 1;
+        }
+    }
+
+    /// Report the keys of the table at `node` that are not in `fields`.
+    fn walk(&mut self, node: Node, path: &mut Vec<Seg>, fields: &[&str], unknown: &mut Vec<Vec<Seg>>) {
+        let Some(table) = node.table() else {
+            return;
+        };
+        for (name, _) in table.iter() {
+            if fields.contains(&name) {
+                continue;
+            }
+            path.push(key(name));
+            let message = match suggest(name, fields) {
+                Some(candidate) => format!("unknown key, did you mean `{}`?", candidate),
+                None => format!("unknown key, expected one of {}", quoted(fields)),
+            };
+            self.error(path, message);
+            unknown.push(path.clone());
+            path.pop();
+        }
+    }
+
+    fn semantics(&mut self, config: &Config, filesystem: bool) {
+        for (path, message) in config.validate() {
+            if path.is_empty() {
+                self.push(Severity::Error, None, None, message);
+            } else {
+                self.error(&path, message);
+            }
+        }
+
+        let scopes: Vec<(Vec<Seg>, &BackupPaths, &BackupOptions)> = if config.jobs.is_empty() {
+            vec![(Vec::new(), &config.paths, &config.backup)]
+        } else {
+            config
+                .jobs
+                .iter()
+                .enumerate()
+                .map(|(i, job)| (vec![key("job"), Seg::Index(i)], &job.paths, &job.backup))
+                .collect()
+        };
+        for (prefix, paths, backup) in scopes {
+            let at = |keys: &[Seg]| prefix.iter().cloned().chain(keys.iter().cloned()).collect::<Vec<_>>();
+            self.patterns(&at(&[key("paths")]), paths);
+            if filesystem {
+                self.filesystem(&at(&[key("paths")]), &at(&[key("backup"), key("destination")]), paths, backup);
+            }
+        }
+    }
+
+    fn patterns(&mut self, prefix: &[Seg], paths: &BackupPaths) {
+        let at = |keys: &[Seg]| prefix.iter().cloned().chain(keys.iter().cloned()).collect::<Vec<_>>();
+        for (i, pattern) in paths.exclude.iter().enumerate() {
+            if let Err(e) = Glob::new(pattern) {
+                self.error(&at(&[key("exclude"), Seg::Index(i)]), format!("invalid glob: {}", e));
+            }
+        }
+        for (i, line) in paths.ignore.iter().enumerate() {
+            if let Err(e) = GitignoreBuilder::new("/").add_line(None, line) {
+                self.error(&at(&[key("ignore"), Seg::Index(i)]), format!("invalid ignore rule: {}", e));
+            }
+        }
+        for (r, root) in paths.include.iter().enumerate() {
+            for (i, pattern) in root.exclude.iter().enumerate() {
+                if let Err(e) = Glob::new(pattern) {
+                    let path = at(&[key("include"), Seg::Index(r), key("exclude"), Seg::Index(i)]);
+                    self.error(&path, format!("invalid glob: {}", e));
+                }
+            }
+            for (i, line) in root.ignore.iter().enumerate() {
+                if let Err(e) = GitignoreBuilder::new(&root.path).add_line(None, line) {
+                    let path = at(&[key("include"), Seg::Index(r), key("ignore"), Seg::Index(i)]);
+                    self.error(&path, format!("invalid ignore rule: {}", e));
+                }
+            }
+        }
+    }
+
+    fn filesystem(&mut self, prefix: &[Seg], dest_key: &[Seg], paths: &BackupPaths, backup: &BackupOptions) {
+        let include_key = |i: usize| {
+            prefix.iter().cloned().chain([key("include"), Seg::Index(i)]).collect::<Vec<_>>()
+        };
+        // A bucket or server is only contacted by a backup run
+        let local_dest = !backup.destination.is_empty() && !storage::is_remote(&backup.destination);
+        let dest = Path::new(&backup.destination);
+        if local_dest {
+            if dest.exists() && !dest.is_dir() {
+                self.error(dest_key, format!("{} is not a directory", dest.display()));
+            } else if !dest.exists() {
+                self.warning(dest_key, format!("{} does not exist yet and will be created", dest.display()));
+            }
+        }
+
+        for (i, root) in paths.include.iter().enumerate() {
+            let path = Path::new(&root.path);
+            if !path.exists() {
+                self.error(&include_key(i), format!("{} does not exist", root.path));
+                continue;
+            }
+            if !path.is_dir() {
+                self.error(&include_key(i), format!("{} is not a directory", root.path));
+                continue;
+            }
+            if !local_dest {
+                continue;
+            }
+            if exclude::inside(dest, path, true).is_some() {
+                self.error(
+                    &include_key(i),
+                    format!("{} lies inside the destination {}", root.path, dest.display()),
+                );
+            } else if exclude::inside(path, dest, false).is_some() {
+                self.warning(
+                    dest_key,
+                    format!("lies inside include '{}' and is excluded from the backup", root.path),
+                );
+            }
+        }
+
+        for (i, outer) in paths.include.iter().enumerate() {
+            for (j, inner) in paths.include.iter().enumerate() {
+                if i != j && Path::new(&inner.path).exists() && exclude::inside(Path::new(&outer.path), Path::new(&inner.path), false).is_some() {
+                    self.warning(
+                        &include_key(j),
+                        format!("lies inside include '{}'; its files are backed up with this root only", outer.path),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn node_at<'a>(parent: Node<'a>, name: &str) -> Option<Node<'a>> {
+    parent.table()?.get(name).map(Node::Item)
+}
+
+/// Remove the key at `path` from a parsed table.
+fn remove(table: &mut toml::Table, path: &[Seg]) {
+    let Some((Seg::Key(last), parents)) = path.split_last() else {
+        return;
+    };
+    let mut current = table;
+    let mut segs = parents.iter().peekable();
+    while let Some(seg) = segs.next() {
+        let Seg::Key(name) = seg else {
+            return;
+        };
+        let value = match current.get_mut(name) {
+            Some(value) => value,
+            None => return,
+        };
+        let value = match segs.peek() {
+            Some(Seg::Index(i)) => {
+                segs.next();
+                match value.as_array_mut().and_then(|a| a.get_mut(*i)) {
+                    Some(value) => value,
+                    None => return,
+                }
+            }
+            _ => value,
+        };
+        current = match value.as_table_mut() {
+            Some(table) => table,
+            None => return,
+        };
+    }
+    current.remove(last);
+}
+
+/// The expected name closest to a misspelled `name`, if any is close enough.
+fn suggest<'f>(name: &str, fields: &[&'f str]) -> Option<&'f str> {
+    fields
+        .iter()
+        .map(|f| (strsim::jaro_winkler(name, f), *f))
+        .filter(|(score, _)| *score > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, f)| f)
+}
+
+fn quoted(fields: &[&str]) -> String {
+    fields.iter().map(|f| format!("`{}`", f)).collect::<Vec<_>>().join(", ")
+}
+
+/// Field names a derived `Deserialize` accepts, captured from its call to
+/// `deserialize_struct` so the key check cannot drift from the structs.
+fn fields<T: DeserializeOwned>() -> &'static [&'static str] {
+    #[derive(Debug)]
+    struct Stop;
+
+    impl fmt::Display for Stop {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("stop")
+        }
+    }
+
+    impl std::error::Error for Stop {}
+
+    impl serde::de::Error for Stop {
+        fn custom<M: fmt::Display>(_: M) -> Self {
+            Stop
+        }
+    }
+
+    struct Probe<'a>(&'a Cell<&'static [&'static str]>);
+
+    impl<'de> Deserializer<'de> for Probe<'_> {
+        type Error = Stop;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> std::result::Result<V::Value, Stop> {
+            Err(Stop)
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> std::result::Result<V::Value, Stop> {
+            self.0.set(fields);
+            Err(Stop)
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let fields = Cell::new(&[][..]);
+    let _ = T::deserialize(Probe(&fields));
+    fields.get()
+}
@@ -1,19 +1,564 @@
-use serde::Deserialize;
+use crate::check::{key, Seg};
+use crate::copy::{CopyOptions, CopyStrategy, SignatureCache, DEFAULT_BUFFER_SIZE};
+use crate::error::{Error, Result};
+use crate::history::{self, DeltaOptions};
+use crate::s3::{self, S3Options};
+use crate::sftp::SftpOptions;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(default)]
     pub paths: BackupPaths,
+    #[serde(default)]
     pub backup: BackupOptions,
+    #[serde(default)]
+    pub logging: LoggingOptions,
+    /// Named jobs from `[[job]]` tables, used instead of `paths` and `backup`
+    #[serde(default, rename = "job")]
+    pub jobs: Vec<Job>,
+    /// Name of the job this config was resolved for by [`Config::job`].
+    /// `None` for a config without `[[job]]` tables.
+    #[serde(skip)]
+    pub job: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// A `[[job]]` table: a named set of sources backed up to one destination.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    pub name: String,
+    pub paths: BackupPaths,
+    pub backup: BackupOptions,
+}
+
+impl Config {
+    /// Read and parse the TOML config file at `path`.
+    ///
+    /// Unknown keys, invalid patterns and inconsistent settings are reported
+    /// together as [`Error::InvalidConfig`]; warnings are logged. See
+    /// [`check::check_file`](crate::check::check_file) for the full check
+    /// including the file system.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path).map_err(|source| Error::ConfigRead {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let analysis = crate::check::analyze(&data, false);
+        if let Some(source) = analysis.parse_error {
+            return Err(Error::ConfigParse {
+                path: path.to_path_buf(),
+                source,
+            });
+        }
+        let (errors, warnings): (Vec<_>, Vec<_>) = analysis
+            .problems
+            .iter()
+            .partition(|p| p.severity == crate::check::Severity::Error);
+        if !errors.is_empty() {
+            let message = errors.iter().map(|p| p.to_string()).collect::<Vec<_>>().join("; ");
+            return Err(Error::InvalidConfig {
+                path: path.to_path_buf(),
+                message,
+            });
+        }
+        for warning in warnings {
+            log::warn!("{}: {}", path.display(), warning);
+        }
+        Ok(analysis.config.expect("a config without errors was parsed"))
+    }
+
+    /// Problems no single field's type rules out, each with the key path it
+    /// refers to; an empty path stands for the file as a whole.
+    pub(crate) fn validate(&self) -> Vec<(Vec<Seg>, String)> {
+        let mut problems = Vec::new();
+        let scopes: Vec<(Vec<Seg>, &BackupPaths, &BackupOptions)> = std::iter::once((Vec::new(), &self.paths, &self.backup))
+            .chain(
+                self.jobs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, j)| (vec![key("job"), Seg::Index(i)], &j.paths, &j.backup)),
+            )
+            .collect();
+        for (prefix, paths, backup) in &scopes {
+            let at = |keys: &[Seg]| prefix.iter().cloned().chain(keys.iter().cloned()).collect::<Vec<_>>();
+            for (i, name) in paths.exclude_presets.iter().enumerate() {
+                if crate::presets::patterns(name).is_none() {
+                    problems.push((
+                        at(&[key("paths"), key("exclude_presets"), Seg::Index(i)]),
+                        format!(
+                            "unknown exclude preset '{}'; available presets: {}",
+                            name,
+                            crate::presets::names().join(", ")
+                        ),
+                    ));
+                }
+            }
+            let mut roots = HashSet::new();
+            for (i, root) in paths.include.iter().enumerate() {
+                let include = at(&[key("paths"), key("include"), Seg::Index(i)]);
+                if !roots.insert(root.path.as_str()) {
+                    problems.push((include.clone(), format!("include '{}' is listed twice", root.path)));
+                }
+                if let Some(label) = &root.label {
+                    let valid = !label.is_empty()
+                        && label != "."
+                        && label != ".."
+                        && !crate::roots::RESERVED.contains(&label.as_str())
+                        && !label.contains(['/', '\\', ':']);
+                    if !valid {
+                        let mut path = include;
+                        path.push(key("label"));
+                        problems.push((path, format!("invalid label '{}' for include '{}'", label, root.path)));
+                    }
+                }
+            }
+
+            let option = |name: &str| at(&[key("backup"), key(name)]);
+            if let (Some(mode), Some(keep)) = (backup.history_mode, backup.keep_versions) {
+                if keep != (mode == HistoryMode::Versions) {
+                    problems.push((
+                        option("keep_versions"),
+                        format!("keep_versions = {} contradicts history_mode = \"{}\"; drop keep_versions", keep, mode),
+                    ));
+                }
+            }
+            if backup.keep_snapshots == Some(0) {
+                problems.push((option("keep_snapshots"), "keep_snapshots must be at least 1".into()));
+            }
+            if backup.copy_buffer_size == Some(0) {
+                problems.push((option("copy_buffer_size"), "copy_buffer_size must be at least 1 byte".into()));
+            }
+            if backup.max_delta_chain == Some(0) {
+                problems.push((
+                    option("max_delta_chain"),
+                    "max_delta_chain must be at least 1; set delta_history = false to disable deltas".into(),
+                ));
+            }
+            if let Some(s3) = &backup.s3 {
+                if !backup.destination.starts_with("s3://") {
+                    problems.push((option("s3"), "[backup.s3] is only used with an s3://bucket/prefix destination".into()));
+                }
+                if !(s3::MIN_PART_SIZE..=s3::MAX_PART_SIZE).contains(&s3.part_size()) {
+                    problems.push((
+                        at(&[key("backup"), key("s3"), key("part_size")]),
+                        "s3.part_size must be between 5 MiB and 5 GiB".into(),
+                    ));
+                }
+            }
+            if backup.sftp.is_some() && !backup.destination.starts_with("sftp://") {
+                problems.push((
+                    option("sftp"),
+                    "[backup.sftp] is only used with an sftp://user@host/path destination".into(),
+                ));
+            }
+        }
+        if self.jobs.is_empty() {
+            if self.backup.destination.is_empty() {
+                problems.push((Vec::new(), "expected a [backup] section or at least one [[job]] table".into()));
+            }
+            return problems;
+        }
+        if !self.backup.destination.is_empty() || !self.paths.include.is_empty() {
+            let section = if self.backup.destination.is_empty() { "paths" } else { "backup" };
+            problems.push((vec![key(section)], "use either [paths] and [backup] or [[job]] tables, not both".into()));
+        }
+        let mut seen = HashSet::new();
+        for (i, job) in self.jobs.iter().enumerate() {
+            let name = vec![key("job"), Seg::Index(i), key("name")];
+            let valid = !job.name.is_empty()
+                && !job.name.starts_with('.')
+                && job.name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+            if !valid {
+                problems.push((
+                    name.clone(),
+                    format!("invalid job name '{}': use letters, digits, '-', '_' and '.'", job.name),
+                ));
+            }
+            if !seen.insert(job.name.as_str()) {
+                problems.push((name, format!("duplicate job name '{}'", job.name)));
+            }
+        }
+        problems
+    }
+
+    /// Names of the configured jobs, in file order.
+    pub fn job_names(&self) -> Vec<&str> {
+        self.jobs.iter().map(|j| j.name.as_str()).collect()
+    }
+
+    /// Config for the job called `name`, carrying that job's paths and backup
+    /// options together with the shared logging options.
+    pub fn job(&self, name: &str) -> Result<Config> {
+        let job = self.jobs.iter().find(|j| j.name == name).ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "No job named '{}'; configured jobs: {}",
+                name,
+                self.job_names().join(", ")
+            ))
+        })?;
+        Ok(Config {
+            paths: job.paths.clone(),
+            backup: job.backup.clone(),
+            logging: self.logging.clone(),
+            jobs: Vec::new(),
+            job: Some(job.name.clone()),
+        })
+    }
+
+    /// One config per job, or this config itself if it has no `[[job]]` tables.
+    pub fn all_jobs(&self) -> Vec<Config> {
+        if self.jobs.is_empty() {
+            return vec![self.clone()];
+        }
+        self.jobs
+            .iter()
+            .map(|j| self.job(&j.name).expect("job name comes from the list"))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct BackupPaths {
-    pub include: Vec<String>,
+    pub include: Vec<IncludeRoot>,
+    /// Glob patterns matched against the absolute path under every include root
     pub exclude: Vec<String>,
+    /// Gitignore-style rules relative to each include root
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// Honor `.gitignore` files in addition to `.backupignore`
+    #[serde(default)]
+    pub use_gitignore: bool,
+    /// Built-in pattern sets from [`crate::presets`], applied before `ignore`
+    #[serde(default)]
+    pub exclude_presets: Vec<String>,
+    /// Skip files larger than this, in bytes or as a string such as `"2G"`
+    #[serde(default, deserialize_with = "size")]
+    pub max_file_size: Option<u64>,
+    /// Skip files smaller than this
+    #[serde(default, deserialize_with = "size")]
+    pub min_file_size: Option<u64>,
+    /// Skip files not modified within this many days
+    #[serde(default)]
+    pub skip_unmodified_days: Option<u64>,
+    /// Skip directories tagged as caches with a valid `CACHEDIR.TAG`
+    #[serde(default)]
+    pub exclude_caches: bool,
+    /// Skip directories containing one of these files, e.g. `.nobackup`
+    #[serde(default)]
+    pub marker_files: Vec<String>,
+    /// Skip files and directories carrying the `user.nobackup` extended attribute
+    #[serde(default)]
+    pub nobackup_xattr: bool,
+    /// Do not cross into other file systems below an include root, like `rsync -x`
+    #[serde(default)]
+    pub one_file_system: bool,
+}
+
+/// Accept a size as a byte count or a string such as `"500M"`.
+pub(crate) fn size<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Size::Text(text)) => crate::find::parse_size(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+/// Run logs kept unless `logging.keep_runs` is set.
+pub const DEFAULT_KEEP_RUNS: usize = 30;
+
+/// Accept a count of run logs, `0` or `false` to keep all of them and `true`
+/// for the default.
+fn keep_runs<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<usize>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Keep {
+        Count(usize),
+        Enabled(bool),
+    }
+
+    Ok(match Keep::deserialize(deserializer)? {
+        Keep::Count(0) | Keep::Enabled(false) => None,
+        Keep::Count(count) => Some(count),
+        Keep::Enabled(true) => Some(DEFAULT_KEEP_RUNS),
+    })
+}
+
+impl BackupPaths {
+    /// The innermost include root containing `path`.
+    pub fn root_for(&self, path: &Path) -> Option<&IncludeRoot> {
+        self.include
+            .iter()
+            .filter(|root| path.starts_with(&root.path))
+            .max_by_key(|root| Path::new(&root.path).components().count())
+    }
+}
+
+/// An entry of `paths.include`: either a plain path or a table with settings
+/// that apply to this root only.
+#[derive(Debug, Clone, Default)]
+pub struct IncludeRoot {
+    pub path: String,
+    /// Folder name of this root in the destination, derived from `path` if unset
+    pub label: Option<String>,
+    /// Glob patterns excluded under this root, in addition to `paths.exclude`
+    pub exclude: Vec<String>,
+    /// Gitignore-style rules for this root, applied after `paths.ignore`
+    pub ignore: Vec<String>,
+    /// Overrides `backup.max_versions` for this root's History
+    pub max_versions: Option<u32>,
+    /// Follow symbolic links while walking this root
+    pub follow_symlinks: bool,
+    /// Overrides `paths.one_file_system` for this root
+    pub one_file_system: Option<bool>,
+}
+
+impl IncludeRoot {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Folder name requested for this root: the explicit `label`, or one
+    /// derived from the path. The folder actually used is assigned by the
+    /// [`RootRegistry`](crate::roots::RootRegistry).
+    pub fn proposed_folder(&self) -> String {
+        match &self.label {
+            Some(label) => label.clone(),
+            None => self.path.replace(':', "").replace(['\\', '/'], "-"),
+        }
+    }
+
+    /// The `max_versions` to apply, falling back to the global `default`.
+    pub fn max_versions(&self, default: Option<u32>) -> Option<u32> {
+        self.max_versions.or(default)
+    }
+
+    /// Whether to stay on the root's file system, falling back to the global `default`.
+    pub fn one_file_system(&self, default: bool) -> bool {
+        self.one_file_system.unwrap_or(default)
+    }
+}
+
+impl From<String> for IncludeRoot {
+    fn from(path: String) -> Self {
+        Self::new(path)
+    }
+}
+
+impl From<&str> for IncludeRoot {
+    fn from(path: &str) -> Self {
+        Self::new(path)
+    }
+}
+
+impl<'de> Deserialize<'de> for IncludeRoot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Entry {
+            Path(String),
+            Table(IncludeTable),
+        }
+
+        Ok(match Entry::deserialize(deserializer)? {
+            Entry::Path(path) => Self::new(path),
+            Entry::Table(IncludeTable { path, label, exclude, ignore, max_versions, follow_symlinks, one_file_system }) => Self {
+                path,
+                label,
+                exclude,
+                ignore,
+                max_versions,
+                follow_symlinks,
+                one_file_system,
+            },
+        })
+    }
+}
+
+/// Table form of an include entry.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct IncludeTable {
+    path: String,
+    label: Option<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    ignore: Vec<String>,
+    max_versions: Option<u32>,
+    #[serde(default)]
+    follow_symlinks: bool,
+    one_file_system: Option<bool>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct BackupOptions {
     pub destination: String,
     pub max_versions: Option<u32>,
+    /// Shorthand for `history_mode`: `false` selects [`HistoryMode::None`]
+    #[serde(default)]
+    pub keep_versions: Option<bool>,
+    /// What happens to superseded and deleted files
+    #[serde(default)]
+    pub history_mode: Option<HistoryMode>,
+    /// Number of snapshot trees `vacuum` keeps
+    #[serde(default)]
+    pub keep_snapshots: Option<usize>,
+    /// `vacuum` deletes snapshot trees older than this many days
+    #[serde(default)]
+    pub max_snapshot_age_days: Option<u64>,
+    /// Force a copy method instead of picking the fastest available one
+    #[serde(default)]
+    pub copy_strategy: CopyStrategy,
+    /// Chunk size of buffered copies and `copy_file_range` calls
+    #[serde(default, deserialize_with = "size")]
+    pub copy_buffer_size: Option<u64>,
+    /// Store superseded History versions as deltas against the next version
+    #[serde(default)]
+    pub delta_history: bool,
+    /// Versions smaller than this are kept in full
+    #[serde(default, deserialize_with = "size")]
+    pub delta_min_size: Option<u64>,
+    /// Most deltas applied in a row to rebuild a version
+    #[serde(default)]
+    pub max_delta_chain: Option<u32>,
+    /// Send only the changed blocks of modified files
+    #[serde(default)]
+    pub delta_transfer: bool,
+    /// Smaller files are always copied in full
+    #[serde(default, deserialize_with = "size")]
+    pub delta_transfer_min_size: Option<u64>,
+    /// Where the block signatures of delta-transferred copies are kept
+    #[serde(default)]
+    pub signature_cache: Option<PathBuf>,
+    /// Connection settings for an `s3://` destination
+    #[serde(default)]
+    pub s3: Option<S3Options>,
+    /// Login settings for an `sftp://` destination
+    #[serde(default)]
+    pub sftp: Option<SftpOptions>,
+}
+
+impl BackupOptions {
+    /// The history mode to use: `history_mode` if set, otherwise `versions`
+    /// unless `keep_versions = false`.
+    pub fn history_mode(&self) -> HistoryMode {
+        match (self.history_mode, self.keep_versions) {
+            (Some(mode), _) => mode,
+            (None, Some(false)) => HistoryMode::None,
+            (None, _) => HistoryMode::Versions,
+        }
+    }
+
+    /// Delta settings for History versions, `None` unless `delta_history` is on.
+    pub fn delta_options(&self) -> Option<DeltaOptions> {
+        self.delta_history.then(|| DeltaOptions {
+            min_size: self.delta_min_size.unwrap_or(history::DEFAULT_MIN_SIZE),
+            max_chain: self.max_delta_chain.unwrap_or(history::DEFAULT_MAX_CHAIN),
+        })
+    }
+
+    /// Size from which modified files are sent as deltas, `None` unless
+    /// `delta_transfer` is on.
+    pub fn delta_transfer_min_size(&self) -> Option<u64> {
+        self.delta_transfer
+            .then(|| self.delta_transfer_min_size.unwrap_or(history::DEFAULT_MIN_SIZE))
+    }
+
+    /// Signatures of delta-transferred copies, in `signature_cache` or the
+    /// user's cache directory. `None` if there is no place to keep them.
+    pub fn signature_cache(&self) -> Option<SignatureCache> {
+        let dir = self.signature_cache.clone().or_else(|| {
+            std::env::var_os("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+                .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+                .map(|cache| cache.join("rustybackup").join("signatures"))
+        })?;
+        Some(SignatureCache::new(dir))
+    }
+
+    /// Settings for copying file data into the destination.
+    pub fn copy_options(&self) -> CopyOptions {
+        CopyOptions {
+            strategy: self.copy_strategy,
+            buffer_size: self
+                .copy_buffer_size
+                .map_or(DEFAULT_BUFFER_SIZE, |size| usize::try_from(size).unwrap_or(usize::MAX)),
+        }
+    }
+}
+
+/// How a destination keeps what a backup run replaces or deletes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HistoryMode {
+    /// Pure mirror: superseded and deleted files are removed
+    None,
+    /// Superseded and deleted files are moved to `History` with a timestamp
+    #[default]
+    Versions,
+    /// The mirror is kept current and every run adds a browsable tree under
+    /// `snapshots/` whose files are hard links into the mirror
+    HardlinkSnapshots,
+}
+
+impl HistoryMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HistoryMode::None => "none",
+            HistoryMode::Versions => "versions",
+            HistoryMode::HardlinkSnapshots => "hardlink-snapshots",
+        }
+    }
+}
+
+impl std::fmt::Display for HistoryMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingOptions {
+    /// Write a log file per backup run to `logs/<snapshot_id>.log` in the destination
+    pub file: bool,
+    /// Most verbose level recorded in the log file
+    pub level: LevelFilter,
+    /// Number of run logs to keep; `None`, or `0` / `false` in the config,
+    /// keeps all of them
+    #[serde(deserialize_with = "keep_runs")]
+    pub keep_runs: Option<usize>,
+    /// Delete run logs older than this many days
+    pub max_age_days: Option<u64>,
+}
+
+impl Default for LoggingOptions {
+    fn default() -> Self {
+        Self {
+            file: false,
+            level: LevelFilter::Debug,
+            keep_runs: Some(DEFAULT_KEEP_RUNS),
+            max_age_days: None,
+        }
+    }
 }
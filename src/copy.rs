@@ -0,0 +1,615 @@
+//! Copying file data into the destination.
+//!
+//! [`copy_file`] tries the fastest method the platform and file systems allow:
+//! a `FICLONE` reflink that shares blocks on Btrfs/XFS, then the in-kernel
+//! `copy_file_range`, then a plain buffered copy. `backup.copy_strategy` can
+//! force a single method. Holes of sparse files are found with
+//! `SEEK_DATA`/`SEEK_HOLE` and skipped, so the copy stays sparse. Backends
+//! without a file system get only the data of a sparse file, and the
+//! [`Extents`] needed to put the holes back are kept in the state.
+
+use crate::cancel::CancellationToken;
+use crate::delta::{self, Signature, SignatureBuilder};
+use crate::error::Error;
+use crate::storage::StorageBackend;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Buffer size of buffered copies unless `backup.copy_buffer_size` is set.
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How `backup.copy_strategy` selects the copy method.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyStrategy {
+    /// Try reflink, then `copy_file_range`, then a buffered copy
+    #[default]
+    Auto,
+    /// Only reflink; files fail where the destination cannot share blocks
+    Reflink,
+    /// Only `copy_file_range`
+    CopyFileRange,
+    /// Only the buffered read/write loop
+    Buffered,
+}
+
+/// The method that actually copied a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMethod {
+    Reflink,
+    CopyFileRange,
+    Buffered,
+    /// Rebuilt from the earlier copy plus the changed blocks of the source
+    Delta,
+}
+
+/// Number of files copied with each [`CopyMethod`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CopyStats {
+    pub reflink: u64,
+    pub copy_file_range: u64,
+    pub buffered: u64,
+    pub delta: u64,
+}
+
+impl CopyStats {
+    pub fn record(&mut self, method: CopyMethod) {
+        match method {
+            CopyMethod::Reflink => self.reflink += 1,
+            CopyMethod::CopyFileRange => self.copy_file_range += 1,
+            CopyMethod::Buffered => self.buffered += 1,
+            CopyMethod::Delta => self.delta += 1,
+        }
+    }
+}
+
+/// Outcome of [`copy_file`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Copied {
+    /// Data actually moved, without the holes of a sparse file
+    pub bytes: u64,
+    /// Length of the file including holes
+    pub logical_bytes: u64,
+    pub method: CopyMethod,
+}
+
+/// Copy settings taken from `[backup]`.
+#[derive(Debug, Clone, Copy)]
+pub struct CopyOptions {
+    pub strategy: CopyStrategy,
+    pub buffer_size: usize,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            strategy: CopyStrategy::Auto,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+/// Copy `src` to `dst` and report the data moved and the method used.
+/// Data is moved in chunks of `buffer_size` so a cancellation request
+/// interrupts large files. Permissions are copied like `fs::copy` does.
+pub fn copy_file(src: &Path, dst: &Path, options: &CopyOptions, cancel: &CancellationToken) -> io::Result<Copied> {
+    let mut reader = File::open(src)?;
+    let metadata = reader.metadata()?;
+    let len = metadata.len();
+    let mut writer = File::create(dst)?;
+    let strategy = options.strategy;
+    let chunk = options.buffer_size.max(1);
+    let segments = fast::data_segments(&reader, &metadata)?;
+
+    let mut method = None;
+    let mut bytes = 0;
+    if matches!(strategy, CopyStrategy::Auto | CopyStrategy::Reflink)
+        && fast::reflink(&reader, &writer, strategy == CopyStrategy::Reflink)?
+    {
+        // The clone shares the extents of the source, holes included
+        method = Some(CopyMethod::Reflink);
+        bytes = segments.as_ref().map_or(len, |s| s.iter().map(|(start, end)| end - start).sum());
+    }
+
+    // A file without holes is one segment that is read until EOF, in case it grows
+    let sparse = segments.is_some();
+    let segments = segments.unwrap_or_else(|| vec![(0, u64::MAX)]);
+    if method.is_none() && matches!(strategy, CopyStrategy::Auto | CopyStrategy::CopyFileRange) {
+        let forced = strategy == CopyStrategy::CopyFileRange;
+        for (idx, (start, end)) in segments.iter().enumerate() {
+            reader.seek(SeekFrom::Start(*start))?;
+            writer.seek(SeekFrom::Start(*start))?;
+            match fast::copy_file_range(&reader, &writer, end - start, chunk, forced && idx == 0, cancel)? {
+                Some(n) => bytes += n,
+                None if idx == 0 => break,
+                None => return Err(io::Error::other("copy_file_range stopped working mid-file")),
+            }
+            method = Some(CopyMethod::CopyFileRange);
+        }
+    }
+    if method.is_none() {
+        for (start, end) in &segments {
+            reader.seek(SeekFrom::Start(*start))?;
+            writer.seek(SeekFrom::Start(*start))?;
+            bytes += buffered(&mut reader, &mut writer, end - start, chunk, cancel)?;
+        }
+        method = Some(CopyMethod::Buffered);
+    }
+
+    // Seeking past the last data segment leaves a trailing hole unwritten
+    if sparse && method != Some(CopyMethod::Reflink) {
+        writer.set_len(len)?;
+    }
+    writer.flush()?;
+    let logical_bytes = writer.metadata()?.len();
+    drop(writer);
+    fs::set_permissions(dst, metadata.permissions())?;
+    Ok(Copied {
+        bytes,
+        logical_bytes,
+        method: method.expect("a copy method ran"),
+    })
+}
+
+/// Layout of a sparse file stored without its holes. The stored object holds
+/// the `data` ranges one after another; everything else is zeros.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extents {
+    /// Length of the file including holes
+    pub len: u64,
+    /// `(start, end)` byte ranges holding data, in file order
+    pub data: Vec<(u64, u64)>,
+}
+
+impl Extents {
+    /// Write the file stored as `packed` to `out`, holes as zeros. Returns the
+    /// length written.
+    pub fn expand(&self, packed: &mut dyn Read, out: &mut dyn Write) -> io::Result<u64> {
+        let mut pos = 0;
+        for (start, end) in self.data.iter().chain([&(self.len, self.len)]) {
+            io::copy(&mut io::repeat(0).take(start - pos), out)?;
+            let copied = io::copy(&mut (&mut *packed).take(end - start), out)?;
+            if copied != end - start {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stored data is shorter than its extents"));
+            }
+            pos = *end;
+        }
+        Ok(self.len)
+    }
+
+    /// Write the file stored as `packed` to `file`, leaving the holes
+    /// unwritten so they stay holes. Returns the data written.
+    pub fn restore(&self, packed: &mut dyn Read, file: &mut File) -> io::Result<u64> {
+        let mut bytes = 0;
+        for (start, end) in &self.data {
+            file.seek(SeekFrom::Start(*start))?;
+            let copied = io::copy(&mut (&mut *packed).take(end - start), file)?;
+            if copied != end - start {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stored data is shorter than its extents"));
+            }
+            bytes += copied;
+        }
+        file.set_len(self.len)?;
+        Ok(bytes)
+    }
+}
+
+/// Store `src` as `key` in a destination without a local file system. The
+/// data is streamed in chunks of `buffer_size`, checking `cancel` between them.
+/// Of a sparse file only the data is stored, and its [`Extents`] are returned.
+pub fn upload(
+    src: &Path,
+    storage: &dyn StorageBackend,
+    key: &Path,
+    options: &CopyOptions,
+    cancel: &CancellationToken,
+) -> io::Result<(Copied, Option<Extents>)> {
+    let file = File::open(src)?;
+    let metadata = file.metadata()?;
+    let extents = fast::data_segments(&file, &metadata)?.map(|data| Extents { len: metadata.len(), data });
+    let mut reader = CancellableReader {
+        inner: BufReader::with_capacity(options.buffer_size.max(1), file),
+        cancel,
+    };
+    let bytes = match &extents {
+        Some(extents) => storage.put(
+            key,
+            &mut SegmentReader {
+                inner: reader,
+                segments: extents.data.iter().copied(),
+                remaining: 0,
+            },
+        )?,
+        None => storage.put(key, &mut reader)?,
+    };
+    let copied = Copied {
+        bytes,
+        logical_bytes: extents.as_ref().map_or(bytes, |e| e.len),
+        method: CopyMethod::Buffered,
+    };
+    Ok((copied, extents))
+}
+
+/// Reads only the given byte ranges of a file, one after another.
+struct SegmentReader<R, I> {
+    inner: R,
+    segments: I,
+    remaining: u64,
+}
+
+impl<R: Read + Seek, I: Iterator<Item = (u64, u64)>> Read for SegmentReader<R, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let Some((start, end)) = self.segments.next() else {
+                return Ok(0);
+            };
+            self.inner.seek(SeekFrom::Start(start))?;
+            self.remaining = end - start;
+        }
+        let limit = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..limit])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while it was stored"));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+struct CancellableReader<'a, R> {
+    inner: R,
+    cancel: &'a CancellationToken,
+}
+
+impl<R: Seek> Seek for CancellableReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<R: Read> Read for CancellableReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Not `Interrupted`, which `io::copy` and `read_to_end` retry
+        if self.cancel.is_cancelled() {
+            return Err(io::Error::other(Error::Cancelled));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// `(device, inode)` of a file with more than one hard link, to store the
+/// data of a link group once. `None` for singly linked files and on platforms
+/// without inode numbers.
+#[cfg(unix)]
+pub fn link_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn link_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Copy `src` to `dst` like rsync updates a file. With a `base`, the earlier
+/// copy in the destination and its [`Signature`], `dst` is assembled from the
+/// matching blocks of that copy plus the source data that changed. Matching
+/// blocks are moved with `copy_file_range` where available, which network file
+/// systems with server-side copy (NFS 4.2, SMB 3) perform without sending the
+/// data; `bytes` counts only the changed data. Without a base every byte is
+/// sent. The signature of the new copy is built from the source data on the
+/// way and returned, so the next run does not need to read `dst` either.
+/// Sparse sources are copied with [`copy_file`] so their holes are kept.
+pub fn delta_copy(
+    src: &Path,
+    base: Option<(&Path, &Signature)>,
+    dst: &Path,
+    options: &CopyOptions,
+    cancel: &CancellationToken,
+) -> io::Result<(Copied, Option<Signature>)> {
+    let reader = File::open(src)?;
+    let metadata = reader.metadata()?;
+    if fast::data_segments(&reader, &metadata)?.is_some() {
+        return Ok((copy_file(src, dst, options, cancel)?, None));
+    }
+
+    let empty;
+    let (mut base_file, signature) = match base {
+        Some((path, signature)) => (Some(File::open(path)?), signature),
+        None => {
+            empty = Signature::empty(delta::block_size(metadata.len()));
+            (None, &empty)
+        }
+    };
+    let mut writer = File::create(dst)?;
+    let chunk = options.buffer_size.clamp(1, DEFAULT_BUFFER_SIZE);
+    let mut source = SigningReader {
+        inner: BufReader::new(reader),
+        builder: SignatureBuilder::new(delta::block_size(metadata.len())),
+    };
+    let mut sent = 0u64;
+    let mut written = 0u64;
+    delta::diff(signature, &mut source, |op| {
+        if cancel.is_cancelled() {
+            return Err(cancelled());
+        }
+        match op {
+            delta::Op::Copy { offset, len } => {
+                let base = base_file.as_mut().expect("copies only come from a base");
+                base.seek(SeekFrom::Start(offset))?;
+                let moved = match fast::copy_file_range(base, &writer, len, chunk, false, cancel)? {
+                    Some(n) => n,
+                    None => buffered(base, &mut writer, len, chunk, cancel)?,
+                };
+                if moved != len {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the earlier copy is shorter than its signature"));
+                }
+                written += len;
+            }
+            delta::Op::Literal(data) => {
+                writer.write_all(data)?;
+                sent += data.len() as u64;
+                written += data.len() as u64;
+            }
+        }
+        Ok(())
+    })?;
+    writer.flush()?;
+    drop(writer);
+    fs::set_permissions(dst, metadata.permissions())?;
+    let copied = Copied {
+        bytes: sent,
+        logical_bytes: written,
+        method: if base_file.is_some() { CopyMethod::Delta } else { CopyMethod::Buffered },
+    };
+    Ok((copied, Some(source.builder.finish())))
+}
+
+/// Reader that builds the signature of everything read through it.
+struct SigningReader<R> {
+    inner: R,
+    builder: SignatureBuilder,
+}
+
+impl<R: Read> Read for SigningReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.builder.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Signatures of the copies written by [`delta_copy`], kept on the source
+/// side. Each is stored with the length and modification time of its copy,
+/// and is only used while the copy still has both.
+#[derive(Debug, Clone)]
+pub struct SignatureCache {
+    dir: PathBuf,
+}
+
+impl SignatureCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn file(&self, stored: &Path) -> PathBuf {
+        let mut hash = delta::Fnv64::default();
+        hash.update(stored.as_os_str().as_encoded_bytes());
+        self.dir.join(format!("{:016x}.sig", hash.finish()))
+    }
+
+    /// The signature saved for the copy at `stored`, if `base` still is that
+    /// copy (it may have been moved to History since).
+    pub fn load(&self, stored: &Path, base: &Path) -> Option<Signature> {
+        let (len, modified) = identity(&fs::metadata(base).ok()?)?;
+        let mut file = BufReader::new(File::open(self.file(stored)).ok()?);
+        let mut header = [0u8; 24];
+        file.read_exact(&mut header).ok()?;
+        let saved_len = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let saved_modified = u128::from_le_bytes(header[8..].try_into().unwrap());
+        if (saved_len, saved_modified) != (len, modified) {
+            return None;
+        }
+        let signature = Signature::read_from(file).ok()?;
+        (signature.len == len).then_some(signature)
+    }
+
+    /// Save `signature` as the one of the copy now at `stored`.
+    pub fn save(&self, stored: &Path, signature: &Signature) -> io::Result<()> {
+        let (len, modified) = identity(&fs::metadata(stored)?)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "no modification time"))?;
+        fs::create_dir_all(&self.dir)?;
+        let path = self.file(stored);
+        let part = path.with_extension("part");
+        let mut writer = BufWriter::new(File::create(&part)?);
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&modified.to_le_bytes())?;
+        signature.write_to(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&part, &path)
+    }
+}
+
+fn identity(metadata: &fs::Metadata) -> Option<(u64, u128)> {
+    let modified = metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_nanos()))
+}
+
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, Error::Cancelled)
+}
+
+/// Copy up to `limit` bytes from the current positions.
+fn buffered(reader: &mut File, writer: &mut File, limit: u64, chunk: usize, cancel: &CancellationToken) -> io::Result<u64> {
+    let mut buffer = vec![0u8; chunk];
+    let mut copied = 0u64;
+    while copied < limit {
+        if cancel.is_cancelled() {
+            return Err(cancelled());
+        }
+        let want = usize::try_from(limit - copied).unwrap_or(usize::MAX).min(chunk);
+        let n = match reader.read(&mut buffer[..want]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..n])?;
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+#[cfg(target_os = "linux")]
+mod fast {
+    use super::cancelled;
+    use crate::cancel::CancellationToken;
+    use std::fs::{File, Metadata};
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::MetadataExt;
+
+    /// Errors meaning "this method does not work here", after which `auto`
+    /// falls back to the next method.
+    fn unsupported(e: &io::Error) -> bool {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY | libc::ENOSYS | libc::EPERM | libc::EBADF)
+        )
+    }
+
+    /// Byte ranges of `file` that hold data, or `None` if it has no holes or
+    /// the file system cannot tell.
+    pub fn data_segments(file: &File, metadata: &Metadata) -> io::Result<Option<Vec<(u64, u64)>>> {
+        let len = metadata.len();
+        // Fewer allocated blocks than the length needs is the sign of a hole
+        if metadata.blocks().saturating_mul(512) >= len {
+            return Ok(None);
+        }
+        let fd = file.as_raw_fd();
+        let seek = |offset: u64, whence| {
+            // SAFETY: the descriptor is open; lseek only moves its position
+            let pos = unsafe { libc::lseek(fd, offset as libc::off_t, whence) };
+            if pos < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(pos as u64)
+            }
+        };
+
+        let mut segments = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let start = match seek(pos, libc::SEEK_DATA) {
+                Ok(start) => start,
+                // No data after `pos`: the rest of the file is a hole
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let end = seek(start, libc::SEEK_HOLE)?.min(len);
+            segments.push((start, end));
+            pos = end;
+        }
+        Ok(Some(segments))
+    }
+
+    /// Share all blocks of `reader` with `writer`. `Ok(false)` if the file
+    /// systems cannot and `forced` is not set.
+    pub fn reflink(reader: &File, writer: &File, forced: bool) -> io::Result<bool> {
+        // SAFETY: both descriptors are open for the duration of the call
+        let rc = unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) };
+        if rc == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        if forced || !unsupported(&e) {
+            return Err(e);
+        }
+        Ok(false)
+    }
+
+    /// Copy up to `limit` bytes from the current positions with
+    /// `copy_file_range` in chunks. `Ok(None)` if the kernel refuses before
+    /// any data was moved and `forced` is not set.
+    pub fn copy_file_range(
+        reader: &File,
+        writer: &File,
+        limit: u64,
+        chunk: usize,
+        forced: bool,
+        cancel: &CancellationToken,
+    ) -> io::Result<Option<u64>> {
+        let mut copied = 0u64;
+        while copied < limit {
+            if cancel.is_cancelled() {
+                return Err(cancelled());
+            }
+            let want = usize::try_from(limit - copied).unwrap_or(usize::MAX).min(chunk);
+            // SAFETY: both descriptors are open, null offsets use the file positions
+            let n = unsafe {
+                libc::copy_file_range(
+                    reader.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    writer.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    want,
+                    0,
+                )
+            };
+            match n {
+                0 => break,
+                n if n > 0 => copied += n as u64,
+                _ => {
+                    let e = io::Error::last_os_error();
+                    match e.kind() {
+                        io::ErrorKind::Interrupted => continue,
+                        _ if copied == 0 && !forced && unsupported(&e) => return Ok(None),
+                        _ => return Err(e),
+                    }
+                }
+            }
+        }
+        Ok(Some(copied))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod fast {
+    use crate::cancel::CancellationToken;
+    use std::fs::{File, Metadata};
+    use std::io;
+
+    pub fn data_segments(_file: &File, _metadata: &Metadata) -> io::Result<Option<Vec<(u64, u64)>>> {
+        Ok(None)
+    }
+
+    fn unavailable(method: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, format!("{method} is only available on Linux"))
+    }
+
+    pub fn reflink(_reader: &File, _writer: &File, forced: bool) -> io::Result<bool> {
+        if forced {
+            return Err(unavailable("reflink"));
+        }
+        Ok(false)
+    }
+
+    pub fn copy_file_range(
+        _reader: &File,
+        _writer: &File,
+        _limit: u64,
+        _chunk: usize,
+        forced: bool,
+        _cancel: &CancellationToken,
+    ) -> io::Result<Option<u64>> {
+        if forced {
+            return Err(unavailable("copy_file_range"));
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,501 @@
+//! Block-matching binary deltas.
+//!
+//! A [`Signature`] indexes the blocks of a base file by a rolling weak
+//! checksum and a strong hash. [`diff`] slides over a target stream and
+//! describes it as copies of base blocks and literal data, the scheme rsync
+//! uses. [`write_delta`] and [`apply_delta`] store such a description in a
+//! file and rebuild the target from it. [`Signature::write_to`] and
+//! [`Signature::read_from`] keep a signature so it can be reused without
+//! reading its base again.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"RBDELTA1";
+const SIGNATURE_MAGIC: &[u8; 8] = b"RBSIG001";
+const OP_COPY: u8 = b'C';
+const OP_LITERAL: u8 = b'L';
+const OP_END: u8 = b'E';
+
+const MIN_BLOCK: usize = 2 * 1024;
+const MAX_BLOCK: usize = 128 * 1024;
+/// Target data read at once while matching.
+const READ_CHUNK: usize = 1024 * 1024;
+/// Pending literal data is emitted in pieces of at most this size.
+const MAX_LITERAL: usize = 4 * 1024 * 1024;
+
+/// Block size for a base of `len` bytes: about the square root of the length,
+/// so large files get few signatures and small files fine-grained matches.
+pub fn block_size(len: u64) -> usize {
+    ((len as f64).sqrt() as usize).next_multiple_of(64).clamp(MIN_BLOCK, MAX_BLOCK)
+}
+
+/// 64-bit FNV-1a, the strong block hash and the whole-file check sum.
+#[derive(Debug, Clone, Copy)]
+pub struct Fnv64(u64);
+
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv64 {
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(self) -> u64 {
+        self.0
+    }
+}
+
+fn fnv64(data: &[u8]) -> u64 {
+    let mut hash = Fnv64::default();
+    hash.update(data);
+    hash.finish()
+}
+
+/// rsync's rolling checksum over a window of fixed length.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, byte) in window.iter().enumerate() {
+            a = a.wrapping_add(u32::from(*byte));
+            b = b.wrapping_add((len - i as u32).wrapping_mul(u32::from(*byte)));
+        }
+        Self { a, b, len }
+    }
+
+    /// Move the window one byte forward.
+    fn roll(&mut self, out: u8, incoming: u8) {
+        self.a = self.a.wrapping_sub(u32::from(out)).wrapping_add(u32::from(incoming));
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(u32::from(out))).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Block index of a base file.
+pub struct Signature {
+    pub block_size: usize,
+    /// Length of the base
+    pub len: u64,
+    /// FNV-1a hash of the whole base
+    pub hash: u64,
+    blocks: HashMap<u32, Vec<(u64, u64)>>,
+}
+
+impl Signature {
+    /// Read `base` and index each full block of `block_size` bytes.
+    pub fn new(mut base: impl Read, block_size: usize) -> io::Result<Self> {
+        let mut builder = SignatureBuilder::new(block_size);
+        let mut block = vec![0u8; block_size];
+        loop {
+            let n = read_full(&mut base, &mut block)?;
+            builder.update(&block[..n]);
+            if n < block_size {
+                break;
+            }
+        }
+        Ok(builder.finish())
+    }
+
+    /// A signature of an empty base, against which every target is literal.
+    pub fn empty(block_size: usize) -> Self {
+        SignatureBuilder::new(block_size).finish()
+    }
+
+    /// Serialize the signature.
+    pub fn write_to(&self, mut out: impl Write) -> io::Result<()> {
+        out.write_all(SIGNATURE_MAGIC)?;
+        let count: usize = self.blocks.values().map(Vec::len).sum();
+        for value in [self.block_size as u64, self.len, self.hash, count as u64] {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        for (weak, entries) in &self.blocks {
+            for (offset, strong) in entries {
+                out.write_all(&weak.to_le_bytes())?;
+                out.write_all(&offset.to_le_bytes())?;
+                out.write_all(&strong.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a signature written by [`write_to`](Self::write_to).
+    pub fn read_from(input: impl Read) -> io::Result<Self> {
+        let mut input = BufReader::new(input);
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != SIGNATURE_MAGIC {
+            return Err(invalid("not a signature file"));
+        }
+        let block_size = usize::try_from(read_u64(&mut input)?).map_err(|_| invalid("block size out of range"))?;
+        let len = read_u64(&mut input)?;
+        let hash = read_u64(&mut input)?;
+        let count = read_u64(&mut input)?;
+        let mut blocks: HashMap<u32, Vec<(u64, u64)>> = HashMap::new();
+        for _ in 0..count {
+            let mut weak = [0u8; 4];
+            input.read_exact(&mut weak)?;
+            let offset = read_u64(&mut input)?;
+            let strong = read_u64(&mut input)?;
+            blocks.entry(u32::from_le_bytes(weak)).or_default().push((offset, strong));
+        }
+        // Prefer the earliest block among equal ones, as `new` does
+        for entries in blocks.values_mut() {
+            entries.sort_unstable();
+        }
+        Ok(Self {
+            block_size,
+            len,
+            hash,
+            blocks,
+        })
+    }
+
+    /// Offset of a base block equal to `window`, preferring `next` so that
+    /// consecutive matches merge into one copy.
+    fn find(&self, weak: u32, window: &[u8], next: Option<u64>) -> Option<u64> {
+        let candidates = self.blocks.get(&weak)?;
+        let strong = fnv64(window);
+        let mut found = None;
+        for (offset, hash) in candidates {
+            if *hash != strong {
+                continue;
+            }
+            if Some(*offset) == next {
+                return Some(*offset);
+            }
+            found.get_or_insert(*offset);
+        }
+        found
+    }
+}
+
+/// Builds a [`Signature`] from data passed in pieces, e.g. while the same
+/// data is being copied.
+pub struct SignatureBuilder {
+    signature: Signature,
+    hash: Fnv64,
+    /// Start of the next block not indexed yet
+    pending: Vec<u8>,
+}
+
+impl SignatureBuilder {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            signature: Signature {
+                block_size,
+                len: 0,
+                hash: 0,
+                blocks: HashMap::new(),
+            },
+            hash: Fnv64::default(),
+            pending: Vec::with_capacity(block_size),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        let bs = self.signature.block_size;
+        self.hash.update(data);
+        while !data.is_empty() {
+            let n = (bs - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.pending.len() == bs {
+                let weak = Rolling::new(&self.pending).digest();
+                let offset = self.signature.len;
+                self.signature.blocks.entry(weak).or_default().push((offset, fnv64(&self.pending)));
+                self.signature.len += bs as u64;
+                self.pending.clear();
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Signature {
+        self.signature.len += self.pending.len() as u64;
+        self.signature.hash = self.hash.finish();
+        self.signature
+    }
+}
+
+/// One piece of a target described against a base.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Op<'a> {
+    /// `len` bytes of the base starting at `offset`
+    Copy { offset: u64, len: u64 },
+    /// Data that does not occur in the base
+    Literal(&'a [u8]),
+}
+
+/// Describe `target` as copies from the base of `signature` and literals,
+/// passing each piece to `emit` in order.
+pub fn diff(signature: &Signature, mut target: impl Read, mut emit: impl FnMut(Op) -> io::Result<()>) -> io::Result<()> {
+    let bs = signature.block_size;
+    let mut buf: Vec<u8> = Vec::new();
+    // Start of the window and of the literal data not emitted yet
+    let mut pos = 0;
+    let mut lit = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    let mut copy: Option<(u64, u64)> = None;
+
+    fn flush_copy(copy: &mut Option<(u64, u64)>, emit: &mut impl FnMut(Op) -> io::Result<()>) -> io::Result<()> {
+        match copy.take() {
+            Some((offset, len)) => emit(Op::Copy { offset, len }),
+            None => Ok(()),
+        }
+    }
+
+    loop {
+        // Keep one byte beyond the window so it can roll
+        if buf.len() < pos + bs + 1 && !eof {
+            buf.drain(..lit);
+            pos -= lit;
+            lit = 0;
+            let start = buf.len();
+            let requested = READ_CHUNK.max(bs + 1);
+            buf.resize(start + requested, 0);
+            let n = read_full(&mut target, &mut buf[start..])?;
+            buf.truncate(start + n);
+            eof = n < requested;
+            continue;
+        }
+        if buf.len() < pos + bs {
+            break;
+        }
+        if pos - lit >= MAX_LITERAL {
+            flush_copy(&mut copy, &mut emit)?;
+            emit(Op::Literal(&buf[lit..pos]))?;
+            lit = pos;
+        }
+
+        let window = &buf[pos..pos + bs];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let next = copy.map(|(offset, len)| offset + len);
+        if let Some(offset) = signature.find(weak, window, next) {
+            if lit < pos {
+                flush_copy(&mut copy, &mut emit)?;
+                emit(Op::Literal(&buf[lit..pos]))?;
+            }
+            match &mut copy {
+                Some((_, len)) if next == Some(offset) => *len += bs as u64,
+                _ => {
+                    flush_copy(&mut copy, &mut emit)?;
+                    copy = Some((offset, bs as u64));
+                }
+            }
+            pos += bs;
+            lit = pos;
+            rolling = None;
+        } else {
+            match (&mut rolling, buf.get(pos + bs)) {
+                (Some(r), Some(incoming)) => r.roll(buf[pos], *incoming),
+                _ => rolling = None,
+            }
+            pos += 1;
+        }
+    }
+
+    flush_copy(&mut copy, &mut emit)?;
+    if lit < buf.len() {
+        emit(Op::Literal(&buf[lit..]))?;
+    }
+    Ok(())
+}
+
+/// Sizes and check sums recorded in a delta file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaHeader {
+    pub base_len: u64,
+    pub base_hash: u64,
+    pub target_len: u64,
+    pub target_hash: u64,
+}
+
+/// Write a delta that rebuilds `target` from `base` to `out` and return its
+/// header and the size of the delta file.
+pub fn write_delta(base: &Path, target: &Path, out: &Path) -> io::Result<(DeltaHeader, u64)> {
+    let base_len = base.metadata()?.len();
+    let signature = Signature::new(BufReader::new(File::open(base)?), block_size(base_len))?;
+
+    let mut writer = BufWriter::new(File::create(out)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[0u8; 32])?;
+
+    let mut reader = HashingReader::new(File::open(target)?);
+    diff(&signature, &mut reader, |op| match op {
+        Op::Copy { offset, len } => {
+            writer.write_all(&[OP_COPY])?;
+            writer.write_all(&offset.to_le_bytes())?;
+            writer.write_all(&len.to_le_bytes())
+        }
+        Op::Literal(data) => {
+            writer.write_all(&[OP_LITERAL])?;
+            writer.write_all(&(data.len() as u64).to_le_bytes())?;
+            writer.write_all(data)
+        }
+    })?;
+    writer.write_all(&[OP_END])?;
+
+    let header = DeltaHeader {
+        base_len: signature.len,
+        base_hash: signature.hash,
+        target_len: reader.len,
+        target_hash: reader.hash.finish(),
+    };
+    let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+    for value in [header.base_len, header.base_hash, header.target_len, header.target_hash] {
+        file.write_all(&value.to_le_bytes())?;
+    }
+    let size = file.seek(SeekFrom::End(0))?;
+    file.sync_all()?;
+    Ok((header, size))
+}
+
+/// Read the header of a delta file.
+pub fn read_header(mut delta: impl Read) -> io::Result<DeltaHeader> {
+    let mut magic = [0u8; 8];
+    delta.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a delta file"));
+    }
+    Ok(DeltaHeader {
+        base_len: read_u64(&mut delta)?,
+        base_hash: read_u64(&mut delta)?,
+        target_len: read_u64(&mut delta)?,
+        target_hash: read_u64(&mut delta)?,
+    })
+}
+
+/// Rebuild the target of `delta` from `base` into `out`. Both the base and
+/// the rebuilt data are checked against the sums in the header, so a changed
+/// base or a damaged delta is reported as `InvalidData`.
+pub fn apply_delta(delta: impl Read, base: &mut File, out: &mut dyn Write) -> io::Result<u64> {
+    let mut delta = BufReader::new(delta);
+    let header = read_header(&mut delta)?;
+
+    let mut base_hash = Fnv64::default();
+    let mut base_len = 0u64;
+    base.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(&mut *base);
+    let mut buf = vec![0u8; READ_CHUNK];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        base_hash.update(&buf[..n]);
+        base_len += n as u64;
+    }
+    if base_len != header.base_len || base_hash.finish() != header.base_hash {
+        return Err(invalid("the base version does not match the delta"));
+    }
+
+    let mut hash = Fnv64::default();
+    let mut written = 0u64;
+    loop {
+        let mut op = [0u8; 1];
+        delta.read_exact(&mut op)?;
+        match op[0] {
+            OP_END => break,
+            OP_COPY => {
+                let offset = read_u64(&mut delta)?;
+                let len = read_u64(&mut delta)?;
+                base.seek(SeekFrom::Start(offset))?;
+                copy_exact(&mut *base, out, len, &mut buf, &mut hash)?;
+                written += len;
+            }
+            OP_LITERAL => {
+                let len = read_u64(&mut delta)?;
+                copy_exact(&mut delta, out, len, &mut buf, &mut hash)?;
+                written += len;
+            }
+            _ => return Err(invalid("unknown delta operation")),
+        }
+    }
+    if written != header.target_len || hash.finish() != header.target_hash {
+        return Err(invalid("the rebuilt data does not match the delta check sum"));
+    }
+    Ok(written)
+}
+
+fn copy_exact(from: &mut dyn Read, to: &mut dyn Write, len: u64, buf: &mut [u8], hash: &mut Fnv64) -> io::Result<()> {
+    let mut left = len;
+    while left > 0 {
+        let want = usize::try_from(left).unwrap_or(usize::MAX).min(buf.len());
+        from.read_exact(&mut buf[..want])?;
+        hash.update(&buf[..want]);
+        to.write_all(&buf[..want])?;
+        left -= want as u64;
+    }
+    Ok(())
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Fill `buf` as far as the reader allows; fewer bytes only at EOF.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reader that hashes and counts everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hash: Fnv64,
+    len: u64,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hash: Fnv64::default(),
+            len: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hash.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
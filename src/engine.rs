@@ -0,0 +1,126 @@
+use crate::backup::{self, BackupReport, ScanReport, StatusReport, VacuumReport};
+use crate::cancel::CancellationToken;
+use crate::config::Config;
+use crate::exclude::{self, Explanation};
+use crate::find::{self, FindQuery, FoundVersion};
+use crate::observer::{BackupObserver, NoopObserver};
+use crate::restore::{self, RestoreReport};
+use crate::error::Result;
+use crate::storage::{self, StorageBackend};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Entry point for embedding rustybackup.
+///
+/// The engine runs the same operations as the command line tool but never
+/// prints. Progress is reported to a [`BackupObserver`] and every operation
+/// returns a structured report.
+///
+/// ```no_run
+/// # fn demo(config: &rustybackup::Config) -> rustybackup::Result<()> {
+/// use rustybackup::BackupEngine;
+///
+/// let report = BackupEngine::new(config).backup()?;
+/// println!("copied {} files", report.files_copied);
+/// # Ok(())
+/// # }
+/// ```
+pub struct BackupEngine<'a> {
+    config: &'a Config,
+    observer: &'a dyn BackupObserver,
+    cancel: CancellationToken,
+    storage: Option<Arc<dyn StorageBackend>>,
+}
+
+impl<'a> BackupEngine<'a> {
+    /// Create an engine for `config` that discards progress events.
+    pub fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            observer: &NoopObserver,
+            cancel: CancellationToken::new(),
+            storage: None,
+        }
+    }
+
+    /// Report progress to `observer`.
+    pub fn with_observer(mut self, observer: &'a dyn BackupObserver) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Stop running operations once `token` is cancelled.
+    ///
+    /// A cancelled backup removes the partially written file, saves its
+    /// progress for the next run to resume and returns
+    /// [`Error::Cancelled`](crate::Error::Cancelled).
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Store backups in `storage` instead of the local directory named by
+    /// `backup.destination`.
+    pub fn with_storage(mut self, storage: Arc<dyn StorageBackend>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        self.config
+    }
+
+    pub fn observer(&self) -> &dyn BackupObserver {
+        self.observer
+    }
+
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    /// The destination backups are stored in. Without an explicit storage the
+    /// one named by `backup.destination` is opened, see [`storage::open`].
+    pub fn storage(&self, create: bool) -> Result<Arc<dyn StorageBackend>> {
+        match &self.storage {
+            Some(storage) => Ok(Arc::clone(storage)),
+            None => storage::open(&self.config.backup, create),
+        }
+    }
+
+    /// List files changed since the last backup. With `fullscan`, files
+    /// missing from the destination are included as well.
+    pub fn scan(&self, fullscan: bool) -> Result<ScanReport> {
+        backup::execute_scan(self, fullscan)
+    }
+
+    /// Report whether `path` is backed up and which exclude rule decided it.
+    pub fn explain(&self, path: &Path) -> Result<Explanation> {
+        exclude::explain(&self.config.paths, self.storage(false)?.location(), path)
+    }
+
+    /// Copy changed files to the destination and move deleted ones to `History`.
+    pub fn backup(&self) -> Result<BackupReport> {
+        backup::execute_backup(self)
+    }
+
+    /// Delete `History` versions beyond `max_versions`.
+    pub fn vacuum(&self) -> Result<VacuumReport> {
+        backup::execute_vacuum(self)
+    }
+
+    /// Report the latest backup and up to `log_lines` lines of its run log.
+    pub fn status(&self, log_lines: usize) -> Result<StatusReport> {
+        backup::execute_status(self, log_lines)
+    }
+
+    /// Copy the live mirror of every include root, or only of `root` (its path
+    /// or folder), to `target`, recreating hard links between the files.
+    pub fn restore(&self, target: &Path, root: Option<&str>) -> Result<RestoreReport> {
+        restore::execute_restore(self, target, root)
+    }
+
+    /// Search the destination for stored versions matching `query`.
+    pub fn find(&self, query: &FindQuery) -> Result<Vec<FoundVersion>> {
+        find::find_in(&*self.storage(false)?, self.config, query)
+    }
+}
@@ -0,0 +1,157 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Errors returned by the rustybackup library.
+///
+/// Variants that concern a file carry the affected path and the underlying
+/// error, so callers can tell an unmounted destination from a corrupt state
+/// file without matching on messages.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The configuration file could not be read.
+    #[error("cannot read config file {}", path.display())]
+    ConfigRead {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The configuration file is not valid TOML or has the wrong shape.
+    #[error("invalid config file {}", path.display())]
+    ConfigParse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    /// The configuration file parses but is inconsistent, e.g. two jobs
+    /// share a name.
+    #[error("invalid config file {}: {message}", path.display())]
+    InvalidConfig { path: PathBuf, message: String },
+
+    /// An exclude glob or search regex does not compile.
+    #[error("invalid pattern '{pattern}'")]
+    InvalidPattern {
+        pattern: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// An include root cannot be given the destination folder it asks for.
+    #[error("include root '{root}' cannot use folder '{folder}': {reason}")]
+    RootConflict {
+        root: String,
+        folder: String,
+        reason: String,
+    },
+
+    /// A user supplied value such as a date or size could not be parsed.
+    #[error("{0}")]
+    InvalidArgument(String),
+
+    /// The backup destination does not exist and cannot be created or accessed,
+    /// e.g. because a network drive is not mounted.
+    #[error("backup destination {} is not available; is the drive mounted?", path.display())]
+    DestinationUnavailable {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The backup destination exists but is not a directory.
+    #[error("backup destination {} is not a directory", path.display())]
+    DestinationNotDirectory { path: PathBuf },
+
+    /// Another backup is writing to the destination.
+    #[error("backup destination is in use by another run (lock {})", path.display())]
+    DestinationLocked { path: PathBuf },
+
+    /// `state.toml` or the `.incomplete` progress file cannot be parsed.
+    #[error("state file {} is corrupt", path.display())]
+    StateCorrupt {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    /// A History version stored as a delta cannot be rebuilt, because the
+    /// delta or a version it depends on is damaged or missing.
+    #[error("stored version {} cannot be rebuilt", path.display())]
+    CorruptVersion {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// A source directory or file cannot be read.
+    #[error("cannot read source {}", path.display())]
+    SourceUnreadable {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The destination ran out of space while writing `path`.
+    #[error("out of space while writing {}", path.display())]
+    OutOfSpace {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// Any other I/O failure on `path`.
+    #[error("I/O error on {}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// State could not be serialized before writing it to `path`.
+    #[error("cannot serialize state for {}", path.display())]
+    Serialize {
+        path: PathBuf,
+        #[source]
+        source: toml::ser::Error,
+    },
+
+    /// The operation was stopped through a
+    /// [`CancellationToken`](crate::CancellationToken). Progress made so far
+    /// is saved.
+    #[error("operation cancelled")]
+    Cancelled,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Wrap an I/O error that happened while accessing `path`. Full disks are
+    /// reported as [`Error::OutOfSpace`].
+    pub fn io(path: impl AsRef<Path>, source: io::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        if is_out_of_space(&source) {
+            Error::OutOfSpace { path, source }
+        } else {
+            Error::Io { path, source }
+        }
+    }
+}
+
+/// Whether `error` means the target file system is full.
+pub fn is_out_of_space(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+    )
+}
+
+/// Attach a path to I/O results, see [`Error::io`].
+pub(crate) trait IoResultExt<T> {
+    fn at(self, path: impl AsRef<Path>) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn at(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|e| Error::io(path, e))
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::warn;
+use serde::Serialize;
+use crate::config::{BackupPaths, IncludeRoot};
+use crate::error::{Error, Result};
+use crate::presets;
+
+/// Per-directory ignore file that is always honored during the walk.
+pub const BACKUPIGNORE: &str = ".backupignore";
+
+/// Tag file marking a cache directory, see <https://bford.info/cachedir/>.
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Rule source reported for mount points skipped by `one_file_system`.
+pub const ONE_FILE_SYSTEM: &str = "one_file_system";
+
+/// Rule source reported for the backup destination inside an include root.
+pub const DESTINATION: &str = "backup.destination";
+
+/// Rule source reported for an include root nested inside another one, whose
+/// files are backed up with the inner root only.
+pub const NESTED_ROOT: &str = "nested include";
+
+/// Extended attribute that excludes a file or directory when
+/// `paths.nobackup_xattr` is set.
+pub const NOBACKUP_XATTR: &str = "user.nobackup";
+
+/// The rule that decided whether a path is backed up.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rule {
+    /// Where the rule comes from: `paths.exclude`, `preset:<name>`,
+    /// `paths.ignore`, the root's `ignore`, or the path of an ignore file
+    pub source: String,
+    /// The pattern as written
+    pub pattern: String,
+    /// Whether the rule is a `!negation` that re-includes the path
+    pub negated: bool,
+}
+
+/// Outcome of checking a path against the exclude rules of its root.
+#[derive(Debug, Clone, Serialize)]
+pub struct Decision {
+    pub excluded: bool,
+    /// `None` if no rule matched and the path is included by default
+    pub rule: Option<Rule>,
+}
+
+impl Decision {
+    fn included() -> Self {
+        Self { excluded: false, rule: None }
+    }
+
+    fn excluded_by(source: &str, pattern: impl Into<String>) -> Self {
+        Self {
+            excluded: true,
+            rule: Some(Rule { source: source.to_string(), pattern: pattern.into(), negated: false }),
+        }
+    }
+}
+
+/// Exclude rules for one include root.
+///
+/// `paths.exclude` (and the root's `exclude`) are globs matched against the
+/// absolute path and cannot be overridden. `paths.exclude_presets`,
+/// `paths.ignore` and the root's `ignore` use gitignore syntax relative to the
+/// root, in that order, and `.backupignore`
+/// files, plus `.gitignore` files if `paths.use_gitignore` is set, add rules
+/// for their directory. Rules in deeper directories take precedence.
+///
+/// Paths that pass these rules can still be excluded by their metadata, see
+/// [`ExcludeRules::check_entry`].
+pub struct ExcludeRules {
+    root: PathBuf,
+    globs: GlobSet,
+    glob_patterns: Vec<(String, String)>,
+    config: Gitignore,
+    use_gitignore: bool,
+    dir_rules: HashMap<PathBuf, Vec<Gitignore>>,
+    max_file_size: Option<u64>,
+    min_file_size: Option<u64>,
+    skip_unmodified_days: Option<u64>,
+    modified_after: Option<SystemTime>,
+    exclude_caches: bool,
+    marker_files: Vec<String>,
+    nobackup_xattr: bool,
+    /// Device of the root when `one_file_system` is in effect
+    root_device: Option<u64>,
+    /// Directories below the root that are never entered, with their rule
+    skip_dirs: Vec<(PathBuf, Rule)>,
+}
+
+impl ExcludeRules {
+    pub fn new(root: &IncludeRoot, paths: &BackupPaths) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        let mut glob_patterns = Vec::new();
+        let root_source = format!("include '{}'", root.path);
+        let sources = std::iter::repeat("paths.exclude".to_string())
+            .zip(&paths.exclude)
+            .chain(std::iter::repeat(format!("{root_source} exclude")).zip(&root.exclude));
+        for (source, pattern) in sources {
+            builder.add(Glob::new(pattern).map_err(|e| Error::InvalidPattern {
+                pattern: pattern.clone(),
+                source: Box::new(e),
+            })?);
+            glob_patterns.push((source, pattern.clone()));
+        }
+        let globs = builder.build().map_err(|e| Error::InvalidPattern {
+            pattern: paths.exclude.join(", "),
+            source: Box::new(e),
+        })?;
+
+        let mut preset_lines = Vec::new();
+        for name in &paths.exclude_presets {
+            let patterns = presets::patterns(name).ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "Unknown exclude preset '{}'; available presets: {}",
+                    name,
+                    presets::names().join(", ")
+                ))
+            })?;
+            preset_lines.extend(patterns.iter().map(|p| (presets::source(name), p.to_string())));
+        }
+
+        // Later rules win, so the config can re-include what a preset excluded
+        // and root rules what a global rule excluded
+        let mut config = GitignoreBuilder::new(&root.path);
+        let lines = preset_lines.into_iter().chain(
+            std::iter::repeat("paths.ignore".to_string())
+                .zip(paths.ignore.iter().cloned())
+                .chain(std::iter::repeat(format!("{root_source} ignore")).zip(root.ignore.iter().cloned())),
+        );
+        for (source, line) in lines {
+            config
+                .add_line(Some(PathBuf::from(source)), &line)
+                .map_err(|e| Error::InvalidPattern {
+                    pattern: line,
+                    source: Box::new(e),
+                })?;
+        }
+        let config = config.build().map_err(|e| Error::InvalidPattern {
+            pattern: paths.ignore.join(", "),
+            source: Box::new(e),
+        })?;
+
+        Ok(Self {
+            root: PathBuf::from(&root.path),
+            globs,
+            glob_patterns,
+            config,
+            use_gitignore: paths.use_gitignore,
+            dir_rules: HashMap::new(),
+            max_file_size: paths.max_file_size,
+            min_file_size: paths.min_file_size,
+            skip_unmodified_days: paths.skip_unmodified_days,
+            modified_after: paths
+                .skip_unmodified_days
+                .and_then(|days| SystemTime::now().checked_sub(Duration::from_secs(days * 24 * 60 * 60))),
+            exclude_caches: paths.exclude_caches,
+            marker_files: paths.marker_files.clone(),
+            nobackup_xattr: paths.nobackup_xattr,
+            root_device: if root.one_file_system(paths.one_file_system) {
+                std::fs::metadata(&root.path).ok().and_then(|m| device_id(&m))
+            } else {
+                None
+            },
+            skip_dirs: Vec::new(),
+        })
+    }
+
+    /// Rules for `root` that also leave out `destination` and include roots
+    /// nested inside `root`, warning about each overlap.
+    ///
+    /// Backing up the destination would copy the previous backup on every
+    /// run, and a nested root would be stored twice under different folders.
+    pub fn for_root(root: &IncludeRoot, paths: &BackupPaths, destination: &Path) -> Result<Self> {
+        let mut rules = Self::new(root, paths)?;
+        let root_path = Path::new(&root.path);
+        if let Some(dir) = inside(root_path, destination, true) {
+            warn!(
+                "Destination {} lies inside include root {}; it is excluded from the backup",
+                destination.display(),
+                root.path
+            );
+            rules.skip_dir(dir, DESTINATION, &destination.to_string_lossy());
+        }
+        for other in &paths.include {
+            if let Some(dir) = inside(root_path, Path::new(&other.path), false) {
+                warn!(
+                    "Include root {} lies inside include root {}; its files are backed up with {} only",
+                    other.path, root.path, other.path
+                );
+                rules.skip_dir(dir, NESTED_ROOT, &other.path);
+            }
+        }
+        Ok(rules)
+    }
+
+    fn skip_dir(&mut self, dir: PathBuf, source: &str, pattern: &str) {
+        let rule = Rule { source: source.to_string(), pattern: pattern.to_string(), negated: false };
+        self.skip_dirs.push((dir, rule));
+    }
+
+    /// Check `path` by its own name only, assuming its parent directories are
+    /// included. This is what the walk uses, since it never descends into an
+    /// excluded directory.
+    pub fn check(&mut self, path: &Path, is_dir: bool) -> Decision {
+        if let Some((_, rule)) = self.skip_dirs.iter().find(|(dir, _)| path.starts_with(dir)) {
+            return Decision { excluded: true, rule: Some(rule.clone()) };
+        }
+        if let Some(idx) = self.globs.matches(path).first() {
+            let (source, pattern) = &self.glob_patterns[*idx];
+            return Decision {
+                excluded: true,
+                rule: Some(Rule { source: source.clone(), pattern: pattern.clone(), negated: false }),
+            };
+        }
+        if path == self.root {
+            return Decision::included();
+        }
+
+        let mut dir = path.parent();
+        while let Some(d) = dir {
+            if !d.starts_with(&self.root) {
+                break;
+            }
+            for rules in self.rules_in(d) {
+                if let Some(decision) = decide(rules.matched(path, is_dir)) {
+                    return decision;
+                }
+            }
+            dir = d.parent();
+        }
+        decide(self.config.matched(path, is_dir)).unwrap_or_else(Decision::included)
+    }
+
+    /// Like [`check`](Self::check), then apply the size, age, cache tag,
+    /// marker file and `user.nobackup` settings to an included path.
+    pub fn check_entry(&mut self, path: &Path, metadata: &Metadata) -> Decision {
+        let decision = self.check(path, metadata.is_dir());
+        if decision.excluded {
+            return decision;
+        }
+        self.check_metadata(path, metadata).unwrap_or(decision)
+    }
+
+    fn check_metadata(&self, path: &Path, metadata: &Metadata) -> Option<Decision> {
+        if metadata.is_dir() {
+            if self.root_device.is_some() && device_id(metadata) != self.root_device {
+                return Some(Decision::excluded_by(ONE_FILE_SYSTEM, "mount point"));
+            }
+            if self.exclude_caches && is_cache_dir(path) {
+                return Some(Decision::excluded_by("paths.exclude_caches", CACHEDIR_TAG));
+            }
+            if let Some(marker) = self.marker_files.iter().find(|m| path.join(m).exists()) {
+                return Some(Decision::excluded_by("paths.marker_files", marker.as_str()));
+            }
+        } else if metadata.is_file() {
+            let size = metadata.len();
+            if let Some(max) = self.max_file_size.filter(|max| size > *max) {
+                return Some(Decision::excluded_by("paths.max_file_size", max.to_string()));
+            }
+            if let Some(min) = self.min_file_size.filter(|min| size < *min) {
+                return Some(Decision::excluded_by("paths.min_file_size", min.to_string()));
+            }
+            if let (Some(after), Ok(modified)) = (self.modified_after, metadata.modified()) {
+                if modified < after {
+                    let days = self.skip_unmodified_days.unwrap_or_default();
+                    return Some(Decision::excluded_by("paths.skip_unmodified_days", days.to_string()));
+                }
+            }
+        }
+        if self.nobackup_xattr && has_nobackup_xattr(path) {
+            return Some(Decision::excluded_by("paths.nobackup_xattr", NOBACKUP_XATTR));
+        }
+        None
+    }
+
+    /// Check `path` and every directory between the root and `path`, reporting
+    /// the rule that excludes the first excluded one.
+    pub fn explain(&mut self, path: &Path) -> Decision {
+        let Ok(rel) = path.strip_prefix(&self.root) else {
+            return Decision::included();
+        };
+        let mut current = self.root.clone();
+        let mut decision = self.check_existing(&current, true);
+        for component in rel.components() {
+            if decision.excluded {
+                break;
+            }
+            current.push(component);
+            let is_dir = current != path || path.is_dir();
+            decision = self.check_existing(&current, is_dir);
+        }
+        decision
+    }
+
+    /// [`check_entry`](Self::check_entry) if `path` exists, otherwise
+    /// [`check`](Self::check) by name.
+    fn check_existing(&mut self, path: &Path, is_dir: bool) -> Decision {
+        match std::fs::metadata(path) {
+            Ok(metadata) => self.check_entry(path, &metadata),
+            Err(_) => self.check(path, is_dir),
+        }
+    }
+
+    /// Ignore files of `dir`, loaded once per directory.
+    fn rules_in(&mut self, dir: &Path) -> &[Gitignore] {
+        let use_gitignore = self.use_gitignore;
+        self.dir_rules.entry(dir.to_path_buf()).or_insert_with(|| {
+            let mut names = vec![BACKUPIGNORE];
+            if use_gitignore {
+                names.push(".gitignore");
+            }
+            names
+                .into_iter()
+                .map(|name| dir.join(name))
+                .filter(|file| file.is_file())
+                .filter_map(|file| {
+                    let (rules, err) = Gitignore::new(&file);
+                    if let Some(e) = err {
+                        log::warn!("Ignoring invalid lines in {}: {e}", file.display());
+                    }
+                    (!rules.is_empty()).then_some(rules)
+                })
+                .collect()
+        })
+    }
+}
+
+/// `other` spelled below `root` if it lies inside `root`, comparing resolved
+/// paths so that symlinks and relative paths are detected. `other == root`
+/// counts only if `or_equal` is set.
+pub(crate) fn inside(root: &Path, other: &Path, or_equal: bool) -> Option<PathBuf> {
+    let resolved = |p: &Path| {
+        p.canonicalize()
+            .or_else(|_| std::path::absolute(p))
+            .unwrap_or_else(|_| p.to_path_buf())
+    };
+    let (root_abs, other_abs) = (resolved(root), resolved(other));
+    let rel = other_abs.strip_prefix(&root_abs).ok()?;
+    if rel.as_os_str().is_empty() && !or_equal {
+        return None;
+    }
+    Some(root.join(rel))
+}
+
+/// Whether `dir` holds a `CACHEDIR.TAG` starting with the standard signature.
+fn is_cache_dir(dir: &Path) -> bool {
+    let mut header = [0u8; CACHEDIR_SIGNATURE.len()];
+    std::fs::File::open(dir.join(CACHEDIR_TAG))
+        .and_then(|mut f| f.read_exact(&mut header))
+        .is_ok_and(|_| header == CACHEDIR_SIGNATURE)
+}
+
+/// Device holding the file, used to detect mount points.
+#[cfg(unix)]
+fn device_id(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+fn has_nobackup_xattr(path: &Path) -> bool {
+    matches!(xattr::get(path, NOBACKUP_XATTR), Ok(Some(_)))
+}
+
+#[cfg(not(unix))]
+fn has_nobackup_xattr(_path: &Path) -> bool {
+    false
+}
+
+/// Total size of the files below `dir`, used to report what a skipped
+/// directory would have cost.
+pub fn dir_size(dir: &Path) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+fn decide(m: Match<&ignore::gitignore::Glob>) -> Option<Decision> {
+    let (glob, excluded) = match m {
+        Match::None => return None,
+        Match::Ignore(glob) => (glob, true),
+        Match::Whitelist(glob) => (glob, false),
+    };
+    Some(Decision {
+        excluded,
+        rule: Some(Rule {
+            source: glob
+                .from()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            pattern: glob.original().to_string(),
+            negated: glob.is_whitelist(),
+        }),
+    })
+}
+
+/// Why a path is or is not backed up, see [`explain`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Explanation {
+    pub path: PathBuf,
+    /// Include root containing `path`, `None` if it lies outside every root
+    pub root: Option<String>,
+    pub excluded: bool,
+    /// The deciding rule, `None` if no rule matched
+    pub rule: Option<Rule>,
+}
+
+/// Explain whether `path` is backed up with the rules in `paths` and which
+/// rule decided it, leaving out `destination` as a backup run does.
+///
+/// A relative path, or one reached through a symlink, is resolved and spelled
+/// below the include root that contains it, as the walk spells it.
+pub fn explain(paths: &BackupPaths, destination: &Path, path: &Path) -> Result<Explanation> {
+    let spelled = paths
+        .include
+        .iter()
+        .filter_map(|root| Some((Path::new(&root.path), inside(Path::new(&root.path), path, true)?)))
+        .max_by_key(|(root, _)| root.components().count())
+        .map(|(_, spelled)| spelled);
+    let path = spelled.as_deref().unwrap_or(path);
+    let Some(root) = paths.root_for(path) else {
+        return Ok(Explanation {
+            path: path.to_path_buf(),
+            root: None,
+            excluded: true,
+            rule: None,
+        });
+    };
+    let decision = ExcludeRules::for_root(root, paths, destination)?.explain(path);
+    Ok(Explanation {
+        path: path.to_path_buf(),
+        root: Some(root.path.clone()),
+        excluded: decision.excluded,
+        rule: decision.rule,
+    })
+}
@@ -0,0 +1,298 @@
+use crate::backup::STATE_KEY;
+use crate::config::Config;
+use crate::delta;
+use crate::error::{Error, Result};
+use crate::history;
+use crate::roots::RootRegistry;
+use crate::state::BackupState;
+use crate::storage::{self, StorageBackend};
+use crate::utils::parse_history_name;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Filters for searching the backup destination.
+///
+/// Every filter is optional; a default query matches every stored version.
+#[derive(Debug, Default, Clone)]
+pub struct FindQuery {
+    /// Glob matched against the original file name (e.g. `budget*.xlsx`)
+    pub name: Option<String>,
+    /// Regular expression matched against the original source path
+    pub regex: Option<String>,
+    /// Restrict results to one include root, given as its path or label
+    pub root: Option<String>,
+    pub modified_after: Option<DateTime<Local>>,
+    pub modified_before: Option<DateTime<Local>>,
+    pub backed_up_after: Option<DateTime<Local>>,
+    pub backed_up_before: Option<DateTime<Local>>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+/// A single stored version of a source file.
+#[derive(Debug, Clone, Serialize)]
+pub struct FoundVersion {
+    /// Path of the file in the original source tree
+    pub source: PathBuf,
+    /// Location of this version inside the backup destination
+    pub stored: PathBuf,
+    /// Time the version was moved to `History`, or `None` for the live mirror copy
+    pub archived: Option<DateTime<Local>>,
+    /// Modification time of the source file when it was copied. Object
+    /// stores only know the upload time, which is reported instead.
+    pub modified: DateTime<Local>,
+    /// Point in time the version was part of the backup: the archive time for
+    /// `History` entries and the first recorded run at or after the source
+    /// modification for live entries
+    pub backed_up: DateTime<Local>,
+    /// Length of the version, also for versions stored as deltas
+    pub size: u64,
+    /// The version is stored as a delta and is read back with `cat`
+    pub delta: bool,
+}
+
+struct Matcher {
+    name: Option<GlobMatcher>,
+    regex: Option<Regex>,
+}
+
+/// Search the live mirror and the `History` folder of the configured
+/// destination for versions matching `query`.
+///
+/// Results are sorted by source path, newest version first.
+pub fn find_versions(config: &Config, query: &FindQuery) -> Result<Vec<FoundVersion>> {
+    find_in(&*storage::open(&config.backup, false)?, config, query)
+}
+
+/// Like [`find_versions`], searching `storage` instead of the destination
+/// named in the config. `stored` paths are the storage's display paths.
+///
+/// Filtering on the modification time fails on storage that does not keep
+/// the source's modification time on stored copies.
+pub fn find_in(storage: &dyn StorageBackend, config: &Config, query: &FindQuery) -> Result<Vec<FoundVersion>> {
+    if !storage.keeps_modified() && (query.modified_after.is_some() || query.modified_before.is_some()) {
+        return Err(Error::InvalidArgument(format!(
+            "{} does not keep modification times, filter on the backup time instead",
+            storage.location().display()
+        )));
+    }
+    let matcher = Matcher {
+        name: query
+            .name
+            .as_deref()
+            .map(|p| {
+                Glob::new(p).map(|g| g.compile_matcher()).map_err(|e| Error::InvalidPattern {
+                    pattern: p.to_string(),
+                    source: Box::new(e),
+                })
+            })
+            .transpose()?,
+        regex: query
+            .regex
+            .as_deref()
+            .map(|p| {
+                Regex::new(p).map_err(|e| Error::InvalidPattern {
+                    pattern: p.to_string(),
+                    source: Box::new(e),
+                })
+            })
+            .transpose()?,
+    };
+
+    // Roots removed from the config stay in the registry, so their stored
+    // versions can still be found and traced back to the original path
+    let mut registry = RootRegistry::load_from(storage)?;
+    registry.resolve(&config.paths.include)?;
+
+    let mut roots: HashMap<String, PathBuf> = HashMap::new();
+    for root in &registry.roots {
+        if let Some(filter) = &query.root {
+            if *filter != root.path && *filter != root.folder {
+                continue;
+            }
+        }
+        roots.insert(root.folder.clone(), PathBuf::from(&root.path));
+    }
+    if roots.is_empty() {
+        if let Some(filter) = &query.root {
+            return Err(Error::InvalidArgument(format!(
+                "No include path matches root '{}'",
+                filter
+            )));
+        }
+    }
+
+    let state: BackupState = storage::read_toml(storage, Path::new(STATE_KEY))?.unwrap_or_default();
+    let mut runs: Vec<DateTime<Local>> = state
+        .stats
+        .iter()
+        .chain(state.jobs.values().flat_map(|job| &job.stats))
+        .map(|stats| stats.timestamp)
+        .collect();
+    runs.sort();
+    let search = Search {
+        storage,
+        matcher,
+        query,
+        state,
+        runs,
+    };
+    let mut found = Vec::new();
+    for (label, src_root) in &roots {
+        search.collect(Path::new(label), src_root, false, &mut found)?;
+        let history = Path::new("History").join(label);
+        search.collect(&history, src_root, true, &mut found)?;
+    }
+
+    found.sort_by(|a, b| {
+        a.source
+            .cmp(&b.source)
+            .then_with(|| b.backed_up.cmp(&a.backed_up))
+    });
+    Ok(found)
+}
+
+/// What `find_in` searches and how.
+struct Search<'a> {
+    storage: &'a dyn StorageBackend,
+    matcher: Matcher,
+    query: &'a FindQuery,
+    /// State of the destination, for the length of sparse files stored
+    /// without their holes
+    state: BackupState,
+    /// Start of every recorded run, oldest first
+    runs: Vec<DateTime<Local>>,
+}
+
+impl Search<'_> {
+    fn collect(&self, backup_root: &Path, src_root: &Path, history: bool, found: &mut Vec<FoundVersion>) -> Result<()> {
+        let storage = self.storage;
+        let objects = storage
+            .list(backup_root)
+            .map_err(|e| Error::io(storage.display_path(backup_root), e))?;
+        for object in objects {
+            let Ok(rel) = object.key.strip_prefix(backup_root) else {
+                continue;
+            };
+            let file_name = rel.file_name().unwrap_or_default().to_string_lossy();
+
+            let (original_name, archived) = if history {
+                match parse_history_name(&file_name) {
+                    Some((name, ts)) => (name, Some(ts)),
+                    None => continue,
+                }
+            } else {
+                // Interrupted copies are not restorable versions
+                if rel.extension().is_some_and(|e| e == "part") {
+                    continue;
+                }
+                (file_name.to_string(), None)
+            };
+
+            let source = src_root
+                .join(rel.parent().unwrap_or_else(|| Path::new("")))
+                .join(&original_name);
+
+            let delta = history::is_delta(&object.key);
+            let size = if delta {
+                match storage.get(&object.key).and_then(delta::read_header) {
+                    Ok(header) => header.target_len,
+                    Err(_) => continue,
+                }
+            } else {
+                self.state.extents(&object.key).map_or(object.size, |extents| extents.len)
+            };
+            let modified = DateTime::<Local>::from(object.modified);
+            let backed_up = match archived {
+                Some(archived) => archived,
+                // The upload time where the source's modification time is not kept
+                None if !storage.keeps_modified() => modified,
+                // A live copy is made by the first run that sees the change
+                None => self
+                    .runs
+                    .iter()
+                    .find(|run| **run >= modified)
+                    .or(self.runs.last())
+                    .copied()
+                    .unwrap_or(modified),
+            };
+            let version = FoundVersion {
+                source,
+                stored: storage.display_path(&object.key),
+                archived,
+                modified,
+                backed_up,
+                size,
+                delta,
+            };
+            if matches(&version, &original_name, &self.matcher, self.query) {
+                found.push(version);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn matches(version: &FoundVersion, name: &str, matcher: &Matcher, query: &FindQuery) -> bool {
+    if let Some(glob) = &matcher.name {
+        if !glob.is_match(name) {
+            return false;
+        }
+    }
+    if let Some(re) = &matcher.regex {
+        if !re.is_match(&version.source.to_string_lossy()) {
+            return false;
+        }
+    }
+
+    let in_range = |value: DateTime<Local>, after: Option<DateTime<Local>>, before: Option<DateTime<Local>>| {
+        after.is_none_or(|a| value >= a) && before.is_none_or(|b| value < b)
+    };
+    if !in_range(version.modified, query.modified_after, query.modified_before) {
+        return false;
+    }
+    if !in_range(version.backed_up, query.backed_up_after, query.backed_up_before) {
+        return false;
+    }
+
+    query.min_size.is_none_or(|min| version.size >= min)
+        && query.max_size.is_none_or(|max| version.size <= max)
+}
+
+/// Parse a date given as `YYYY-MM-DD` (local midnight) or RFC 3339.
+pub fn parse_date(value: &str) -> Result<DateTime<Local>> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Ok(dt.with_timezone(&Local));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| Error::InvalidArgument(format!("Invalid date '{}', expected YYYY-MM-DD", value)))?;
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .ok_or_else(|| Error::InvalidArgument(format!("Date '{}' does not exist in the local time zone", value)))
+}
+
+/// Parse a size such as `512`, `10K`, `1.5M` or `2G` (binary units) into bytes.
+pub fn parse_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| Error::InvalidArgument(format!("Invalid size '{}'", value)))?;
+    let factor: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(Error::InvalidArgument(format!("Invalid size unit in '{}'", value))),
+    };
+    Ok((number * factor as f64) as u64)
+}
@@ -0,0 +1,279 @@
+//! Versions of a file in `History`, stored in full or as reverse deltas.
+//!
+//! With `backup.delta_history` the newest version of a file in `History` stays
+//! a full copy. When a newer version arrives, the previous one is replaced by
+//! a delta against it (`name_<timestamp>.ext.rbdelta`), so an old version is
+//! rebuilt by applying the deltas from the nearest newer full copy backwards.
+//! Chains never reference the live mirror, so overwriting or deleting a mirror
+//! file cannot break them. Pruning a version that an older delta is based on
+//! rebases that delta onto the next newer version first.
+
+use crate::delta;
+use crate::error::{Error, IoResultExt, Result};
+use crate::backup::STATE_KEY;
+use crate::state::BackupState;
+use crate::storage::{self, StorageBackend};
+use crate::utils::parse_history_name;
+use log::debug;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Suffix of a version stored as a delta against the next newer version.
+pub const DELTA_SUFFIX: &str = ".rbdelta";
+/// Versions smaller than this are kept in full unless `backup.delta_min_size` is set.
+pub const DEFAULT_MIN_SIZE: u64 = 1024 * 1024;
+/// Chain length used unless `backup.max_delta_chain` is set.
+pub const DEFAULT_MAX_CHAIN: u32 = 10;
+
+/// Settings for storing History versions as deltas.
+#[derive(Debug, Clone, Copy)]
+pub struct DeltaOptions {
+    /// Versions smaller than this are always kept in full
+    pub min_size: u64,
+    /// Most deltas applied in a row to rebuild a version
+    pub max_chain: u32,
+}
+
+/// Whether `path` is a History version stored as a delta.
+pub fn is_delta(path: &Path) -> bool {
+    path.file_name().is_some_and(|n| n.to_string_lossy().ends_with(DELTA_SUFFIX))
+}
+
+/// Length of the version stored at `stored`, rebuilt if it is a delta.
+pub fn version_size(stored: &Path) -> io::Result<u64> {
+    if is_delta(stored) {
+        Ok(delta::read_header(File::open(stored)?)?.target_len)
+    } else {
+        Ok(fs::metadata(stored)?.len())
+    }
+}
+
+/// All versions of the file `stored` is a version of, newest first.
+fn versions_of(stored: &Path) -> io::Result<Vec<PathBuf>> {
+    let name = stored.file_name().unwrap_or_default().to_string_lossy();
+    let (Some((original, _)), Some(dir)) = (parse_history_name(&name), stored.parent()) else {
+        return Ok(vec![stored.to_path_buf()]);
+    };
+    let mut versions = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let Some((other, archived)) = parse_history_name(&name) else {
+            continue;
+        };
+        // A delta next to the full copy it replaces is left over from an
+        // interrupted conversion; the full copy wins
+        let leftover = is_delta(&path) && dir.join(name.trim_end_matches(DELTA_SUFFIX)).exists();
+        if other == original && path.is_file() && !leftover {
+            versions.push((archived, path));
+        }
+    }
+    versions.sort_by_key(|v| std::cmp::Reverse(v.0));
+    Ok(versions.into_iter().map(|(_, path)| path).collect())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Called after `newest` was moved into History: replace the previous version
+/// of the same file with a delta against `newest` if that saves at least half
+/// its size and keeps chains within `max_chain`. Returns the bytes saved.
+pub(crate) fn compact_previous(newest: &Path, options: &DeltaOptions) -> Result<Option<u64>> {
+    let dir = newest.parent().unwrap_or_else(|| Path::new(""));
+    let versions = versions_of(newest).at(dir)?;
+    match versions.iter().position(|p| p == newest) {
+        Some(idx) => compact_after(&versions, idx, options),
+        None => Ok(None),
+    }
+}
+
+/// Replace the full copy after `versions[idx]` with a delta against it.
+/// `versions[idx]` may itself be a delta, in which case it is rebuilt first.
+fn compact_after(versions: &[PathBuf], idx: usize, options: &DeltaOptions) -> Result<Option<u64>> {
+    let Some(previous) = versions.get(idx + 1) else {
+        return Ok(None);
+    };
+    if is_delta(previous) {
+        return Ok(None);
+    }
+    let metadata = fs::metadata(previous).at(previous)?;
+    if metadata.len() < options.min_size {
+        return Ok(None);
+    }
+    let Some(full) = (0..=idx).rev().find(|i| !is_delta(&versions[*i])) else {
+        return Ok(None);
+    };
+    // `previous` is rebuilt through the deltas down from the nearest full copy,
+    // and older deltas through `previous`, so their chains grow accordingly
+    let chain = idx - full + 1 + versions[idx + 2..].iter().take_while(|p| is_delta(p)).count();
+    if chain > options.max_chain as usize {
+        debug!("Keeping {} in full, delta chain is at its limit", previous.display());
+        return Ok(None);
+    }
+
+    let rebuilt;
+    let newest = if full == idx {
+        &versions[idx]
+    } else {
+        rebuilt = TempFile::next_to(&versions[idx]);
+        let mut writer = BufWriter::new(File::create(&rebuilt.0).at(&rebuilt.0)?);
+        read_version(&versions[idx], &mut writer)?;
+        writer.flush().at(&rebuilt.0)?;
+        &rebuilt.0
+    };
+
+    let stored = with_suffix(previous, DELTA_SUFFIX);
+    let part = with_suffix(&stored, ".part");
+    let (_, size) = delta::write_delta(newest, previous, &part).at(&part)?;
+    if size.saturating_mul(2) > metadata.len() {
+        debug!("Keeping {} in full, delta would be {} bytes", previous.display(), size);
+        fs::remove_file(&part).at(&part)?;
+        return Ok(None);
+    }
+
+    // Only drop the full copy once the delta is known to rebuild it
+    let check = File::open(newest)
+        .and_then(|mut base| delta::apply_delta(File::open(&part)?, &mut base, &mut io::sink()));
+    if let Err(e) = check {
+        fs::remove_file(&part).ok();
+        return Err(Error::io(&part, e));
+    }
+    if let Ok(modified) = metadata.modified() {
+        File::options()
+            .write(true)
+            .open(&part)
+            .and_then(|f| f.set_modified(modified))
+            .at(&part)?;
+    }
+    fs::rename(&part, &stored).at(&stored)?;
+    fs::remove_file(previous).at(previous)?;
+    debug!("Stored {} as a {} byte delta", previous.display(), size);
+    Ok(Some(metadata.len() - size))
+}
+
+/// Delete the History version `stored`. An older delta based on it is first
+/// rebuilt as a full copy and then, with `options`, stored as a delta against
+/// the next newer version again, so the remaining versions stay readable even
+/// if pruning is interrupted.
+pub fn prune_version(stored: &Path, options: Option<&DeltaOptions>) -> Result<()> {
+    let dir = stored.parent().unwrap_or_else(|| Path::new(""));
+    let versions = versions_of(stored).at(dir)?;
+    let idx = versions.iter().position(|p| p == stored);
+    let older = idx.and_then(|idx| versions.get(idx + 1)).filter(|p| is_delta(p));
+    let Some(older) = older else {
+        return fs::remove_file(stored).at(stored);
+    };
+
+    let full = older.with_file_name(older.file_name().unwrap_or_default().to_string_lossy().trim_end_matches(DELTA_SUFFIX));
+    let part = with_suffix(&full, ".part");
+    let modified = fs::metadata(older).and_then(|m| m.modified()).at(older)?;
+    let written = File::create(&part).at(&part).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        read_version(older, &mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error()).at(&part)?;
+        file.set_modified(modified).at(&part)
+    });
+    if let Err(e) = written {
+        fs::remove_file(&part).ok();
+        return Err(e);
+    }
+    // The full copy wins over the delta next to it until the delta is gone
+    fs::rename(&part, &full).at(&full)?;
+    fs::remove_file(older).at(older)?;
+    fs::remove_file(stored).at(stored)?;
+    debug!("Rebased {} before pruning {}", older.display(), stored.display());
+
+    let Some(options) = options else {
+        return Ok(());
+    };
+    let versions = versions_of(&full).at(dir)?;
+    match versions.iter().position(|p| *p == full) {
+        Some(idx) if idx > 0 => compact_after(&versions, idx - 1, options).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// Write the content of the History version or mirror file `stored` to `out`,
+/// applying deltas as needed. Returns the number of bytes written.
+pub fn read_version(stored: &Path, out: &mut dyn Write) -> Result<u64> {
+    if !is_delta(stored) {
+        let mut file = File::open(stored).at(stored)?;
+        return io::copy(&mut file, out).at(stored);
+    }
+
+    let dir = stored.parent().unwrap_or_else(|| Path::new(""));
+    let versions = versions_of(stored).at(dir)?;
+    let idx = versions.iter().position(|p| p == stored).unwrap_or(0);
+    let Some(full) = (0..idx).rev().find(|i| !is_delta(&versions[*i])) else {
+        return Err(Error::CorruptVersion {
+            path: stored.to_path_buf(),
+            source: io::Error::new(io::ErrorKind::NotFound, "no newer full copy to rebuild from"),
+        });
+    };
+
+    let mut base = File::open(&versions[full]).at(&versions[full])?;
+    let mut temps = Vec::new();
+    for step in &versions[full + 1..idx] {
+        let temp = TempFile::next_to(stored);
+        let mut writer = BufWriter::new(File::create(&temp.0).at(&temp.0)?);
+        apply(step, &mut base, &mut writer)?;
+        writer.flush().at(&temp.0)?;
+        base = File::open(&temp.0).at(&temp.0)?;
+        temps.push(temp);
+    }
+    apply(stored, &mut base, out)
+}
+
+/// Write the content of the version stored as `key` in `storage` to `out`.
+/// Deltas are only written to local destinations, so other backends return
+/// the stored object as is, with the holes of a sparse file put back.
+pub fn read_stored(storage: &dyn StorageBackend, key: &Path, out: &mut dyn Write) -> Result<u64> {
+    if let Some(root) = storage.local_root() {
+        return read_version(&root.join(key), out);
+    }
+    let path = storage.display_path(key);
+    let state = storage::read_toml::<BackupState>(storage, Path::new(STATE_KEY))?.unwrap_or_default();
+    let mut reader = storage.get(key).at(&path)?;
+    match state.extents(key) {
+        Some(extents) => extents.expand(&mut reader, out).map_err(|source| match source.kind() {
+            io::ErrorKind::UnexpectedEof => Error::CorruptVersion { path: path.clone(), source },
+            _ => Error::io(&path, source),
+        }),
+        None => io::copy(&mut reader, out).at(&path),
+    }
+}
+
+fn apply(delta_path: &Path, base: &mut File, out: &mut dyn Write) -> Result<u64> {
+    let delta_file = File::open(delta_path).at(delta_path)?;
+    delta::apply_delta(delta_file, base, out).map_err(|source| match source.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Error::CorruptVersion {
+            path: delta_path.to_path_buf(),
+            source,
+        },
+        _ => Error::io(delta_path, source),
+    })
+}
+
+/// Intermediate version rebuilt while walking a chain, deleted on drop. It is
+/// written next to the version being rebuilt rather than to the system temp
+/// directory, which may be too small for the files deltas are used for.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn next_to(stored: &Path) -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self(with_suffix(stored, &format!(".rebuild-{}-{}.part", std::process::id(), n)))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
@@ -1,12 +1,16 @@
-use anyhow::Result;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
-use std::collections::HashMap;
-use crate::config::Config;
-
-use globset::{Glob, GlobSetBuilder};
+use crate::config::{BackupPaths, IncludeRoot};
+use crate::error::{Error, Result};
+use crate::exclude::{ExcludeRules, Rule};
+use crate::cancel::CancellationToken;
+use crate::observer::{BackupObserver, NoopObserver};
+use crate::roots::{self, RootMap};
+use crate::storage::{LocalStorage, StorageBackend};
+
+use log::info;
 use walkdir::WalkDir;
-use indicatif::{ProgressBar, ProgressStyle};
 
 
 /// Return a list of files modified after `since` from the `include_paths`,
@@ -20,66 +24,147 @@ pub fn changed_files(
     destination: &Path,
     check_destination: bool,
 ) -> Result<Vec<PathBuf>> {
+    let roots: Vec<IncludeRoot> = include_paths
+        .iter()
+        .map(|p| IncludeRoot::new(p.to_string_lossy()))
+        .collect();
+    let roots = roots::map_roots(destination, &roots, false)?;
+    let paths = BackupPaths {
+        exclude: exclude_patterns.to_vec(),
+        ..Default::default()
+    };
+    let storage = LocalStorage::new(destination);
+    let changed = changed_files_observed(
+        since,
+        &roots,
+        &paths,
+        destination,
+        check_destination.then_some(&storage as &dyn StorageBackend),
+        &NoopObserver,
+        &CancellationToken::new(),
+    )?;
+    Ok(changed.files)
+}
 
-    eprintln!("Updating Journal... changed files");
+/// A file or directory left out of a walk by an exclude rule.
+#[derive(Debug, Clone)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub rule: Option<Rule>,
+    pub is_dir: bool,
+    /// Size of a skipped file; `0` for directories, whose contents are not walked
+    pub size: u64,
+}
 
-    let mut builder = GlobSetBuilder::new();
-    for pattern in exclude_patterns {
-        builder.add(Glob::new(pattern)?);
-    }
-    let excludes = builder.build()?;
+/// Result of [`changed_files_observed`].
+#[derive(Debug, Clone, Default)]
+pub struct ChangedFiles {
+    /// Files that need to be backed up
+    pub files: Vec<PathBuf>,
+    /// Everything excluded during the walk
+    pub skipped: Vec<Skipped>,
+}
 
-    let mut files = Vec::new();
+/// Like [`changed_files`] for mapped include roots with their own settings, reporting
+/// walk progress to `observer` and stopping with
+/// [`Error::Cancelled`](crate::Error::Cancelled) once `cancel` is set.
+///
+/// Paths are filtered by the [`ExcludeRules`] built from `paths` and each
+/// root's own settings, and every excluded path is recorded in
+/// [`ChangedFiles::skipped`]. With `stored`, unchanged files missing from that
+/// storage are included as well.
+pub fn changed_files_observed(
+    since: SystemTime,
+    roots: &RootMap,
+    paths: &BackupPaths,
+    destination: &Path,
+    stored: Option<&dyn StorageBackend>,
+    observer: &dyn BackupObserver,
+    cancel: &CancellationToken,
+) -> Result<ChangedFiles> {
+
+    info!("Updating Journal... changed files");
 
-    let style = ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} files ({eta})")
-        .unwrap()
-        .progress_chars("##-");
+    let mut files = Vec::new();
+    let mut skipped = Vec::new();
+
+    // One listing per root instead of a round trip per file on remote storage
+    let stored: Option<HashSet<PathBuf>> = match stored {
+        Some(storage) => {
+            let mut keys = HashSet::new();
+            for mapped in &roots.roots {
+                let folder = Path::new(&mapped.folder);
+                let objects = storage
+                    .list(folder)
+                    .map_err(|e| Error::io(storage.display_path(folder), e))?;
+                keys.extend(objects.into_iter().map(|o| o.key));
+            }
+            Some(keys)
+        }
+        None => None,
+    };
 
     // this actually takes quite some time when scanning tons of files.
-    for include in include_paths {
+    for mapped in &roots.roots {
+        let root = &mapped.root;
+        let include = Path::new(&root.path);
+        let mut rules = ExcludeRules::for_root(root, paths, destination)?;
+
+        // A root that exists but cannot be listed would otherwise look empty
+        if let Err(e) = std::fs::read_dir(include) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(Error::SourceUnreadable {
+                    path: include.to_path_buf(),
+                    source: e,
+                });
+            }
+        }
         let entries: Vec<_> = WalkDir::new(include)
+            .follow_links(root.follow_symlinks)
             .into_iter()
-            .filter_entry(|e| !excludes.is_match(e.path()))
+            .filter_entry(|e| {
+                let is_dir = e.file_type().is_dir();
+                let metadata = e.metadata().ok();
+                let decision = match &metadata {
+                    Some(metadata) => rules.check_entry(e.path(), metadata),
+                    None => rules.check(e.path(), is_dir),
+                };
+                if decision.excluded {
+                    skipped.push(Skipped {
+                        path: e.path().to_path_buf(),
+                        rule: decision.rule,
+                        is_dir,
+                        size: metadata.filter(|m| m.is_file()).map_or(0, |m| m.len()),
+                    });
+                }
+                !decision.excluded
+            })
             .filter_map(Result::ok)
             .filter(|e| e.file_type().is_file())
             .collect();
 
         
-        let pb = ProgressBar::new(entries.len() as u64);
-        pb.set_style(style.clone());
-        pb.set_message(include.display().to_string());
+        observer.scan_started(include, entries.len() as u64);
 
         // this is pretty slow.
         for entry in entries {
+            cancel.check()?;
             let path = entry.path();
-            pb.inc(1);
+            observer.scan_progress(path);
             if entry.file_type().is_file()  {
                 if let Ok(metadata) = entry.metadata() {
                     if let Ok(modified) = metadata.modified() {
                         let needs_update = if modified > since {
                             true
-                        } else if check_destination {
-                            // compute destination path and check if it exists
-                            let (normalized_root, relative) = include_paths
-                                .iter()
-                                .find_map(|root| {
-                                    let root_path = Path::new(root);
-                                    path.strip_prefix(root_path).ok().map(|rel| {
-                                        let root_label = root_path
-                                            .to_string_lossy()
-                                            .replace(':', "")
-                                            .replace('\\', "-")
-                                            .replace('/', "-");
-                                        (root_label, rel)
-                                    })
-                                })
-                                .unwrap_or_else(|| ("UnknownSource".into(), path));
-
-                            let dest_file = destination
-                                .join(normalized_root)
-                                .join(relative);
-                            !dest_file.exists()
+                        } else if let Some(stored) = &stored {
+                            // compute destination key and check if it exists
+                            let (normalized_root, relative) = roots
+                                .locate(path)
+                                .map(|(m, rel)| (m.folder.as_str(), rel))
+                                .unwrap_or(("UnknownSource", path));
+
+                            let key = Path::new(normalized_root).join(relative);
+                            !stored.contains(&key)
                         } else {
                             false
                         };
@@ -92,46 +177,38 @@ pub fn changed_files(
                 }
             }
         }
-        pb.finish_with_message(format!("{} scanned", include.display()));
+        observer.scan_finished(include);
     }
 
-    Ok(files)
+    Ok(ChangedFiles { files, skipped })
 }
 
 /// Scan the backup destination for files that no longer exist in the source
 /// directories. Returns a list of backup file paths that should be moved to the
 /// `History` folder.
-pub fn find_removed_files(dest: &Path, config: &Config) -> Result<Vec<PathBuf>> {
-    
-    eprintln!("Updating Journal... removed files");
-    let mut roots: HashMap<String, PathBuf> = HashMap::new();
-    for include in &config.paths.include {
-        let p = PathBuf::from(include);
-        let label = p
-            .to_string_lossy()
-            .replace(':', "")
-            .replace('\\', "-")
-            .replace('/', "-");
-        roots.insert(label, p);
-    }
+pub fn find_removed_files(dest: &Path, roots: &RootMap) -> Result<Vec<PathBuf>> {
+    let removed = removed_files(&LocalStorage::new(dest), roots)?;
+    Ok(removed.into_iter().map(|key| dest.join(key)).collect())
+}
 
+/// Like [`find_removed_files`] for any storage backend, returning the keys of
+/// the stored files.
+pub fn removed_files(storage: &dyn StorageBackend, roots: &RootMap) -> Result<Vec<PathBuf>> {
+    
+    info!("Updating Journal... removed files");
     let mut removed = Vec::new();
-    for (label, src_root) in &roots {
-        let backup_root = dest.join(label);
-        if !backup_root.exists() {
-            continue;
-        }
-
-        for entry in WalkDir::new(&backup_root)
-            .into_iter()
-            .filter_map(Result::ok)
-            .filter(|e| e.file_type().is_file())
-        {
-            let backup_path = entry.path();
-            let rel = backup_path.strip_prefix(&backup_root).unwrap();
+    for mapped in &roots.roots {
+        let src_root = Path::new(&mapped.root.path);
+        let folder = Path::new(&mapped.folder);
+        let stored = storage
+            .list(folder)
+            .map_err(|e| Error::io(storage.display_path(folder), e))?;
+
+        for object in stored {
+            let rel = object.key.strip_prefix(folder).unwrap();
             let src_path = src_root.join(rel);
             if !src_path.exists() {
-                removed.push(backup_path.to_path_buf());
+                removed.push(object.key);
             }
         }
     }
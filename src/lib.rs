@@ -1,8 +1,31 @@
 pub mod config;
 pub mod backup;
+pub mod cancel;
+pub mod check;
+pub mod copy;
+pub mod delta;
+pub mod engine;
+pub mod error;
+pub mod exclude;
+pub mod find;
+pub mod history;
 pub mod journal;
+pub mod logging;
+pub mod observer;
+pub mod presets;
+pub mod restore;
+pub mod roots;
+pub mod s3;
+pub mod sftp;
 pub mod state;
+pub mod storage;
 pub mod utils;
 
-pub use config::{Config, BackupPaths, BackupOptions};
+pub use config::{Config, BackupPaths, BackupOptions, HistoryMode, LoggingOptions};
 pub use state::{BackupState, LatestBackup, BackupStats, load_or_init_state};
+pub use cancel::CancellationToken;
+pub use engine::BackupEngine;
+pub use error::{Error, Result};
+pub use observer::{BackupObserver, BackupPlan, NoopObserver};
+pub use roots::{RootMap, RootRegistry};
+pub use storage::{LocalStorage, MemoryStorage, StorageBackend};
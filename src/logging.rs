@@ -0,0 +1,246 @@
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use crate::config::LoggingOptions;
+use crate::error::{Error, IoResultExt, Result};
+use crate::storage::StorageBackend;
+
+/// Logger writing to stderr and, while a backup run is active, to a per-run
+/// log file inside the destination. Destinations without a local file system
+/// get the log written to a local file first and stored when the run ends.
+struct Logger {
+    stderr_level: LevelFilter,
+    run_log: Mutex<Option<RunLog>>,
+}
+
+struct RunLog {
+    file: File,
+    level: LevelFilter,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.stderr_level || metadata.level() <= self.file_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= self.stderr_level {
+            eprintln!("{:<5} {}", record.level(), record.args());
+        }
+        if let Ok(mut guard) = self.run_log.lock() {
+            if let Some(run_log) = guard.as_mut() {
+                if record.level() <= run_log.level {
+                    let _ = writeln!(
+                        run_log.file,
+                        "{} {:<5} {}",
+                        Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+                        record.level(),
+                        record.args()
+                    );
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut guard) = self.run_log.lock() {
+            if let Some(run_log) = guard.as_mut() {
+                let _ = run_log.file.flush();
+            }
+        }
+    }
+}
+
+impl Logger {
+    fn file_level(&self) -> LevelFilter {
+        self.run_log
+            .lock()
+            .ok()
+            .and_then(|g| g.as_ref().map(|r| r.level))
+            .unwrap_or(LevelFilter::Off)
+    }
+}
+
+/// Install the global logger. Messages at or below `level` are written to stderr.
+///
+/// Calling this more than once has no effect.
+pub fn init(level: LevelFilter) {
+    let logger = LOGGER.get_or_init(|| Logger {
+        stderr_level: level,
+        run_log: Mutex::new(None),
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Map the `-v` / `-q` counts to a level filter, starting from `default`.
+pub fn level_from_verbosity(default: LevelFilter, verbose: u8, quiet: u8) -> LevelFilter {
+    const LEVELS: [LevelFilter; 6] = [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ];
+    let base = default as i32;
+    let idx = (base + verbose as i32 - quiet as i32).clamp(0, LEVELS.len() as i32 - 1);
+    LEVELS[idx as usize]
+}
+
+/// Directory holding the per-run logs of a destination. Named jobs keep
+/// their logs in `logs/<job>` since their snapshot ids overlap.
+pub fn log_dir(dest: &Path, job: Option<&str>) -> PathBuf {
+    match job {
+        Some(name) => dest.join("logs").join(name),
+        None => dest.join("logs"),
+    }
+}
+
+/// Path of the log file for `snapshot_id`.
+pub fn run_log_path(dest: &Path, job: Option<&str>, snapshot_id: u64) -> PathBuf {
+    log_dir(dest, job).join(format!("{}.log", snapshot_id))
+}
+
+/// Stops the per-run log file when dropped, and stores it in a destination
+/// without a local file system.
+pub struct RunLogGuard {
+    staged: Option<StagedLog>,
+}
+
+/// A run log written locally until it is stored as `key`.
+struct StagedLog {
+    storage: Arc<dyn StorageBackend>,
+    key: PathBuf,
+    path: PathBuf,
+}
+
+impl Drop for RunLogGuard {
+    fn drop(&mut self) {
+        finish_run_log();
+        if let Some(staged) = self.staged.take() {
+            let stored = File::open(&staged.path).and_then(|mut file| staged.storage.put(&staged.key, &mut file));
+            if let Err(e) = stored {
+                log::warn!("Failed to store the run log {}: {e}", staged.storage.display_path(&staged.key).display());
+            }
+            fs::remove_file(&staged.path).ok();
+        }
+    }
+}
+
+/// Start writing log records to `logs/<snapshot_id>.log` in `storage` (or
+/// `logs/<job>/<snapshot_id>.log`) until the returned guard is dropped.
+///
+/// Records are appended, so a resumed run continues the log of the
+/// interrupted one. Nothing is written if the global logger was not installed
+/// through [`init`].
+pub fn start_run_log(
+    storage: &Arc<dyn StorageBackend>,
+    job: Option<&str>,
+    snapshot_id: u64,
+    options: &LoggingOptions,
+) -> Result<RunLogGuard> {
+    let Some(logger) = LOGGER.get() else {
+        return Ok(RunLogGuard { staged: None });
+    };
+    let key = run_log_path(Path::new(""), job, snapshot_id);
+    let (path, staged) = match storage.local_root() {
+        Some(dest) => {
+            let dir = log_dir(dest, job);
+            fs::create_dir_all(&dir).at(&dir)?;
+            (dest.join(&key), None)
+        }
+        None => {
+            let path = std::env::temp_dir().join(format!("rustybackup-{}-{}.log", std::process::id(), snapshot_id));
+            // Continue the log of an interrupted run
+            let mut staged = File::create(&path).at(&path)?;
+            match storage.get(&key) {
+                Ok(mut earlier) => {
+                    io::copy(&mut earlier, &mut staged).map_err(|e| Error::io(storage.display_path(&key), e))?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::io(storage.display_path(&key), e)),
+            }
+            let staged = StagedLog {
+                storage: Arc::clone(storage),
+                key,
+                path: path.clone(),
+            };
+            (path, Some(staged))
+        }
+    };
+    let file = OpenOptions::new().create(true).append(true).open(&path).at(&path)?;
+    let level = options.level.max(logger.stderr_level);
+    if let Ok(mut guard) = logger.run_log.lock() {
+        *guard = Some(RunLog { file, level });
+    }
+    log::set_max_level(level.max(logger.stderr_level));
+    Ok(RunLogGuard { staged })
+}
+
+fn finish_run_log() {
+    if let Some(logger) = LOGGER.get() {
+        if let Ok(mut guard) = logger.run_log.lock() {
+            if let Some(mut run_log) = guard.take() {
+                let _ = run_log.file.flush();
+            }
+        }
+        log::set_max_level(logger.stderr_level);
+    }
+}
+
+/// Delete old run logs, keeping at most `keep_runs` files (all of them if it
+/// is `None` or `0`) and nothing older than `max_age_days`. Returns the number
+/// of deleted logs.
+pub fn rotate_logs(storage: &dyn StorageBackend, job: Option<&str>, options: &LoggingOptions) -> Result<usize> {
+    let dir = log_dir(Path::new(""), job);
+    let mut logs: Vec<(SystemTime, PathBuf)> = storage
+        .list(&dir)
+        .map_err(|e| Error::io(storage.display_path(&dir), e))?
+        .into_iter()
+        // The logs of named jobs below `logs/` are rotated on their own
+        .filter(|o| o.key.parent() == Some(dir.as_path()) && o.key.extension().is_some_and(|ext| ext == "log"))
+        .map(|o| (o.modified, o.key))
+        .collect();
+    logs.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+
+    let cutoff = options
+        .max_age_days
+        .map(|days| SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60));
+
+    let mut deleted = 0;
+    for (idx, (modified, key)) in logs.iter().enumerate() {
+        let too_many = options.keep_runs.is_some_and(|keep| keep > 0 && idx >= keep);
+        let too_old = cutoff.is_some_and(|c| *modified < c);
+        if too_many || too_old {
+            storage.delete(key).map_err(|e| Error::io(storage.display_path(key), e))?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// Return up to `lines` trailing lines of the log for `snapshot_id`.
+pub fn tail(storage: &dyn StorageBackend, job: Option<&str>, snapshot_id: u64, lines: usize) -> Result<Vec<String>> {
+    let key = run_log_path(Path::new(""), job, snapshot_id);
+    let path = storage.display_path(&key);
+    let reader = match storage.get(&key) {
+        Ok(reader) => reader,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::io(&path, e)),
+    };
+    let all: Vec<String> = BufReader::new(reader)
+        .lines()
+        .collect::<std::io::Result<_>>()
+        .at(&path)?;
+    let start = all.len().saturating_sub(lines);
+    Ok(all[start..].to_vec())
+}
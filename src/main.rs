@@ -6,15 +6,24 @@
 // 
 
 
-mod config;
-mod backup;
-mod journal;
-mod state;
-mod utils;
+mod output;
 
-use clap::{Parser, Subcommand};
-use std::{fs, path::PathBuf};
-use config::Config;
+use clap::{ArgAction, Args as ClapArgs, Parser, Subcommand};
+use log::LevelFilter;
+use std::io::Write;
+use std::path::PathBuf;
+use output::{CliObserver, OutputMode};
+use rustybackup::{check, find, history, logging, storage, BackupEngine, CancellationToken, Config, Error};
+
+/// Exit codes, documented in the README.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_DESTINATION: i32 = 3;
+const EXIT_STATE_CORRUPT: i32 = 4;
+const EXIT_SOURCE_UNREADABLE: i32 = 5;
+const EXIT_OUT_OF_SPACE: i32 = 6;
+/// Exit code used when a run was stopped by SIGINT/SIGTERM.
+const EXIT_CANCELLED: i32 = 130;
 
 #[derive(Parser, Debug)]
 #[command(name = "rustybackup")]
@@ -28,6 +37,26 @@ struct Args {
     #[arg(long, default_value_t = false)]
     fullscan: bool,
 
+    /// Run the command for the `[[job]]` with this name
+    #[arg(long, global = true, conflicts_with = "all")]
+    job: Option<String>,
+
+    /// Run the command for every configured job
+    #[arg(long, global = true)]
+    all: bool,
+
+    /// Output format; `json` prints a single versioned document on stdout
+    #[arg(long, value_enum, default_value_t = OutputMode::Text, global = true)]
+    output: OutputMode,
+
+    /// Increase log verbosity (-v debug, -vv trace)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Decrease log verbosity (-q warnings only, -qq errors only)
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+
     /// Action to perform
     #[command(subcommand)]
     command: Commands,
@@ -36,29 +65,289 @@ struct Args {
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Scan configured paths and print discovered files
-    Scan,
+    Scan {
+        /// Show which rule includes or excludes this path instead of scanning
+        #[arg(long, value_name = "PATH")]
+        explain: Option<PathBuf>,
+    },
     /// Perform a backup run
     Backup,
-    /// Remove outdated backups (placeholder)
+    /// Remove outdated History versions and snapshot trees
     Vacuum,
-    /// Show backup status information (placeholder)
-    Status,
+    /// Show backup status information
+    Status {
+        /// Print the last N lines of the latest run's log file
+        #[arg(long, default_value_t = 0)]
+        tail: usize,
+    },
+    /// Search the backup and its History for stored file versions
+    Find(FindArgs),
+    /// Copy the backed up files to TARGET, recreating hard links
+    Restore {
+        /// Directory the files are written to, as `<TARGET>/<folder>/...`
+        target: PathBuf,
+        /// Only restore the include root with this path or folder
+        #[arg(long)]
+        root: Option<String>,
+    },
+    /// Write a stored version to stdout, rebuilding versions stored as deltas
+    Cat {
+        /// Stored path as printed by `find`
+        stored: PathBuf,
+    },
+    /// Inspect the configuration file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Report every problem in the config file with its location; exits with
+    /// code 2 if there are errors
+    Check,
+}
+
+impl Commands {
+    fn name(&self) -> &'static str {
+        match self {
+            Commands::Scan { .. } => "scan",
+            Commands::Backup => "backup",
+            Commands::Vacuum => "vacuum",
+            Commands::Status { .. } => "status",
+            Commands::Find(_) => "find",
+            Commands::Restore { .. } => "restore",
+            Commands::Cat { .. } => "cat",
+            Commands::Config { command: ConfigCommand::Check } => "config check",
+        }
+    }
+}
+
+#[derive(ClapArgs, Debug, Clone)]
+struct FindArgs {
+    /// Glob matched against the file name, e.g. "budget*.xlsx"
+    #[arg(long)]
+    name: Option<String>,
+    /// Regular expression matched against the original source path
+    #[arg(long)]
+    regex: Option<String>,
+    /// Only search the include root with this path or label
+    #[arg(long)]
+    root: Option<String>,
+    /// Source modified on or after this date (YYYY-MM-DD or RFC 3339); not
+    /// available on S3, which only keeps the upload time
+    #[arg(long, value_parser = find::parse_date)]
+    modified_after: Option<chrono::DateTime<chrono::Local>>,
+    /// Source modified before this date
+    #[arg(long, value_parser = find::parse_date)]
+    modified_before: Option<chrono::DateTime<chrono::Local>>,
+    /// Version backed up on or after this date
+    #[arg(long, value_parser = find::parse_date)]
+    backed_up_after: Option<chrono::DateTime<chrono::Local>>,
+    /// Version backed up before this date
+    #[arg(long, value_parser = find::parse_date)]
+    backed_up_before: Option<chrono::DateTime<chrono::Local>>,
+    /// Minimum size, e.g. 10K, 1.5M, 2G
+    #[arg(long, value_parser = find::parse_size)]
+    min_size: Option<u64>,
+    /// Maximum size, e.g. 10K, 1.5M, 2G
+    #[arg(long, value_parser = find::parse_size)]
+    max_size: Option<u64>,
+}
+
+impl From<FindArgs> for find::FindQuery {
+    fn from(args: FindArgs) -> Self {
+        Self {
+            name: args.name,
+            regex: args.regex,
+            root: args.root,
+            modified_after: args.modified_after,
+            modified_before: args.modified_before,
+            backed_up_after: args.backed_up_after,
+            backed_up_before: args.backed_up_before,
+            min_size: args.min_size,
+            max_size: args.max_size,
+        }
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    output::set_mode(args.output);
 
-    let config_data = fs::read_to_string(&args.config)?;
-    let config: Config = toml::from_str(&config_data)?;
+    // Keep stderr free of informational chatter when emitting JSON
+    let default_level = if output::is_json() { LevelFilter::Warn } else { LevelFilter::Info };
+    logging::init(logging::level_from_verbosity(default_level, args.verbose, args.quiet));
 
-    println!("Loaded config: {:?}", config);
+    // First Ctrl-C / SIGTERM asks the engine to stop after cleaning up the
+    // current file, a second one exits immediately.
+    let cancel = CancellationToken::new();
+    let handler_token = cancel.clone();
+    ctrlc::set_handler(move || {
+        if handler_token.is_cancelled() {
+            std::process::exit(EXIT_CANCELLED);
+        }
+        log::warn!("Cancellation requested, stopping after the current file (press Ctrl-C again to abort)");
+        handler_token.cancel();
+    })?;
 
-    match args.command {
-        Commands::Scan => backup::scan(&config, args.fullscan)?,
-        Commands::Backup => backup::run_backup(&config)?,
-        Commands::Vacuum => backup::vacuum(&config)?,
-        Commands::Status => backup::status(&config)?,
+    let command = args.command.name();
+    let mut finished = Vec::new();
+    match run(args, cancel, &mut finished) {
+        Ok(0) => {}
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            if output::is_json() {
+                // With --all, the jobs that finished before the failure keep their results
+                output::print_error(command, &e, Some(&finished).filter(|jobs| !jobs.is_empty()));
+            }
+            let code = exit_code(&e);
+            if code == EXIT_CANCELLED {
+                eprintln!("Cancelled; the next backup run resumes where this one stopped.");
+            } else {
+                eprintln!("Error: {:?}", e);
+            }
+            std::process::exit(code);
+        }
     }
-
     Ok(())
 }
+
+/// Map a failed run to the process exit code.
+fn exit_code(error: &anyhow::Error) -> i32 {
+    match error.downcast_ref::<Error>() {
+        Some(Error::ConfigRead { .. } | Error::ConfigParse { .. } | Error::InvalidConfig { .. }) => EXIT_USAGE,
+        Some(Error::InvalidPattern { .. } | Error::InvalidArgument(_) | Error::RootConflict { .. }) => EXIT_USAGE,
+        Some(Error::DestinationUnavailable { .. } | Error::DestinationNotDirectory { .. }) => EXIT_DESTINATION,
+        Some(Error::DestinationLocked { .. }) => EXIT_DESTINATION,
+        Some(Error::StateCorrupt { .. } | Error::CorruptVersion { .. }) => EXIT_STATE_CORRUPT,
+        Some(Error::SourceUnreadable { .. }) => EXIT_SOURCE_UNREADABLE,
+        Some(Error::OutOfSpace { .. }) => EXIT_OUT_OF_SPACE,
+        Some(Error::Cancelled) => EXIT_CANCELLED,
+        Some(Error::Io { .. } | Error::Serialize { .. }) | None => EXIT_FAILURE,
+    }
+}
+
+/// Run the command, returning the exit code for a run that completed.
+fn run(args: Args, cancel: CancellationToken, results: &mut Vec<output::JobResult>) -> anyhow::Result<i32> {
+    if let Commands::Config { command: ConfigCommand::Check } = &args.command {
+        let report = check::check_file(&args.config)?;
+        if output::is_json() {
+            output::print_result(args.command.name(), &report)?;
+        } else {
+            output::print_check(&report);
+        }
+        return Ok(if report.errors() > 0 { EXIT_USAGE } else { 0 });
+    }
+
+    if let Commands::Cat { stored } = &args.command {
+        let mut stdout = std::io::stdout().lock();
+        if storage::is_remote(&stored.to_string_lossy()) {
+            // Remote paths need the credentials of the destination they are in
+            let config = Config::load(&args.config)?;
+            let found = config.all_jobs().into_iter().find_map(|job| {
+                let storage = storage::open(&job.backup, false).ok()?;
+                let key = stored.strip_prefix(storage.location()).ok()?.to_path_buf();
+                Some((storage, key))
+            });
+            let Some((storage, key)) = found else {
+                return Err(Error::InvalidArgument(format!(
+                    "{} is not in a destination of {}",
+                    stored.display(),
+                    args.config.display()
+                ))
+                .into());
+            };
+            history::read_stored(&*storage, &key, &mut stdout)?;
+        } else {
+            history::read_version(stored, &mut stdout)?;
+        }
+        stdout.flush()?;
+        return Ok(0);
+    }
+
+    let config = Config::load(&args.config)?;
+    log::debug!("Loaded config from {}", args.config.display());
+
+    let jobs = select_jobs(&config, args.job.as_deref(), args.all)?;
+    let observer = CliObserver::default();
+    for job in &jobs {
+        if let Some(name) = &job.job {
+            output::say!("Job: {}", name);
+        }
+        let engine = BackupEngine::new(job)
+            .with_observer(&observer)
+            .with_cancellation(cancel.clone());
+
+        let result = run_job(&args, &engine).map_err(|e| match (&job.job, jobs.len()) {
+            (Some(name), 2..) => e.context(format!("Job {} failed", name)),
+            _ => e,
+        })?;
+        results.push(output::JobResult { job: job.job.clone(), result });
+    }
+
+    if output::is_json() {
+        let command = args.command.name();
+        if args.all {
+            output::print_result(command, &results)?;
+        } else if let Some(only) = results.pop() {
+            output::print_result(command, &only.result)?;
+        }
+    }
+    Ok(0)
+}
+
+/// Run the command for the job of `engine`, returning its JSON result.
+fn run_job(args: &Args, engine: &BackupEngine) -> anyhow::Result<serde_json::Value> {
+    match &args.command {
+        Commands::Scan { explain: Some(path) } => emit(engine.explain(path)?, output::print_explain),
+        Commands::Scan { explain: None } => emit(engine.scan(args.fullscan)?, output::print_scan),
+        Commands::Backup => emit(engine.backup()?, output::print_backup),
+        Commands::Vacuum => emit(engine.vacuum()?, output::print_vacuum),
+        Commands::Status { tail } => {
+            emit(engine.status(*tail)?, |r| output::print_status(r, *tail))
+        }
+        Commands::Find(find_args) => {
+            emit(engine.find(&find_args.clone().into())?, |v| output::print_find(v))
+        }
+        Commands::Restore { target, root } => {
+            emit(engine.restore(target, root.as_deref())?, output::print_restore)
+        }
+        Commands::Config { .. } | Commands::Cat { .. } => unreachable!("handled before loading the config"),
+    }
+}
+
+/// Resolve `--job`/`--all` to the configs to run. A config with a single job
+/// needs neither flag.
+fn select_jobs(config: &Config, job: Option<&str>, all: bool) -> anyhow::Result<Vec<Config>> {
+    if let Some(name) = job {
+        if config.jobs.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "--job {} given but the config defines no [[job]] tables",
+                name
+            ))
+            .into());
+        }
+        return Ok(vec![config.job(name)?]);
+    }
+    if all || config.jobs.len() <= 1 {
+        return Ok(config.all_jobs());
+    }
+    Err(Error::InvalidArgument(format!(
+        "The config defines several jobs ({}); pass --job NAME or --all",
+        config.job_names().join(", ")
+    ))
+    .into())
+}
+
+/// Print a command result in text mode with `print_text`, or return it as
+/// JSON for the final document.
+fn emit<T: serde::Serialize>(result: T, print_text: impl FnOnce(&T)) -> anyhow::Result<serde_json::Value> {
+    if output::is_json() {
+        Ok(serde_json::to_value(&result)?)
+    } else {
+        print_text(&result);
+        Ok(serde_json::Value::Null)
+    }
+}
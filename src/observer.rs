@@ -0,0 +1,58 @@
+use crate::backup::{BackupReport, FileResult, VacuumReport};
+use std::path::{Path, PathBuf};
+
+/// Work list of a backup run, reported before any file is touched.
+#[derive(Debug)]
+pub struct BackupPlan<'a> {
+    pub snapshot_id: u64,
+    /// Whether an interrupted run is being resumed
+    pub resumed: bool,
+    /// Source files that will be copied
+    pub changed: &'a [PathBuf],
+    /// Backup files whose source was deleted and that will move to `History`
+    pub removed: &'a [PathBuf],
+    /// Location of the `.incomplete` progress file
+    pub progress_file: &'a Path,
+}
+
+/// Receives progress events from a [`BackupEngine`](crate::BackupEngine).
+///
+/// Every method has an empty default implementation, so observers only
+/// implement the events they care about. Methods take `&self`; use interior
+/// mutability to keep state.
+pub trait BackupObserver {
+    /// A directory walk over `root` is about to check `total` files.
+    fn scan_started(&self, _root: &Path, _total: u64) {}
+    /// `path` was checked during a directory walk.
+    fn scan_progress(&self, _path: &Path) {}
+    /// The directory walk over `root` is done.
+    fn scan_finished(&self, _root: &Path) {}
+
+    /// A backup run determined its work list.
+    fn backup_started(&self, _plan: &BackupPlan) {}
+    /// Processing of `path` starts. Emitted for changed and removed files.
+    fn file_started(&self, _path: &Path) {}
+    /// `bytes` of `path` were written to the destination.
+    fn bytes_copied(&self, _path: &Path, _bytes: u64) {}
+    /// A stored file was moved from `from` to its `History` location `to`.
+    fn history_moved(&self, _from: &Path, _to: &Path) {}
+    /// Processing of a file ended, successfully or not.
+    fn file_finished(&self, _result: &FileResult) {}
+    /// The backup run completed and its state was recorded.
+    fn backup_finished(&self, _report: &BackupReport) {}
+
+    /// Vacuum selected `candidates` for deletion.
+    fn vacuum_started(&self, _candidates: &[PathBuf]) {}
+    /// An outdated version was deleted.
+    fn version_pruned(&self, _path: &Path) {}
+    /// An outdated snapshot tree was deleted.
+    fn snapshot_pruned(&self, _path: &Path) {}
+    /// Vacuum finished.
+    fn vacuum_finished(&self, _report: &VacuumReport) {}
+}
+
+/// Observer that ignores every event.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopObserver;
+
+impl BackupObserver for NoopObserver {}
@@ -0,0 +1,362 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use rustybackup::backup::{BackupReport, FileResult, ScanReport, StatusReport, VacuumReport};
+use rustybackup::check::{CheckReport, Severity};
+use rustybackup::exclude::Explanation;
+use rustybackup::find::FoundVersion;
+use rustybackup::restore::RestoreReport;
+use rustybackup::{BackupObserver, BackupPlan};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Version of the JSON document layout. Bump when fields are renamed or removed.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How command results are presented.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputMode {
+    /// Human readable text and progress bars
+    #[default]
+    Text,
+    /// A single JSON document on stdout, no progress bars
+    Json,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Select the output mode for the rest of the process.
+pub fn set_mode(mode: OutputMode) {
+    JSON.store(mode == OutputMode::Json, Ordering::Relaxed);
+}
+
+/// Whether machine readable output was requested.
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Print to stdout only in text mode.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !$crate::output::is_json() {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use say;
+
+/// Create a progress bar with the standard style. The bar is hidden in JSON mode.
+pub fn progress_bar(len: u64) -> ProgressBar {
+    if is_json() {
+        return ProgressBar::hidden();
+    }
+    let pb = ProgressBar::new(len);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} files ({eta})")
+            .unwrap()
+            .progress_chars("##-"),
+    );
+    pb
+}
+
+/// Observer driving the command line progress bars and plan listing.
+#[derive(Default)]
+pub struct CliObserver {
+    bar: Mutex<Option<ProgressBar>>,
+}
+
+impl CliObserver {
+    fn start_bar(&self, len: u64, message: String) {
+        let pb = progress_bar(len);
+        pb.set_message(message);
+        if let Ok(mut bar) = self.bar.lock() {
+            *bar = Some(pb);
+        }
+    }
+
+    fn with_bar(&self, f: impl FnOnce(&ProgressBar)) {
+        if let Ok(bar) = self.bar.lock() {
+            if let Some(pb) = bar.as_ref() {
+                f(pb);
+            }
+        }
+    }
+
+    fn finish_bar(&self, message: String) {
+        if let Ok(mut bar) = self.bar.lock() {
+            if let Some(pb) = bar.take() {
+                pb.finish_with_message(message);
+            }
+        }
+    }
+}
+
+impl BackupObserver for CliObserver {
+    fn scan_started(&self, root: &Path, total: u64) {
+        self.start_bar(total, root.display().to_string());
+    }
+
+    fn scan_progress(&self, _path: &Path) {
+        self.with_bar(|pb| pb.inc(1));
+    }
+
+    fn scan_finished(&self, root: &Path) {
+        self.finish_bar(format!("{} scanned", root.display()));
+    }
+
+    fn backup_started(&self, plan: &BackupPlan) {
+        if plan.resumed {
+            say!("Resuming previous backup from {}", plan.progress_file.display());
+        }
+        say!("files to update: ");
+        for p in plan.changed {
+            say!("{}", p.display());
+        }
+        say!("removed files: ");
+        for removed in plan.removed {
+            say!("{}", removed.display());
+        }
+        say!(
+            "Summary -> files changed: {} removed files: {}",
+            plan.changed.len(),
+            plan.removed.len()
+        );
+        say!("Progress file: {}", plan.progress_file.display());
+
+        let total = plan.changed.len() as u64 + plan.removed.len() as u64;
+        self.start_bar(total, String::new());
+    }
+
+    fn file_started(&self, path: &Path) {
+        self.with_bar(|pb| {
+            pb.inc(1);
+            pb.set_message(path.display().to_string());
+        });
+    }
+
+    fn file_finished(&self, _result: &FileResult) {}
+
+    fn backup_finished(&self, _report: &BackupReport) {
+        self.finish_bar("Backup completed.".to_string());
+    }
+
+    fn vacuum_started(&self, candidates: &[PathBuf]) {
+        say!("Found {} prune candidate(s):", candidates.len());
+        for path in candidates {
+            say!("{} ", path.display());
+        }
+        self.start_bar(candidates.len() as u64, String::new());
+    }
+
+    fn version_pruned(&self, path: &Path) {
+        self.with_bar(|pb| {
+            pb.inc(1);
+            pb.set_message(path.display().to_string());
+        });
+    }
+
+    fn snapshot_pruned(&self, path: &Path) {
+        self.version_pruned(path);
+    }
+
+    fn vacuum_finished(&self, _report: &VacuumReport) {
+        self.finish_bar("Vacuum complete.".to_string());
+    }
+}
+
+pub fn print_scan(report: &ScanReport) {
+    for f in &report.files {
+        say!("{}", f.path.display());
+    }
+    for m in &report.mount_points {
+        say!("Skipped mount point: {}", m.display());
+    }
+    if report.skipped_files > 0 {
+        say!(
+            "Skipped {} files and folders ({:.2} MB) by exclude rules:",
+            report.skipped_files,
+            report.skipped_bytes as f64 / (1024.0 * 1024.0)
+        );
+        for s in &report.skipped_by_source {
+            say!("  {}: {} ({:.2} MB)", s.source, s.files, s.bytes as f64 / (1024.0 * 1024.0));
+        }
+    }
+    say!(
+        "Scan complete: Found {} files / ({:.2} MB) changed since {}",
+        report.total_files,
+        report.total_bytes as f64 / (1024.0 * 1024.0),
+        report.since
+    );
+}
+
+pub fn print_backup(report: &BackupReport) {
+    say!(
+        "Backup {} finished: {} copied, {} linked, {} failed, {} removed ({:.2} MB)",
+        report.snapshot_id,
+        report.files_copied,
+        report.files_linked,
+        report.files_failed,
+        report.files_removed,
+        report.bytes_copied as f64 / (1024.0 * 1024.0)
+    );
+    if let Some(path) = &report.snapshot_path {
+        say!("Snapshot: {}", path.display());
+    }
+}
+
+pub fn print_vacuum(report: &VacuumReport) {
+    if report.pruned.is_empty() && report.snapshots_removed.is_empty() && report.uploads_discarded.is_empty() {
+        say!("Nothing to vacuum.");
+        return;
+    }
+    say!(
+        "Removed {} outdated file(s) and {} snapshot(s).",
+        report.files_removed,
+        report.snapshots_removed.len()
+    );
+    if !report.uploads_discarded.is_empty() {
+        say!("Aborted {} unfinished upload(s).", report.uploads_discarded.len());
+    }
+}
+
+pub fn print_restore(report: &RestoreReport) {
+    say!(
+        "Restored {} file(s) and {} hard link(s) to {} ({:.2} MB)",
+        report.files_restored,
+        report.files_linked,
+        report.target.display(),
+        report.bytes as f64 / (1024.0 * 1024.0)
+    );
+}
+
+pub fn print_status(report: &StatusReport, log_lines: usize) {
+    say!("Backup status: OK");
+    if let Some(latest) = &report.latest {
+        say!("Latest snapshot: {} at {}", latest.snapshot_id, latest.timestamp);
+    }
+    if log_lines > 0 {
+        match &report.latest {
+            Some(l) if !report.log_tail.is_empty() => {
+                say!("Last run log (snapshot {}):", l.snapshot_id);
+                for line in &report.log_tail {
+                    say!("{}", line);
+                }
+            }
+            _ => say!("No run log available."),
+        }
+    }
+}
+
+pub fn print_explain(explanation: &Explanation) {
+    let verdict = if explanation.excluded { "excluded" } else { "included" };
+    match (&explanation.root, &explanation.rule) {
+        (None, _) => say!("{}: excluded, not below any include root", explanation.path.display()),
+        (Some(root), None) => say!(
+            "{}: {} (no rule matched below root {})",
+            explanation.path.display(),
+            verdict,
+            root
+        ),
+        (Some(_), Some(rule)) => say!(
+            "{}: {} by '{}' from {}",
+            explanation.path.display(),
+            verdict,
+            rule.pattern,
+            rule.source
+        ),
+    }
+}
+
+pub fn print_check(report: &CheckReport) {
+    for p in &report.problems {
+        let location = match (p.line, p.column) {
+            (Some(line), Some(column)) => format!("{}:{}:{}", report.path.display(), line, column),
+            _ => report.path.display().to_string(),
+        };
+        let severity = match p.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match &p.key {
+            Some(key) => say!("{}: {}: {}: {}", location, severity, key, p.message),
+            None => say!("{}: {}: {}", location, severity, p.message),
+        }
+    }
+    if report.problems.is_empty() {
+        say!("{}: config OK", report.path.display());
+    } else {
+        let plural = |n: usize, word: &str| format!("{} {}{}", n, word, if n == 1 { "" } else { "s" });
+        say!("{}, {}", plural(report.errors(), "error"), plural(report.warnings(), "warning"));
+    }
+}
+
+pub fn print_find(versions: &[FoundVersion]) {
+    for v in versions {
+        let version = match v.archived {
+            Some(ts) => ts.format("%Y-%m-%d %H:%M:%S").to_string(),
+            None => "current".to_string(),
+        };
+        say!(
+            "{}  [{}]  {} bytes  modified {}",
+            v.source.display(),
+            version,
+            v.size,
+            v.modified.format("%Y-%m-%d %H:%M:%S")
+        );
+        if v.delta {
+            say!("    {} (delta, read with `cat`)", v.stored.display());
+        } else {
+            say!("    {}", v.stored.display());
+        }
+    }
+    say!("Found {} matching version(s).", versions.len());
+}
+
+/// Envelope around every JSON result.
+#[derive(Serialize)]
+pub struct Document<'a, T: Serialize> {
+    pub schema_version: u32,
+    pub command: &'a str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<&'a T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Result of one job in the `--all` JSON document.
+#[derive(Serialize)]
+pub struct JobResult {
+    pub job: Option<String>,
+    pub result: serde_json::Value,
+}
+
+/// Print the result of `command` as a JSON document on stdout.
+pub fn print_result<T: Serialize>(command: &str, result: &T) -> anyhow::Result<()> {
+    let doc = Document {
+        schema_version: SCHEMA_VERSION,
+        command,
+        ok: true,
+        result: Some(result),
+        error: None,
+    };
+    println!("{}", serde_json::to_string_pretty(&doc)?);
+    Ok(())
+}
+
+/// Print a failed `command` as a JSON document on stdout, with the `partial`
+/// result of the work that finished before the failure.
+pub fn print_error<T: Serialize>(command: &str, error: &anyhow::Error, partial: Option<&T>) {
+    let doc = Document {
+        schema_version: SCHEMA_VERSION,
+        command,
+        ok: false,
+        result: partial,
+        error: Some(format!("{:#}", error)),
+    };
+    if let Ok(text) = serde_json::to_string_pretty(&doc) {
+        println!("{}", text);
+    }
+}
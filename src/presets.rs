@@ -0,0 +1,79 @@
+//! Built-in exclude presets selected with `paths.exclude_presets`.
+//!
+//! Each preset is a list of patterns in `.gitignore` syntax, matched relative
+//! to every include root before `paths.ignore`, so a `!negation` in the
+//! config can re-include something a preset excludes.
+
+/// Build output and dependency folders of common toolchains.
+const DEV_BUILD_ARTIFACTS: &[&str] = &[
+    "node_modules/",
+    "bower_components/",
+    "target/",
+    "__pycache__/",
+    "*.py[cod]",
+    ".tox/",
+    ".mypy_cache/",
+    ".pytest_cache/",
+    ".gradle/",
+    ".next/",
+    ".nuxt/",
+    ".parcel-cache/",
+    "*.o",
+    "*.obj",
+];
+
+/// Files the operating system or file manager creates next to user files.
+const OS_METADATA: &[&str] = &[
+    ".DS_Store",
+    "._*",
+    ".Spotlight-V100/",
+    ".Trashes/",
+    ".fseventsd/",
+    ".TemporaryItems/",
+    "Thumbs.db",
+    "ehthumbs.db",
+    "desktop.ini",
+    "$RECYCLE.BIN/",
+    "System Volume Information/",
+    ".Trash-*/",
+    ".directory",
+];
+
+/// Cache folders of browsers, Electron apps and the XDG cache directory.
+const BROWSER_CACHES: &[&str] = &[
+    ".cache/",
+    "Cache/",
+    "Code Cache/",
+    "GPUCache/",
+    "ShaderCache/",
+    "GrShaderCache/",
+    "cache2/",
+    "CacheStorage/",
+];
+
+/// Internal data of version control systems.
+const VCS_INTERNALS: &[&str] = &[".git/", ".hg/", ".svn/", ".bzr/", "_darcs/", "CVS/"];
+
+/// All presets by name, in the order they are documented.
+pub const PRESETS: &[(&str, &[&str])] = &[
+    ("dev-build-artifacts", DEV_BUILD_ARTIFACTS),
+    ("os-metadata", OS_METADATA),
+    ("browser-caches", BROWSER_CACHES),
+    ("vcs-internals", VCS_INTERNALS),
+];
+
+/// Patterns of the preset called `name`.
+pub fn patterns(name: &str) -> Option<&'static [&'static str]> {
+    PRESETS.iter().find(|(n, _)| *n == name).map(|(_, p)| *p)
+}
+
+/// Names of all presets, for error messages.
+pub fn names() -> Vec<&'static str> {
+    PRESETS.iter().map(|(n, _)| *n).collect()
+}
+
+/// Rule source reported for patterns of the preset `name`, e.g. by
+/// `scan --explain` and in the scan summary.
+pub fn source(name: &str) -> String {
+    format!("preset:{}", name)
+}
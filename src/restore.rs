@@ -0,0 +1,163 @@
+//! Copying the live mirror back out of the destination.
+//!
+//! Every include root is restored to `<target>/<folder>/…`, the layout of the
+//! destination. The names of a multiply linked source file recorded in the
+//! state are linked to the restored copy of the name that holds its data, so
+//! a link group comes back as one inode, also from backends that stored the
+//! data once and nothing for the other names. Sparse files come back with
+//! their holes, also from backends that stored only their data.
+
+use crate::backup::STATE_KEY;
+use crate::copy;
+use crate::engine::BackupEngine;
+use crate::error::{Error, IoResultExt, Result};
+use crate::roots::RootRegistry;
+use crate::state::BackupState;
+use crate::storage::{self, ObjectInfo};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Result of a restore run.
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreReport {
+    pub target: PathBuf,
+    /// Files written from their stored copy
+    pub files_restored: u64,
+    /// Files recreated as hard links to another restored name
+    pub files_linked: u64,
+    /// Data written, without the holes of sparse files
+    pub bytes: u64,
+}
+
+pub(crate) fn execute_restore(engine: &BackupEngine, target: &Path, root: Option<&str>) -> Result<RestoreReport> {
+    let config = engine.config();
+    let storage = engine.storage(false)?;
+    let store = &*storage;
+
+    // Like `find`, roots removed from the config can still be restored
+    let mut registry = RootRegistry::load_from(store)?;
+    registry.resolve(&config.paths.include)?;
+    let folders: Vec<&str> = registry
+        .roots
+        .iter()
+        .filter(|r| root.is_none_or(|filter| filter == r.path || filter == r.folder))
+        .map(|r| r.folder.as_str())
+        .collect();
+    if let (Some(filter), true) = (root, folders.is_empty()) {
+        return Err(Error::InvalidArgument(format!("No include path matches root '{}'", filter)));
+    }
+
+    let mut objects: Vec<ObjectInfo> = Vec::new();
+    // Stored key to read for a restored name whose data is held by another name
+    let mut sources: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for folder in &folders {
+        let folder = Path::new(folder);
+        let listed = store.list(folder).at(store.display_path(folder))?;
+        // Interrupted copies are not restorable versions
+        objects.extend(listed.into_iter().filter(|o| o.key.extension().is_none_or(|e| e != "part")));
+    }
+    let stored: HashSet<PathBuf> = objects.iter().map(|o| o.key.clone()).collect();
+
+    // Names that are linked to the restored copy of their group's first name.
+    // With `--root`, the first name can lie in a root that is not restored: its
+    // data is then downloaded to the first selected name of the group, and the
+    // other selected names are linked to that one.
+    let state = storage::read_toml::<BackupState>(store, Path::new(STATE_KEY))?.unwrap_or_default();
+    let groups = state.namespace(config.job.as_deref()).map(|s| s.links.clone()).unwrap_or_default();
+    let selected = |key: &Path| folders.iter().any(|folder| key.starts_with(folder));
+    let mut links: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for group in &groups {
+        let first = &group.files[0];
+        let mut names = group.files[1..].iter().filter(|key| selected(key));
+        let holder = if stored.contains(first) {
+            first.clone()
+        } else {
+            let Some(holder) = names.next() else {
+                continue;
+            };
+            if !stored.contains(holder) {
+                match store.stat(first).at(store.display_path(first))? {
+                    Some(info) => objects.push(ObjectInfo { key: holder.clone(), ..info }),
+                    None => continue,
+                }
+                sources.insert(holder.clone(), first.clone());
+            }
+            holder.clone()
+        };
+        links.extend(names.filter(|key| **key != holder).map(|key| (key.clone(), holder.clone())));
+    }
+    let linked: HashMap<&Path, &Path> = links.iter().map(|(name, first)| (name.as_path(), first.as_path())).collect();
+
+    info!("Restoring {} file(s) to {}", objects.len() + links.len(), target.display());
+    let copy_options = config.backup.copy_options();
+    let mut report = RestoreReport {
+        target: target.to_path_buf(),
+        ..Default::default()
+    };
+    for object in objects.iter().filter(|o| !linked.contains_key(o.key.as_path())) {
+        engine.cancellation().check()?;
+        let path = restore_path(target, &object.key)?;
+        let key = sources.get(&object.key).unwrap_or(&object.key);
+        let bytes = match store.local_root() {
+            // Copied from the file system so holes stay holes
+            Some(root) => copy::copy_file(&root.join(key), &path, &copy_options, engine.cancellation())
+                .map(|copied| copied.bytes)
+                .at(&path)?,
+            None => {
+                let mut reader = store.get(key).at(store.display_path(key))?;
+                let mut file = File::create(&path).at(&path)?;
+                // Sparse files were stored without their holes
+                match state.extents(key) {
+                    Some(extents) => extents.restore(&mut reader, &mut file).at(&path)?,
+                    None => io::copy(&mut reader, &mut file).at(&path)?,
+                }
+            }
+        };
+        File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(object.modified))
+            .at(&path)?;
+        debug!("Restored {}", path.display());
+        report.files_restored += 1;
+        report.bytes += bytes;
+    }
+
+    for (name, first) in &links {
+        engine.cancellation().check()?;
+        let path = restore_path(target, name)?;
+        let original = target.join(first);
+        match fs::hard_link(&original, &path) {
+            Ok(()) => report.files_linked += 1,
+            Err(e) => {
+                warn!("Cannot link {} to {}, copying instead: {e}", path.display(), original.display());
+                report.bytes += fs::copy(&original, &path).at(&path)?;
+                report.files_restored += 1;
+            }
+        }
+    }
+    info!(
+        "Restored {} file(s) and {} hard link(s) to {}",
+        report.files_restored,
+        report.files_linked,
+        target.display()
+    );
+    Ok(report)
+}
+
+/// Path of `key` below `target` with its parent created. Existing files are
+/// never overwritten.
+fn restore_path(target: &Path, key: &Path) -> Result<PathBuf> {
+    let path = target.join(key);
+    if path.symlink_metadata().is_ok() {
+        return Err(Error::io(&path, io::Error::new(io::ErrorKind::AlreadyExists, "file exists")));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).at(parent)?;
+    }
+    Ok(path)
+}
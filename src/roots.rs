@@ -0,0 +1,187 @@
+use chrono::{DateTime, Local};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use crate::config::IncludeRoot;
+use crate::error::{Error, Result};
+use crate::storage::{self, LocalStorage, StorageBackend};
+
+/// Folder names in the destination that can never hold a source root.
+pub(crate) const RESERVED: &[&str] = &["History", "logs", "snapshots"];
+/// Key of the registry in the destination.
+const REGISTRY_KEY: &str = "roots.toml";
+
+/// Persistent mapping of source roots to their folder in the destination,
+/// stored as `roots.toml` next to `state.toml`.
+///
+/// A root keeps its folder for the lifetime of the destination, even after it
+/// is removed from the config, so stored files can always be traced back to
+/// their original path.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RootRegistry {
+    #[serde(default, rename = "root")]
+    pub roots: Vec<RegisteredRoot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredRoot {
+    /// Source root as written in the config
+    pub path: String,
+    /// Folder below the destination holding the mirror and `History` of this root
+    pub folder: String,
+    /// When the root was first backed up to this destination
+    pub added: DateTime<Local>,
+}
+
+/// An include root together with its folder in the destination.
+#[derive(Debug, Clone)]
+pub struct MappedRoot {
+    pub root: IncludeRoot,
+    pub folder: String,
+}
+
+/// The include roots of a config resolved against a [`RootRegistry`].
+#[derive(Debug, Clone, Default)]
+pub struct RootMap {
+    pub roots: Vec<MappedRoot>,
+}
+
+impl RootMap {
+    /// The innermost root containing `path`, and `path` relative to it.
+    pub fn locate<'p>(&self, path: &'p Path) -> Option<(&MappedRoot, &'p Path)> {
+        self.roots
+            .iter()
+            .filter_map(|m| path.strip_prefix(&m.root.path).ok().map(|rel| (m, rel)))
+            .min_by_key(|(_, rel)| rel.components().count())
+    }
+
+    /// Source path of the mirror `key`, `<folder>/<relative path>`.
+    pub fn source_of(&self, key: &Path) -> Option<PathBuf> {
+        self.roots
+            .iter()
+            .find_map(|m| key.strip_prefix(&m.folder).ok().map(|rel| Path::new(&m.root.path).join(rel)))
+    }
+
+    /// Include roots in config order.
+    pub fn include_roots(&self) -> Vec<IncludeRoot> {
+        self.roots.iter().map(|m| m.root.clone()).collect()
+    }
+}
+
+impl RootRegistry {
+    /// Path of the registry file in `dest`.
+    pub fn path(dest: &Path) -> PathBuf {
+        dest.join(REGISTRY_KEY)
+    }
+
+    /// Load the registry of `dest`, or an empty one if none was written yet.
+    pub fn load(dest: &Path) -> Result<Self> {
+        Self::load_from(&LocalStorage::new(dest))
+    }
+
+    pub fn save(&self, dest: &Path) -> Result<()> {
+        self.save_to(&LocalStorage::new(dest))
+    }
+
+    /// Like [`load`](Self::load) for any storage backend.
+    pub fn load_from(storage: &dyn StorageBackend) -> Result<Self> {
+        Ok(storage::read_toml(storage, Path::new(REGISTRY_KEY))?.unwrap_or_default())
+    }
+
+    pub fn save_to(&self, storage: &dyn StorageBackend) -> Result<()> {
+        storage::write_toml(storage, Path::new(REGISTRY_KEY), self)
+    }
+
+    /// Folder registered for the source root `path`.
+    pub fn folder_of(&self, path: &str) -> Option<&str> {
+        self.roots.iter().find(|r| r.path == path).map(|r| r.folder.as_str())
+    }
+
+    fn is_taken(&self, folder: &str) -> bool {
+        RESERVED.contains(&folder) || self.roots.iter().any(|r| r.folder == folder)
+    }
+
+    /// Map every root in `roots` to its folder, registering new roots.
+    ///
+    /// A new root gets its `label`, or the folder name derived from its path.
+    /// If a derived name is already used by another root, a numeric suffix is
+    /// appended; an explicit label that is taken, or that differs from the
+    /// folder a root was registered with, is an [`Error::RootConflict`].
+    /// Returns the map and whether new roots were registered.
+    pub fn resolve(&mut self, roots: &[IncludeRoot]) -> Result<(RootMap, bool)> {
+        let registered = self.roots.len();
+        let mut map = RootMap::default();
+        for root in roots {
+            let folder = match (self.folder_of(&root.path), &root.label) {
+                (Some(folder), Some(label)) if folder != label => {
+                    return Err(Error::RootConflict {
+                        root: root.path.clone(),
+                        folder: label.clone(),
+                        reason: format!(
+                            "the root is already stored in '{}'; rename that folder in roots.toml and the destination or drop the label",
+                            folder
+                        ),
+                    });
+                }
+                (Some(folder), _) => folder.to_string(),
+                (None, Some(label)) => {
+                    if self.is_taken(label) {
+                        let owner = self.roots.iter().find(|r| r.folder == *label);
+                        return Err(Error::RootConflict {
+                            root: root.path.clone(),
+                            folder: label.clone(),
+                            reason: match owner {
+                                Some(owner) => format!("the folder is used by '{}'", owner.path),
+                                None => "the name is reserved".to_string(),
+                            },
+                        });
+                    }
+                    self.register(root, label.clone())
+                }
+                (None, None) => {
+                    let base = root.proposed_folder();
+                    let mut folder = base.clone();
+                    let mut n = 2;
+                    while self.is_taken(&folder) {
+                        folder = format!("{}-{}", base, n);
+                        n += 1;
+                    }
+                    if folder != base {
+                        warn!(
+                            "Folder '{}' is already used, storing {} in '{}'",
+                            base, root.path, folder
+                        );
+                    }
+                    self.register(root, folder)
+                }
+            };
+            map.roots.push(MappedRoot { root: root.clone(), folder });
+        }
+        Ok((map, self.roots.len() != registered))
+    }
+
+    fn register(&mut self, root: &IncludeRoot, folder: String) -> String {
+        self.roots.push(RegisteredRoot {
+            path: root.path.clone(),
+            folder: folder.clone(),
+            added: Local::now(),
+        });
+        folder
+    }
+}
+
+/// Resolve the include roots of a destination, writing newly registered roots
+/// back to `roots.toml` when `persist` is set.
+pub fn map_roots(dest: &Path, roots: &[IncludeRoot], persist: bool) -> Result<RootMap> {
+    map_roots_in(&LocalStorage::new(dest), roots, persist)
+}
+
+/// Like [`map_roots`] for any storage backend.
+pub fn map_roots_in(storage: &dyn StorageBackend, roots: &[IncludeRoot], persist: bool) -> Result<RootMap> {
+    let mut registry = RootRegistry::load_from(storage)?;
+    let (map, changed) = registry.resolve(roots)?;
+    if persist && changed {
+        registry.save_to(storage)?;
+    }
+    Ok(map)
+}
@@ -0,0 +1,754 @@
+//! Destinations in an S3-compatible bucket, such as MinIO or AWS S3.
+//!
+//! `backup.destination = "s3://bucket/prefix"` stores every key below
+//! `prefix/` in `bucket`. Requests are signed with AWS Signature Version 4
+//! and share one connection pool. Objects of at least `part_size` bytes are
+//! sent as multipart uploads, straight to their final key since an object
+//! only changes once its upload completes. An upload that stops while reading
+//! the source or on a transient error, e.g. because the run was cancelled or
+//! the connection dropped, stays open in the bucket; the resumed run sends the
+//! same key again and only uploads the parts that are missing or changed.
+//! `vacuum` aborts uploads that nobody resumed for a day. History moves are
+//! server-side copies, so no data goes through the client.
+
+use crate::error::{Error, Result};
+use crate::storage::{self, ObjectInfo, StorageBackend, StorageLock, STALE_LOCK};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use md5::Md5;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Part size of multipart uploads unless `backup.s3.part_size` is set.
+pub const DEFAULT_PART_SIZE: u64 = 16 * 1024 * 1024;
+/// Smallest part S3 accepts, except for the last part of an upload.
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// Largest object or part S3 copies in a single request.
+pub const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+/// Most parts in one multipart upload.
+const MAX_PARTS: u64 = 10_000;
+
+/// Settings from `[backup.s3]`. Unset credentials, region and endpoint are
+/// read from the usual `AWS_*` environment variables.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3Options {
+    /// Service URL such as `http://minio.local:9000`; AWS S3 if unset
+    pub endpoint: Option<String>,
+    /// Signing region, `us-east-1` if unset
+    pub region: Option<String>,
+    /// Address buckets as `endpoint/bucket` instead of `bucket.endpoint`.
+    /// On by default with a custom endpoint.
+    pub path_style: Option<bool>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// Size of the parts of multipart uploads
+    #[serde(default, deserialize_with = "crate::config::size")]
+    pub part_size: Option<u64>,
+}
+
+impl std::fmt::Debug for S3Options {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Options")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("path_style", &self.path_style)
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_access_key", &self.secret_access_key.as_ref().map(|_| "<redacted>"))
+            .field("part_size", &self.part_size)
+            .finish()
+    }
+}
+
+impl S3Options {
+    pub fn part_size(&self) -> u64 {
+        self.part_size.unwrap_or(DEFAULT_PART_SIZE)
+    }
+}
+
+#[derive(Clone)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A destination in an S3-compatible bucket.
+#[derive(Clone)]
+pub struct S3Storage {
+    location: PathBuf,
+    bucket: String,
+    /// Prepended to every key, empty or ending in `/`
+    prefix: String,
+    /// `scheme://host[:port]` of the service
+    endpoint: String,
+    path_style: bool,
+    region: String,
+    credentials: Option<Credentials>,
+    part_size: u64,
+    agent: ureq::Agent,
+}
+
+impl std::fmt::Debug for S3Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Storage")
+            .field("location", &self.location)
+            .field("endpoint", &self.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+impl S3Storage {
+    /// Storage for `destination`, an `s3://bucket[/prefix]` URL.
+    pub fn new(destination: &str, options: &S3Options) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidArgument(format!("invalid S3 destination '{}': {}", destination, reason));
+        let rest = destination
+            .strip_prefix("s3://")
+            .ok_or_else(|| invalid("expected s3://bucket/prefix"))?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(invalid("the bucket name is missing"));
+        }
+        let prefix = prefix.trim_matches('/');
+
+        let region = options
+            .region
+            .clone()
+            .or_else(|| env("AWS_REGION"))
+            .or_else(|| env("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let custom = options.endpoint.clone().or_else(|| env("AWS_ENDPOINT_URL"));
+        let path_style = options.path_style.unwrap_or(custom.is_some());
+        let endpoint = custom
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region))
+            .trim_end_matches('/')
+            .to_string();
+        if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+            return Err(invalid("the endpoint must be an http:// or https:// URL"));
+        }
+        let access_key_id = options.access_key_id.clone().or_else(|| env("AWS_ACCESS_KEY_ID"));
+        let secret_access_key = options.secret_access_key.clone().or_else(|| env("AWS_SECRET_ACCESS_KEY"));
+        let credentials = match (access_key_id, secret_access_key) {
+            (Some(access_key_id), Some(secret_access_key)) => Some(Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: env("AWS_SESSION_TOKEN"),
+            }),
+            (None, None) => None,
+            _ => return Err(invalid("set both access_key_id and secret_access_key, or neither")),
+        };
+
+        Ok(Self {
+            location: PathBuf::from(format!("s3://{}/{}", bucket, prefix).trim_end_matches('/')),
+            bucket: bucket.to_string(),
+            prefix: if prefix.is_empty() { String::new() } else { format!("{}/", prefix) },
+            endpoint,
+            path_style,
+            region,
+            credentials,
+            part_size: options.part_size(),
+            agent: ureq::AgentBuilder::new().timeout_connect(Duration::from_secs(30)).build(),
+        })
+    }
+
+    /// Fail with [`Error::DestinationUnavailable`] unless the bucket can be reached.
+    pub fn check(&self) -> Result<()> {
+        self.send("HEAD", "", &[], &[], &[])
+            .map(|_| ())
+            .map_err(|source| Error::DestinationUnavailable { path: self.location.clone(), source })
+    }
+
+    /// Object name of `key` in the bucket.
+    fn object(&self, key: &Path) -> String {
+        let parts: Vec<_> = key.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+        format!("{}{}", self.prefix, parts.join("/"))
+    }
+
+    fn key_of(&self, object: &str) -> PathBuf {
+        PathBuf::from(object.strip_prefix(&self.prefix).unwrap_or(object))
+    }
+
+    /// Send a signed request for `object` (the bucket itself if empty).
+    fn send(
+        &self,
+        method: &str,
+        object: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<ureq::Response> {
+        let (scheme, authority) = self.endpoint.split_once("://").expect("checked in new");
+        let (host, path) = if self.path_style {
+            let path = match object {
+                "" => format!("/{}", self.bucket),
+                _ => format!("/{}/{}", self.bucket, encode(object, true)),
+            };
+            (authority.to_string(), path)
+        } else {
+            (format!("{}.{}", self.bucket, authority), format!("/{}", encode(object, true)))
+        };
+        let query_string = canonical_query(query);
+        let url = match query_string.as_str() {
+            "" => format!("{}://{}{}", scheme, host, path),
+            _ => format!("{}://{}{}?{}", scheme, host, path, query_string),
+        };
+
+        let mut request = self.agent.request(method, &url);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        if let Some(credentials) = &self.credentials {
+            let now = Utc::now();
+            let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+            let payload = hex::encode(Sha256::digest(body));
+            let mut signed: Vec<(String, String)> = vec![
+                ("host".to_string(), host.clone()),
+                ("x-amz-content-sha256".to_string(), payload.clone()),
+                ("x-amz-date".to_string(), timestamp),
+            ];
+            if let Some(token) = &credentials.session_token {
+                signed.push(("x-amz-security-token".to_string(), token.clone()));
+            }
+            for (name, value) in headers.iter().filter(|(n, _)| n.starts_with("x-amz-")) {
+                signed.push((name.to_string(), value.trim().to_string()));
+            }
+            signed.sort();
+            let canonical = CanonicalRequest {
+                method,
+                path: &path,
+                query,
+                headers: &signed,
+                payload: &payload,
+            };
+            let authorization = authorization(
+                &canonical,
+                &credentials.access_key_id,
+                &credentials.secret_access_key,
+                &self.region,
+                now,
+            );
+            for (name, value) in signed.iter().filter(|(n, _)| n != "host") {
+                request = request.set(name, value);
+            }
+            request = request.set("authorization", &authorization);
+        }
+
+        let what = format!("{} {}", method, if object.is_empty() { &self.bucket } else { object });
+        let result = match method {
+            "GET" | "HEAD" | "DELETE" => request.call(),
+            _ => request.send_bytes(body),
+        };
+        match result {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(code, response)) => Err(status_error(code, response, &what)),
+            Err(ureq::Error::Transport(e)) => Err(io::Error::other(format!("{}: {}", what, e))),
+        }
+    }
+
+    /// Size and modification time of `object`, `None` if it does not exist.
+    fn head(&self, object: &str) -> io::Result<Option<(u64, SystemTime)>> {
+        let response = match self.send("HEAD", object, &[], &[], &[]) {
+            Ok(response) => response,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let size = response
+            .header("content-length")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let modified = response
+            .header("last-modified")
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+            .map_or(SystemTime::UNIX_EPOCH, SystemTime::from);
+        Ok(Some((size, modified)))
+    }
+
+    /// Id of an unfinished multipart upload of `object` left by an earlier run.
+    fn pending_upload(&self, object: &str) -> io::Result<Option<String>> {
+        Ok(self
+            .uploads(object)?
+            .into_iter()
+            .rev()
+            .find(|(key, _, _)| key == object)
+            .map(|(_, id, _)| id))
+    }
+
+    /// Unfinished multipart uploads of objects starting with `prefix`, as
+    /// `(object, upload id, start time)`.
+    fn uploads(&self, prefix: &str) -> io::Result<Vec<(String, String, Option<SystemTime>)>> {
+        let mut uploads = Vec::new();
+        let mut markers: Option<(String, String)> = None;
+        loop {
+            let mut query = vec![("uploads", ""), ("prefix", prefix)];
+            if let Some((key, id)) = &markers {
+                query.push(("key-marker", key));
+                query.push(("upload-id-marker", id));
+            }
+            let body = self.send("GET", "", &query, &[], &[])?.into_string()?;
+            for upload in elements(&body, "Upload") {
+                if let (Some(key), Some(id)) = (text(upload, "Key"), text(upload, "UploadId")) {
+                    let initiated = text(upload, "Initiated")
+                        .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+                        .map(SystemTime::from);
+                    uploads.push((key, id, initiated));
+                }
+            }
+            match (text(&body, "NextKeyMarker"), text(&body, "NextUploadIdMarker")) {
+                (Some(key), Some(id)) if text(&body, "IsTruncated").as_deref() == Some("true") => {
+                    markers = Some((key, id));
+                }
+                _ => return Ok(uploads),
+            }
+        }
+    }
+
+    /// Parts already stored for `upload_id` as `(number, etag, size)`.
+    fn uploaded_parts(&self, object: &str, upload_id: &str) -> io::Result<Vec<(u64, String, u64)>> {
+        let mut parts = Vec::new();
+        let mut marker = String::new();
+        loop {
+            let mut query = vec![("uploadId", upload_id)];
+            if !marker.is_empty() {
+                query.push(("part-number-marker", marker.as_str()));
+            }
+            let body = self.send("GET", object, &query, &[], &[])?.into_string()?;
+            for part in elements(&body, "Part") {
+                let number = text(part, "PartNumber").and_then(|n| n.parse().ok());
+                let size = text(part, "Size").and_then(|n| n.parse().ok());
+                if let (Some(number), Some(etag), Some(size)) = (number, text(part, "ETag"), size) {
+                    parts.push((number, etag.trim_matches('"').to_string(), size));
+                }
+            }
+            match text(&body, "NextPartNumberMarker") {
+                Some(next) if text(&body, "IsTruncated").as_deref() == Some("true") => marker = next,
+                _ => return Ok(parts),
+            }
+        }
+    }
+
+    fn create_upload(&self, object: &str) -> io::Result<String> {
+        let body = self.send("POST", object, &[("uploads", "")], &[], &[])?.into_string()?;
+        text(&body, "UploadId").ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no UploadId in response"))
+    }
+
+    fn complete_upload(&self, object: &str, upload_id: &str, parts: &[(u64, String)]) -> io::Result<()> {
+        let mut xml = String::from("<CompleteMultipartUpload>");
+        for (number, etag) in parts {
+            let _ = write!(xml, "<Part><PartNumber>{}</PartNumber><ETag>\"{}\"</ETag></Part>", number, etag);
+        }
+        xml.push_str("</CompleteMultipartUpload>");
+        let body = self
+            .send("POST", object, &[("uploadId", upload_id)], &[], xml.as_bytes())?
+            .into_string()?;
+        // S3 reports some failures of long requests in a 200 response
+        check_body(&body, object)
+    }
+
+    fn abort_upload(&self, object: &str, upload_id: &str) {
+        if let Err(e) = self.send("DELETE", object, &[("uploadId", upload_id)], &[], &[]) {
+            warn!("Failed to abort the upload of {}: {e}", object);
+        }
+    }
+
+    fn put_multipart(&self, object: &str, mut chunk: Vec<u8>, data: &mut dyn Read) -> io::Result<u64> {
+        let (upload_id, stored) = match self.pending_upload(object)? {
+            Some(id) => {
+                let stored = self.uploaded_parts(object, &id)?;
+                debug!("Resuming the upload of {} with {} stored part(s)", object, stored.len());
+                (id, stored)
+            }
+            None => (self.create_upload(object)?, Vec::new()),
+        };
+
+        let mut parts = Vec::new();
+        let mut total = 0u64;
+        loop {
+            let number = parts.len() as u64 + 1;
+            if number > MAX_PARTS {
+                self.abort_upload(object, &upload_id);
+                return Err(io::Error::other(format!(
+                    "{} needs more than {} parts; raise backup.s3.part_size",
+                    object, MAX_PARTS
+                )));
+            }
+            let etag = hex::encode(Md5::digest(&chunk));
+            let kept = stored.iter().any(|(n, e, size)| *n == number && *e == etag && *size == chunk.len() as u64);
+            if !kept {
+                let number_text = number.to_string();
+                let query = [("partNumber", number_text.as_str()), ("uploadId", upload_id.as_str())];
+                if let Err(e) = self.send("PUT", object, &query, &[], &chunk) {
+                    return Err(self.fail_upload(object, &upload_id, e));
+                }
+            }
+            total += chunk.len() as u64;
+            parts.push((number, etag));
+            // A failed read leaves the upload open for the resumed run
+            chunk = read_chunk(data, self.part_size)?;
+            if chunk.is_empty() {
+                break;
+            }
+        }
+        if let Err(e) = self.complete_upload(object, &upload_id, &parts) {
+            return Err(self.fail_upload(object, &upload_id, e));
+        }
+        Ok(total)
+    }
+
+    /// Keep the upload and its stored parts for the resumed run if the failure
+    /// may pass, abort it otherwise. Returns `error`.
+    fn fail_upload(&self, object: &str, upload_id: &str, error: io::Error) -> io::Error {
+        if is_transient(&error) {
+            debug!("Keeping the upload of {} open for the next run: {error}", object);
+        } else {
+            self.abort_upload(object, upload_id);
+        }
+        error
+    }
+
+    /// Server-side copy of `source` to `target`, in parts above [`MAX_PART_SIZE`].
+    fn copy(&self, source: &str, target: &str, size: u64) -> io::Result<()> {
+        let copy_source = format!("{}/{}", self.bucket, encode(source, true));
+        if size <= MAX_PART_SIZE {
+            let body = self
+                .send("PUT", target, &[], &[("x-amz-copy-source", &copy_source)], &[])?
+                .into_string()?;
+            return check_body(&body, target);
+        }
+
+        let part_size = self.part_size.max(size.div_ceil(MAX_PARTS)).min(MAX_PART_SIZE);
+        let upload_id = self.create_upload(target)?;
+        let mut parts = Vec::new();
+        for (idx, start) in (0..size).step_by(part_size as usize).enumerate() {
+            let number = (idx + 1).to_string();
+            let range = format!("bytes={}-{}", start, (start + part_size).min(size) - 1);
+            let query = [("partNumber", number.as_str()), ("uploadId", upload_id.as_str())];
+            let headers = [("x-amz-copy-source", copy_source.as_str()), ("x-amz-copy-source-range", range.as_str())];
+            let body = match self.send("PUT", target, &query, &headers, &[]).and_then(|r| r.into_string()) {
+                Ok(body) => body,
+                Err(e) => {
+                    self.abort_upload(target, &upload_id);
+                    return Err(e);
+                }
+            };
+            let Some(etag) = text(&body, "ETag") else {
+                self.abort_upload(target, &upload_id);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "no ETag in UploadPartCopy response"));
+            };
+            parts.push((idx as u64 + 1, etag.trim_matches('"').to_string()));
+        }
+        if let Err(e) = self.complete_upload(target, &upload_id, &parts) {
+            self.abort_upload(target, &upload_id);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for S3Storage {
+    fn location(&self) -> &Path {
+        &self.location
+    }
+
+    // The object only changes once the whole PUT or multipart upload succeeded
+    fn atomic_put(&self) -> bool {
+        true
+    }
+
+    fn put(&self, key: &Path, data: &mut dyn Read) -> io::Result<u64> {
+        let object = self.object(key);
+        let first = read_chunk(data, self.part_size)?;
+        if (first.len() as u64) < self.part_size {
+            self.send("PUT", &object, &[], &[], &first)?;
+            return Ok(first.len() as u64);
+        }
+        self.put_multipart(&object, first, data)
+    }
+
+    fn get(&self, key: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let response = self.send("GET", &self.object(key), &[], &[], &[])?;
+        Ok(Box::new(response.into_reader()))
+    }
+
+    fn stat(&self, key: &Path) -> io::Result<Option<ObjectInfo>> {
+        Ok(self.head(&self.object(key))?.map(|(size, modified)| ObjectInfo {
+            key: key.to_path_buf(),
+            size,
+            modified,
+        }))
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<ObjectInfo>> {
+        let mut object_prefix = self.object(prefix);
+        if !object_prefix.is_empty() && !object_prefix.ends_with('/') {
+            object_prefix.push('/');
+        }
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", object_prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token));
+            }
+            let body = self.send("GET", "", &query, &[], &[])?.into_string()?;
+            for entry in elements(&body, "Contents") {
+                let Some(name) = text(entry, "Key").filter(|k| !k.ends_with('/')) else {
+                    continue;
+                };
+                objects.push(ObjectInfo {
+                    key: self.key_of(&name),
+                    size: text(entry, "Size").and_then(|s| s.parse().ok()).unwrap_or(0),
+                    modified: text(entry, "LastModified")
+                        .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+                        .map_or(SystemTime::UNIX_EPOCH, SystemTime::from),
+                });
+            }
+            token = text(&body, "NextContinuationToken");
+            if token.is_none() || text(&body, "IsTruncated").as_deref() != Some("true") {
+                break;
+            }
+        }
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (source, target) = (self.object(from), self.object(to));
+        let (size, _) = self.head(&source)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no object {}", source))
+        })?;
+        self.copy(&source, &target, size)?;
+        self.send("DELETE", &source, &[], &[], &[])?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &Path) -> io::Result<()> {
+        self.send("DELETE", &self.object(key), &[], &[], &[])?;
+        Ok(())
+    }
+
+    fn lock(&self, key: &Path) -> io::Result<StorageLock> {
+        // A conditional PUT only succeeds while the lock object does not exist
+        let object = self.object(key);
+        let body = storage::lock_contents();
+        for attempt in 0..2 {
+            match self.send("PUT", &object, &[], &[("if-none-match", "*")], body.as_bytes()) {
+                Ok(_) => {
+                    return Ok(StorageLock::new(S3Lock {
+                        storage: self.clone(),
+                        object,
+                    }))
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt == 0 => {
+                    let mut contents = String::new();
+                    let read = self.get(key).and_then(|mut reader| reader.read_to_string(&mut contents));
+                    let stale = match read.ok().and_then(|_| storage::lock_is_stale(&contents)) {
+                        Some(stale) => stale,
+                        // Unreadable contents fall back to the object's age
+                        None => self
+                            .head(&object)?
+                            .is_some_and(|(_, modified)| modified.elapsed().is_ok_and(|age| age > STALE_LOCK)),
+                    };
+                    if !stale {
+                        break;
+                    }
+                    warn!("Removing the stale lock {} of a run that did not finish", object);
+                    self.send("DELETE", &object, &[], &[], &[])?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::WouldBlock))
+    }
+
+    fn discard_stale_uploads(&self, older_than: Duration) -> io::Result<Vec<PathBuf>> {
+        let mut discarded = Vec::new();
+        for (object, upload_id, initiated) in self.uploads(&self.prefix)? {
+            if initiated.is_some_and(|t| t.elapsed().is_ok_and(|age| age > older_than)) {
+                self.send("DELETE", &object, &[("uploadId", &upload_id)], &[], &[])?;
+                discarded.push(self.key_of(&object));
+            }
+        }
+        Ok(discarded)
+    }
+}
+
+struct S3Lock {
+    storage: S3Storage,
+    object: String,
+}
+
+impl Drop for S3Lock {
+    fn drop(&mut self) {
+        if let Err(e) = self.storage.send("DELETE", &self.object, &[], &[], &[]) {
+            warn!("Failed to release the lock {}: {e}", self.object);
+        }
+    }
+}
+
+/// The parts of a request covered by its AWS Signature Version 4.
+pub struct CanonicalRequest<'a> {
+    pub method: &'a str,
+    /// URI-encoded path, starting with `/`
+    pub path: &'a str,
+    /// Query parameters, not yet encoded
+    pub query: &'a [(&'a str, &'a str)],
+    /// Lowercase names and trimmed values of the signed headers, sorted by name
+    pub headers: &'a [(String, String)],
+    /// Hex-encoded SHA-256 of the body
+    pub payload: &'a str,
+}
+
+/// `Authorization` header for `request` sent at `time` to S3 in `region`.
+pub fn authorization(
+    request: &CanonicalRequest,
+    access_key_id: &str,
+    secret_access_key: &str,
+    region: &str,
+    time: DateTime<Utc>,
+) -> String {
+    let date = time.format("%Y%m%d").to_string();
+    let timestamp = time.format("%Y%m%dT%H%M%SZ").to_string();
+    let names = request.headers.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>().join(";");
+    let canonical_headers: String = request.headers.iter().map(|(n, v)| format!("{}:{}\n", n, v)).collect();
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        request.method,
+        request.path,
+        canonical_query(request.query),
+        canonical_headers,
+        names,
+        request.payload
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let mut key = hmac(format!("AWS4{}", secret_access_key).as_bytes(), date.as_bytes());
+    for part in [region, "s3", "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key_id, scope, names, signature
+    )
+}
+
+/// Encoded query parameters sorted by name, as sent and signed.
+fn canonical_query(query: &[(&str, &str)]) -> String {
+    let mut pairs: Vec<(String, String)> = query.iter().map(|(k, v)| (encode(k, false), encode(v, false))).collect();
+    pairs.sort();
+    pairs.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&")
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode everything but unreserved characters, and `/` if `keep_slash`.
+fn encode(value: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(byte as char),
+            b'/' if keep_slash => out.push('/'),
+            _ => {
+                let _ = write!(out, "%{:02X}", byte);
+            }
+        }
+    }
+    out
+}
+
+/// Read up to `limit` bytes, less only at the end of `data`.
+fn read_chunk(data: &mut dyn Read, limit: u64) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::new();
+    data.take(limit).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+fn status_error(code: u16, response: ureq::Response, what: &str) -> io::Error {
+    let body = response.into_string().unwrap_or_default();
+    let detail = text(&body, "Message").or_else(|| text(&body, "Code")).unwrap_or_default();
+    let kind = match code {
+        404 => io::ErrorKind::NotFound,
+        403 => io::ErrorKind::PermissionDenied,
+        412 => io::ErrorKind::AlreadyExists,
+        // Timeouts, throttling and server errors may pass when sent again
+        408 | 429 | 500.. => io::ErrorKind::Other,
+        _ => io::ErrorKind::InvalidInput,
+    };
+    io::Error::new(kind, format!("S3 returned {} for {}: {}", code, what, detail))
+}
+
+/// Whether a failed request may succeed when sent again: transport errors,
+/// timeouts, throttling and server errors.
+fn is_transient(error: &io::Error) -> bool {
+    !matches!(
+        error.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::AlreadyExists
+            | io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidData
+    )
+}
+
+fn check_body(body: &str, object: &str) -> io::Result<()> {
+    match elements(body, "Error").next() {
+        Some(error) => Err(io::Error::other(format!(
+            "S3 failed on {}: {}",
+            object,
+            text(error, "Message").or_else(|| text(error, "Code")).unwrap_or_default()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Contents of every `<tag>…</tag>` element in `xml`.
+fn elements<'a>(xml: &'a str, tag: &str) -> impl Iterator<Item = &'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let len = rest[start..].find(&close)?;
+        let content = &rest[start..start + len];
+        rest = &rest[start + len + close.len()..];
+        Some(content)
+    })
+}
+
+/// Unescaped text of the first `<tag>` element in `xml`.
+fn text(xml: &str, tag: &str) -> Option<String> {
+    elements(xml, tag).next().map(|t| {
+        t.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&#34;", "\"")
+            .replace("&amp;", "&")
+    })
+}
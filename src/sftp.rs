@@ -0,0 +1,543 @@
+//! Destinations on a server reachable over SSH.
+//!
+//! `backup.destination = "sftp://user@host[:port]/path"` stores the
+//! destination below the absolute `path` on `host`; `/~/path` is relative to
+//! the user's home directory. Authentication uses keys only: the ssh-agent
+//! first, then `backup.sftp.identity_file` or the default keys in `~/.ssh`.
+//! The server's host key must be listed in `known_hosts`.
+//!
+//! One SSH session is opened on first use and shared by every operation of a
+//! run. A session that breaks is dropped and the next operation reconnects.
+//! Renames onto an existing file move that file aside first (see
+//! [`ASIDE_SUFFIX`]), because SFTP version 3 servers do not overwrite.
+//!
+//! The requests go through [`SftpSession`], so the storage can also run on
+//! a session other than an SSH login, such as an in-memory test server.
+
+use crate::error::{Error, Result};
+use crate::storage::{self, ObjectInfo, StorageBackend, StorageLock, STALE_LOCK};
+use log::{debug, warn};
+use serde::Deserialize;
+use ssh2::{CheckResult, ErrorCode, FileStat, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp};
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Timeout for connecting and for each blocking SSH operation.
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// Suffix of a file moved out of the way while another is renamed onto it.
+pub const ASIDE_SUFFIX: &str = ".rbold";
+/// SFTP status codes for a generic failure and an existing target.
+const FX_FAILURE: i32 = 4;
+const FX_FILE_ALREADY_EXISTS: i32 = 11;
+
+/// Settings from `[backup.sftp]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SftpOptions {
+    /// Private key to log in with if the ssh-agent has none that is accepted
+    pub identity_file: Option<PathBuf>,
+    /// File with the accepted host keys, `~/.ssh/known_hosts` if unset
+    pub known_hosts: Option<PathBuf>,
+}
+
+/// `user@host:port/path` of an `sftp://` destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SftpTarget {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    /// Directory on the server, relative to the home directory if not absolute
+    pub path: PathBuf,
+}
+
+impl SftpTarget {
+    /// Parse `sftp://[user@]host[:port]/path`. The user defaults to `$USER`.
+    pub fn parse(destination: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidArgument(format!("invalid SFTP destination '{}': {}", destination, reason));
+        let rest = destination
+            .strip_prefix("sftp://")
+            .ok_or_else(|| invalid("expected sftp://user@host/path"))?;
+        let (authority, path) = rest.split_once('/').ok_or_else(|| invalid("the path is missing"))?;
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (user.to_string(), host_port),
+            None => (std::env::var("USER").map_err(|_| invalid("no user given and $USER is not set"))?, authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':').filter(|(_, p)| !p.contains(']')) {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid("the port is not a number"))?),
+            None => (host_port, 22),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if user.is_empty() || host.is_empty() {
+            return Err(invalid("the user or host is empty"));
+        }
+        let path = path.trim_end_matches('/');
+        let path = match path.strip_prefix('~') {
+            Some(home) => PathBuf::from(home.trim_start_matches('/')),
+            None => PathBuf::from(format!("/{}", path)),
+        };
+        Ok(Self {
+            user,
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+/// The SFTP requests [`SftpStorage`] sends over one session. Errors carry
+/// the SFTP status code, or a session error code if the connection broke.
+pub trait SftpSession: Send + Sync {
+    fn stat(&self, path: &Path) -> std::result::Result<FileStat, ssh2::Error>;
+    /// Entries of `dir` with their full paths.
+    fn readdir(&self, dir: &Path) -> std::result::Result<Vec<(PathBuf, FileStat)>, ssh2::Error>;
+    fn mkdir(&self, dir: &Path) -> std::result::Result<(), ssh2::Error>;
+    /// Open `path` for writing, truncated, or failing if it exists with
+    /// `exclusive` set.
+    fn create(&self, path: &Path, exclusive: bool) -> std::result::Result<Box<dyn Write + Send>, ssh2::Error>;
+    fn open(&self, path: &Path) -> std::result::Result<Box<dyn Read + Send>, ssh2::Error>;
+    /// Rename `from` to `to`; fails if `to` exists, as in SFTP version 3.
+    fn rename(&self, from: &Path, to: &Path) -> std::result::Result<(), ssh2::Error>;
+    fn unlink(&self, path: &Path) -> std::result::Result<(), ssh2::Error>;
+    fn setstat(&self, path: &Path, stat: FileStat) -> std::result::Result<(), ssh2::Error>;
+}
+
+impl SftpSession for Sftp {
+    fn stat(&self, path: &Path) -> std::result::Result<FileStat, ssh2::Error> {
+        Sftp::stat(self, path)
+    }
+
+    fn readdir(&self, dir: &Path) -> std::result::Result<Vec<(PathBuf, FileStat)>, ssh2::Error> {
+        Sftp::readdir(self, dir)
+    }
+
+    fn mkdir(&self, dir: &Path) -> std::result::Result<(), ssh2::Error> {
+        Sftp::mkdir(self, dir, 0o755)
+    }
+
+    fn create(&self, path: &Path, exclusive: bool) -> std::result::Result<Box<dyn Write + Send>, ssh2::Error> {
+        let last = if exclusive { OpenFlags::EXCLUSIVE } else { OpenFlags::TRUNCATE };
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | last;
+        Ok(Box::new(self.open_mode(path, flags, 0o644, OpenType::File)?))
+    }
+
+    fn open(&self, path: &Path) -> std::result::Result<Box<dyn Read + Send>, ssh2::Error> {
+        Ok(Box::new(Sftp::open(self, path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::result::Result<(), ssh2::Error> {
+        Sftp::rename(self, from, to, None)
+    }
+
+    fn unlink(&self, path: &Path) -> std::result::Result<(), ssh2::Error> {
+        Sftp::unlink(self, path)
+    }
+
+    fn setstat(&self, path: &Path, stat: FileStat) -> std::result::Result<(), ssh2::Error> {
+        Sftp::setstat(self, path, stat)
+    }
+}
+
+/// Opens the session of a connection, again after one broke.
+type Opener = Box<dyn Fn() -> io::Result<Arc<dyn SftpSession>> + Send + Sync>;
+
+/// A destination directory on an SFTP server.
+pub struct SftpStorage {
+    location: PathBuf,
+    connection: Arc<Connection>,
+}
+
+/// The SSH session shared by the operations of a storage and its lock.
+struct Connection {
+    target: SftpTarget,
+    open: Opener,
+    sftp: Mutex<Option<Arc<dyn SftpSession>>>,
+    /// Directories known to exist on the server
+    dirs: Mutex<HashSet<PathBuf>>,
+}
+
+impl std::fmt::Debug for SftpStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SftpStorage").field("location", &self.location).finish_non_exhaustive()
+    }
+}
+
+fn home() -> PathBuf {
+    std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default()
+}
+
+impl SftpStorage {
+    /// Storage for `destination`, an `sftp://` URL. Nothing is sent to the
+    /// server before the first operation or [`connect`](Self::connect).
+    pub fn new(destination: &str, options: &SftpOptions) -> Result<Self> {
+        let target = SftpTarget::parse(destination)?;
+        let options = options.clone();
+        Self::with_session(destination, move || {
+            let sftp: Arc<dyn SftpSession> = Arc::new(open_session(&target, &options)?);
+            Ok(sftp)
+        })
+    }
+
+    /// Storage for `destination` on the sessions `open` returns instead of
+    /// an SSH login. `open` is called on first use and after a session broke.
+    pub fn with_session(
+        destination: &str,
+        open: impl Fn() -> io::Result<Arc<dyn SftpSession>> + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let target = SftpTarget::parse(destination)?;
+        Ok(Self {
+            location: PathBuf::from(destination.trim_end_matches('/')),
+            connection: Arc::new(Connection {
+                target,
+                open: Box::new(open),
+                sftp: Mutex::new(None),
+                dirs: Mutex::new(HashSet::new()),
+            }),
+        })
+    }
+
+    /// Log in and create the destination directory, failing with
+    /// [`Error::DestinationUnavailable`] if either is not possible.
+    pub fn connect(&self) -> Result<()> {
+        let unavailable = |source| Error::DestinationUnavailable { path: self.location.clone(), source };
+        self.connection.sftp().map_err(unavailable)?;
+        self.connection.create_dirs(&self.connection.target.path).map_err(unavailable)
+    }
+}
+
+impl Connection {
+    /// The shared SFTP channel, logging in first if there is none.
+    fn sftp(&self) -> io::Result<Arc<dyn SftpSession>> {
+        let mut connection = self.sftp.lock().unwrap();
+        if let Some(sftp) = connection.as_ref() {
+            return Ok(Arc::clone(sftp));
+        }
+        let sftp = (self.open)()?;
+        *connection = Some(Arc::clone(&sftp));
+        Ok(sftp)
+    }
+
+    /// Run `op` on the shared channel. A broken session is dropped so the
+    /// next operation reconnects.
+    fn run<T>(&self, op: impl FnOnce(&dyn SftpSession) -> std::result::Result<T, ssh2::Error>) -> io::Result<T> {
+        let sftp = self.sftp()?;
+        op(&*sftp).map_err(|e| {
+            if matches!(e.code(), ErrorCode::Session(_)) {
+                warn!("SFTP session to {} failed, reconnecting on the next operation: {}", self.target.host, e);
+                self.sftp.lock().unwrap().take();
+                self.dirs.lock().unwrap().clear();
+            }
+            e.into()
+        })
+    }
+
+    fn path(&self, key: &Path) -> PathBuf {
+        self.target.path.join(key)
+    }
+
+    /// Create `dir` and its missing parents.
+    fn create_dirs(&self, dir: &Path) -> io::Result<()> {
+        let mut missing = Vec::new();
+        let dirs = self.dirs.lock().unwrap().clone();
+        for ancestor in dir.ancestors().filter(|a| !a.as_os_str().is_empty() && *a != Path::new("/")) {
+            if dirs.contains(ancestor) {
+                break;
+            }
+            missing.push(ancestor.to_path_buf());
+        }
+        for dir in missing.into_iter().rev() {
+            self.run(|sftp| match sftp.mkdir(&dir) {
+                Ok(()) => Ok(()),
+                // Created by an earlier run
+                Err(e) => match sftp.stat(&dir) {
+                    Ok(stat) if stat.is_dir() => Ok(()),
+                    _ => Err(e),
+                },
+            })?;
+            self.dirs.lock().unwrap().insert(dir);
+        }
+        Ok(())
+    }
+
+    /// Put back the file a [`rename`](StorageBackend::rename) moved aside if
+    /// it was interrupted before the new file took its place. Returns whether
+    /// there was one.
+    fn restore_aside(&self, path: &Path) -> io::Result<bool> {
+        let aside = with_suffix(path, ASIDE_SUFFIX);
+        self.run(|sftp| match sftp.stat(&aside) {
+            Ok(_) => {
+                warn!("Restoring {} from an interrupted rename", path.display());
+                sftp.rename(&aside, path).map(|_| true)
+            }
+            Err(_) => Ok(false),
+        })
+    }
+
+    fn create_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) => self.create_dirs(parent),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Log in to `target` and open its SFTP channel.
+fn open_session(target: &SftpTarget, options: &SftpOptions) -> io::Result<Sftp> {
+    let SftpTarget { user, host, port, .. } = target;
+    debug!("Connecting to {}@{}:{}", user, host, port);
+    let addr = (host.as_str(), *port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host)))?;
+    let tcp = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(TIMEOUT.as_millis() as u32);
+    session.handshake()?;
+    verify_host_key(&session, target, options)?;
+
+    // Keys from the agent first, then the configured or default key files
+    if session.userauth_agent(user).is_err() {
+        let keys: Vec<PathBuf> = match &options.identity_file {
+            Some(key) => vec![key.clone()],
+            None => ["id_ed25519", "id_ecdsa", "id_rsa"]
+                .iter()
+                .map(|name| home().join(".ssh").join(name))
+                .filter(|key| key.exists())
+                .collect(),
+        };
+        for key in keys {
+            match session.userauth_pubkey_file(user, None, &key, None) {
+                Ok(()) => break,
+                Err(e) => debug!("Key {} was not accepted: {}", key.display(), e),
+            }
+        }
+    }
+    if !session.authenticated() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("no key was accepted for {}@{}", user, host),
+        ));
+    }
+    Ok(session.sftp()?)
+}
+
+fn verify_host_key(session: &Session, target: &SftpTarget, options: &SftpOptions) -> io::Result<()> {
+    let SftpTarget { host, port, .. } = target;
+    let file = options
+        .known_hosts
+        .clone()
+        .unwrap_or_else(|| home().join(".ssh").join("known_hosts"));
+    let mut known = session.known_hosts()?;
+    if file.exists() {
+        known.read_file(&file, KnownHostFileKind::OpenSSH)?;
+    }
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| io::Error::other("the server sent no host key"))?;
+    let refused = |reason: &str| {
+        io::Error::new(io::ErrorKind::PermissionDenied, format!("host key of {} {} {}", host, reason, file.display()))
+    };
+    match known.check_port(host, *port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => Err(refused("is not listed in")),
+        CheckResult::Mismatch => Err(refused("does not match the key in")),
+        CheckResult::Failure => Err(refused("could not be checked against")),
+    }
+}
+
+fn modified(stat: &FileStat) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(stat.mtime.unwrap_or(0))
+}
+
+impl StorageBackend for SftpStorage {
+    fn location(&self) -> &Path {
+        &self.location
+    }
+
+    fn put(&self, key: &Path, data: &mut dyn Read) -> io::Result<u64> {
+        let path = self.connection.path(key);
+        self.connection.create_parent(&path)?;
+        let mut file = self.connection.run(|sftp| sftp.create(&path, false))?;
+        let written = io::copy(data, &mut file)?;
+        file.flush()?;
+        Ok(written)
+    }
+
+    fn get(&self, key: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let path = self.connection.path(key);
+        let file = match self.connection.run(|sftp| sftp.open(&path)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.connection.restore_aside(&path)? => {
+                self.connection.run(|sftp| sftp.open(&path))?
+            }
+            file => file?,
+        };
+        Ok(Box::new(file))
+    }
+
+    fn stat(&self, key: &Path) -> io::Result<Option<ObjectInfo>> {
+        let path = self.connection.path(key);
+        let stat = match self.connection.run(|sftp| sftp.stat(&path)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.connection.restore_aside(&path)? => {
+                self.connection.run(|sftp| sftp.stat(&path))
+            }
+            stat => stat,
+        };
+        match stat {
+            Ok(stat) if stat.is_file() => Ok(Some(ObjectInfo {
+                key: key.to_path_buf(),
+                size: stat.size.unwrap_or(0),
+                modified: modified(&stat),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+        let mut pending = vec![self.connection.path(prefix)];
+        while let Some(dir) = pending.pop() {
+            let entries = match self.connection.run(|sftp| sftp.readdir(&dir)) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for (path, stat) in entries {
+                if stat.is_dir() {
+                    pending.push(path);
+                } else if stat.is_file() && !path.to_string_lossy().ends_with(ASIDE_SUFFIX) {
+                    objects.push(ObjectInfo {
+                        key: path.strip_prefix(&self.connection.target.path).expect("listed below root").to_path_buf(),
+                        size: stat.size.unwrap_or(0),
+                        modified: modified(&stat),
+                    });
+                }
+            }
+        }
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (self.connection.path(from), self.connection.path(to));
+        self.connection.create_parent(&to)?;
+        self.connection.run(|sftp| match sftp.rename(&from, &to) {
+            Ok(()) => Ok(()),
+            // SFTP version 3 servers such as OpenSSH refuse to rename onto an
+            // existing file. The target is moved aside rather than removed,
+            // so it is never lost and is put back if the rename still fails.
+            Err(e) if matches!(e.code(), ErrorCode::SFTP(FX_FAILURE | FX_FILE_ALREADY_EXISTS)) => {
+                let aside = with_suffix(&to, ASIDE_SUFFIX);
+                // Left over from an earlier attempt that was interrupted
+                if sftp.stat(&aside).is_ok() {
+                    sftp.unlink(&aside)?;
+                }
+                if sftp.rename(&to, &aside).is_err() {
+                    return Err(e);
+                }
+                match sftp.rename(&from, &to) {
+                    Ok(()) => {
+                        if let Err(e) = sftp.unlink(&aside) {
+                            debug!("Cannot remove {}: {e}", aside.display());
+                        }
+                        Ok(())
+                    }
+                    Err(e) => {
+                        sftp.rename(&aside, &to).ok();
+                        Err(e)
+                    }
+                }
+            }
+            Err(e) => Err(e),
+        })
+    }
+
+    fn delete(&self, key: &Path) -> io::Result<()> {
+        let path = self.connection.path(key);
+        self.connection.run(|sftp| sftp.unlink(&path))
+    }
+
+    fn lock(&self, key: &Path) -> io::Result<StorageLock> {
+        // Exclusive creation fails while another run holds the lock file
+        let path = self.connection.path(key);
+        self.connection.create_parent(&path)?;
+        for attempt in 0..2 {
+            match self.connection.run(|sftp| sftp.create(&path, true)) {
+                Ok(mut file) => {
+                    file.write_all(storage::lock_contents().as_bytes())?;
+                    return Ok(StorageLock::new(SftpLock {
+                        connection: Arc::clone(&self.connection),
+                        path,
+                    }));
+                }
+                Err(e) if attempt == 0 => {
+                    let Ok(stat) = self.connection.run(|sftp| sftp.stat(&path)) else {
+                        return Err(e);
+                    };
+                    let mut contents = String::new();
+                    let read = self
+                        .connection
+                        .run(|sftp| sftp.open(&path))
+                        .and_then(|mut file| file.read_to_string(&mut contents));
+                    let stale = match read.ok().and_then(|_| storage::lock_is_stale(&contents)) {
+                        Some(stale) => stale,
+                        // Unreadable contents fall back to the file's age
+                        None => modified(&stat).elapsed().is_ok_and(|age| age > STALE_LOCK),
+                    };
+                    if !stale {
+                        break;
+                    }
+                    warn!("Removing the stale lock {} of a run that did not finish", path.display());
+                    self.connection.run(|sftp| sftp.unlink(&path))?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::WouldBlock))
+    }
+
+    fn set_modified(&self, key: &Path, modified: SystemTime) -> io::Result<()> {
+        let path = self.connection.path(key);
+        let secs = modified.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        // The server only takes the times when both are given
+        let stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: Some(secs),
+            mtime: Some(secs),
+        };
+        self.connection.run(|sftp| sftp.setstat(&path, stat))
+    }
+
+    fn keeps_modified(&self) -> bool {
+        true
+    }
+}
+
+struct SftpLock {
+    connection: Arc<Connection>,
+    path: PathBuf,
+}
+
+impl Drop for SftpLock {
+    fn drop(&mut self) {
+        // A session that broke during the run is replaced for the second try
+        let release = || self.connection.run(|sftp| sftp.unlink(&self.path));
+        if let Err(e) = release().or_else(|_| release()) {
+            warn!("Failed to release the lock {}: {e}", self.path.display());
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
@@ -1,18 +1,51 @@
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 use crate::backup;
-use crate::config::Config;
+use crate::config::{Config, HistoryMode};
+use crate::copy::{CopyStats, Extents};
+use crate::error::{Error, IoResultExt, Result};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BackupState {
     pub latest: LatestBackup,
     /// Statistics for each completed backup run, newest first
     pub stats: Vec<BackupStats>,
+    /// State of each named job backing up to this destination, keyed by job name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub jobs: BTreeMap<String, BackupState>,
+    /// History mode of the last backup run, to detect a change of mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_mode: Option<HistoryMode>,
+    /// Source files with several names whose data is stored once
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<LinkGroup>,
+    /// Sparse files stored without their holes, by key, in destinations that
+    /// cannot hold holes
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sparse: BTreeMap<PathBuf, Extents>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The names of one multiply linked source inode in the mirror.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkGroup {
+    pub device: u64,
+    pub inode: u64,
+    /// Mirror keys of the names. The first one holds the data; in a local
+    /// destination the others are hard links to it, elsewhere they are not
+    /// stored at all and `restore` links them.
+    pub files: Vec<PathBuf>,
+}
+
+impl LinkGroup {
+    pub fn id(&self) -> (u64, u64) {
+        (self.device, self.inode)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LatestBackup {
     pub timestamp: DateTime<Local>,
     pub snapshot_id: String,
@@ -25,8 +58,18 @@ pub struct BackupStats {
     /// Unique identifier for this snapshot
     pub snapshot_id: String,
     pub files_synced: u64,
+    /// Data moved, without the holes of sparse files
     pub bytes_copied: u64,
+    /// Length of the copied files including holes
+    #[serde(default)]
+    pub logical_bytes: u64,
+    /// Bytes of modified files rebuilt from their earlier copy instead of sent
+    #[serde(default)]
+    pub bytes_saved: u64,
     pub duration_ms: u64,
+    /// Files copied with each copy method
+    #[serde(default)]
+    pub copy_methods: CopyStats,
 }
 
 impl Default for BackupStats {
@@ -36,7 +79,10 @@ impl Default for BackupStats {
             snapshot_id: String::new(),
             files_synced: 0,
             bytes_copied: 0,
+            logical_bytes: 0,
+            bytes_saved: 0,
             duration_ms: 0,
+            copy_methods: CopyStats::default(),
         }
     }
 }
@@ -50,47 +96,84 @@ impl Default for BackupState {
                 destination: PathBuf::new(),
             },
             stats: Vec::new(),
+            jobs: BTreeMap::new(),
+            history_mode: None,
+            links: Vec::new(),
+            sparse: BTreeMap::new(),
         }
     }
 }
 
 impl BackupState {
-    pub fn load(path: &Path) -> anyhow::Result<Self> {
-        let data = std::fs::read_to_string(path)?;
-        Ok(toml::from_str(&data)?)
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path).at(path)?;
+        toml::from_str(&data).map_err(|source| Error::StateCorrupt {
+            path: path.to_path_buf(),
+            source,
+        })
     }
 
-    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
-        let data = toml::to_string_pretty(self)?;
-        std::fs::write(path, data)?;
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let data = toml::to_string_pretty(self).map_err(|source| Error::Serialize {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        std::fs::write(path, data).at(path)?;
         Ok(())
     }
 
+    /// State of `job`, or this state itself for the unnamed job. `None` if the
+    /// job has not run against this destination yet.
+    pub fn namespace(&self, job: Option<&str>) -> Option<&BackupState> {
+        match job {
+            Some(name) => self.jobs.get(name),
+            None => Some(self),
+        }
+    }
+
+    /// Mutable state of `job`, created on first use.
+    pub fn namespace_mut(&mut self, job: Option<&str>) -> &mut BackupState {
+        match job {
+            Some(name) => self.jobs.entry(name.to_string()).or_default(),
+            None => self,
+        }
+    }
+
+    /// Extents of the object `key` if it holds a sparse file without its
+    /// holes, looked up in the state of every job.
+    pub fn extents(&self, key: &Path) -> Option<&Extents> {
+        self.sparse.get(key).or_else(|| self.jobs.values().find_map(|job| job.extents(key)))
+    }
+
     pub fn record_backup(&mut self, progress: &backup::TempBackup, config: &Config, removed: u64) {
         self.latest.timestamp = progress.timestamp;
         self.latest.snapshot_id = progress.snapshot_id.to_string();
         self.latest.destination = PathBuf::from(&config.backup.destination);
+        self.history_mode = Some(config.backup.history_mode());
+        self.links = progress.links.clone();
+        self.sparse = progress.sparse.clone();
 
         let entry = BackupStats {
             timestamp: progress.timestamp,
             snapshot_id: progress.snapshot_id.to_string(),
             files_synced: progress.completed.files.len() as u64 + removed,
             bytes_copied: progress.bytes_copied,
+            logical_bytes: progress.logical_bytes,
+            bytes_saved: progress.bytes_saved,
             duration_ms: progress.duration.as_millis() as u64,
+            copy_methods: progress.copy_methods.clone(),
         };
         self.stats.insert(0, entry);
     }
-
-
 }
 
 /// Load the backup state from `path`, or initialize it if it does not exist.
-pub fn load_or_init_state(path: &Path) -> anyhow::Result<BackupState> {
+pub fn load_or_init_state(path: &Path) -> Result<BackupState> {
     if path.exists() {
         BackupState::load(path)
     } else {
         if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
+            std::fs::create_dir_all(parent).at(parent)?;
         }
         let state = BackupState::default();
         state.save(path)?;
@@ -0,0 +1,450 @@
+//! Where backups are stored.
+//!
+//! The engine reaches the destination only through a [`StorageBackend`], so
+//! scan, backup and vacuum work the same for every kind of target. Objects are
+//! addressed by keys relative to the destination, such as `state.toml` or
+//! `docs/report.txt`; [`StorageBackend::location`] joined with a key gives the
+//! path shown in reports and logs.
+//!
+//! [`open`] picks the backend for `backup.destination`: an
+//! [`S3Storage`](crate::s3::S3Storage) for `s3://bucket/prefix`, an
+//! [`SftpStorage`](crate::sftp::SftpStorage) for `sftp://user@host/path`,
+//! otherwise a [`LocalStorage`] directory. [`LocalStorage`] is the only backend with a
+//! [`local_root`](StorageBackend::local_root). Features that need a real file
+//! system below the destination, like reflinks, hard-linked snapshots and
+//! delta History, are only available there. [`MemoryStorage`] keeps
+//! everything in memory and is meant for tests.
+
+use crate::config::BackupOptions;
+use crate::error::{Error, Result};
+use crate::s3::S3Storage;
+use crate::sftp::SftpStorage;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+/// A stored object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectInfo {
+    /// Key relative to the destination
+    pub key: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Exclusive lock on a destination, released when dropped.
+pub struct StorageLock(#[allow(dead_code)] Box<dyn Send>);
+
+impl StorageLock {
+    pub fn new(guard: impl Send + 'static) -> Self {
+        Self(Box::new(guard))
+    }
+}
+
+impl std::fmt::Debug for StorageLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StorageLock")
+    }
+}
+
+/// Operations the engine needs from a backup destination.
+///
+/// Missing objects are reported as [`io::ErrorKind::NotFound`], except by
+/// [`stat`](Self::stat) and [`list`](Self::list), which return `None` and an
+/// empty list, and by [`delete`](Self::delete) on object stores, where
+/// deleting a missing object succeeds.
+pub trait StorageBackend: Send + Sync {
+    /// The destination as shown to the user; keys are reported joined to it.
+    fn location(&self) -> &Path;
+
+    /// Store everything read from `data` as `key`, replacing an existing
+    /// object. Returns the number of bytes written.
+    fn put(&self, key: &Path, data: &mut dyn Read) -> io::Result<u64>;
+
+    /// Open the object `key` for reading.
+    fn get(&self, key: &Path) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Size and modification time of `key`, `None` if it does not exist.
+    fn stat(&self, key: &Path) -> io::Result<Option<ObjectInfo>>;
+
+    /// All objects below `prefix`, recursively, sorted by key.
+    fn list(&self, prefix: &Path) -> io::Result<Vec<ObjectInfo>>;
+
+    /// Move `from` to `to`, replacing an existing object.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Delete the object `key`.
+    fn delete(&self, key: &Path) -> io::Result<()>;
+
+    /// Take the lock named `key`, failing with [`io::ErrorKind::WouldBlock`]
+    /// while another run holds it.
+    fn lock(&self, key: &Path) -> io::Result<StorageLock>;
+
+    /// Set the modification time of the object `key`, failing with
+    /// [`io::ErrorKind::Unsupported`] on backends that set it to the upload
+    /// time.
+    fn set_modified(&self, _key: &Path, _modified: SystemTime) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Whether [`set_modified`](Self::set_modified) is supported, so the
+    /// modification time of a stored copy is the one of its source.
+    fn keeps_modified(&self) -> bool {
+        false
+    }
+
+    /// Whether [`put`](Self::put) leaves an existing `key` untouched until all
+    /// data was stored, so copies are written to their final key instead of
+    /// a `.part` key that is renamed afterwards.
+    fn atomic_put(&self) -> bool {
+        false
+    }
+
+    /// Discard unfinished uploads that runs started more than `older_than`
+    /// ago and never completed. Returns their keys.
+    fn discard_stale_uploads(&self, _older_than: Duration) -> io::Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    /// Directory of the destination if it is on a local file system.
+    fn local_root(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Path shown for `key` in reports and errors.
+    fn display_path(&self, key: &Path) -> PathBuf {
+        self.location().join(key)
+    }
+}
+
+/// A lock on a remote destination left behind by a crashed run is taken over
+/// after this long.
+pub(crate) const STALE_LOCK: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Content of a remote lock object: the process holding it and since when.
+pub(crate) fn lock_contents() -> String {
+    format!("pid = {}\nstarted = \"{}\"\n", std::process::id(), chrono::Local::now().to_rfc3339())
+}
+
+/// Whether the lock with `contents` was taken more than [`STALE_LOCK`] ago,
+/// by the `started` time it records rather than the server's clock. `None` if
+/// the contents cannot be read.
+pub(crate) fn lock_is_stale(contents: &str) -> Option<bool> {
+    #[derive(serde::Deserialize)]
+    struct Held {
+        started: String,
+    }
+    let held: Held = toml::from_str(contents).ok()?;
+    let started = chrono::DateTime::parse_from_rfc3339(&held.started).ok()?;
+    let age = chrono::Local::now().signed_duration_since(started).to_std().unwrap_or_default();
+    Some(age > STALE_LOCK)
+}
+
+/// Whether `destination` is a URL of a remote storage rather than a local directory.
+pub fn is_remote(destination: &str) -> bool {
+    destination.contains("://")
+}
+
+/// The backend for `options.destination`. A local directory is created and
+/// canonicalized and a bucket or server is checked to be reachable if
+/// `create` is set.
+pub fn open(options: &BackupOptions, create: bool) -> Result<Arc<dyn StorageBackend>> {
+    let dest = &options.destination;
+    if dest.starts_with("s3://") {
+        let storage = S3Storage::new(dest, &options.s3.clone().unwrap_or_default())?;
+        if create {
+            storage.check()?;
+        }
+        return Ok(Arc::new(storage));
+    }
+    if dest.starts_with("sftp://") {
+        let storage = SftpStorage::new(dest, &options.sftp.clone().unwrap_or_default())?;
+        if create {
+            storage.connect()?;
+        }
+        return Ok(Arc::new(storage));
+    }
+    Ok(if create {
+        Arc::new(LocalStorage::create(dest)?)
+    } else {
+        Arc::new(LocalStorage::new(dest))
+    })
+}
+
+/// Read the TOML document stored as `key`, `None` if there is none.
+pub(crate) fn read_toml<T: serde::de::DeserializeOwned>(storage: &dyn StorageBackend, key: &Path) -> Result<Option<T>> {
+    let path = storage.display_path(key);
+    let mut data = String::new();
+    match storage.get(key) {
+        Ok(mut reader) => reader.read_to_string(&mut data).map_err(|e| Error::io(&path, e))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::io(&path, e)),
+    };
+    toml::from_str(&data)
+        .map(Some)
+        .map_err(|source| Error::StateCorrupt { path, source })
+}
+
+/// Store `value` as the TOML document `key`.
+pub(crate) fn write_toml<T: serde::Serialize>(storage: &dyn StorageBackend, key: &Path, value: &T) -> Result<()> {
+    let path = storage.display_path(key);
+    let data = toml::to_string_pretty(value).map_err(|source| Error::Serialize {
+        path: path.clone(),
+        source,
+    })?;
+    storage.put(key, &mut data.as_bytes()).map_err(|e| Error::io(&path, e))?;
+    Ok(())
+}
+
+/// A destination directory on a local or mounted file system.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    /// Use `root` as it is, without checking that it exists.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Create `root` if needed and use its canonical path.
+    pub fn create(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        if !root.exists() {
+            fs::create_dir_all(&root)
+                .map_err(|source| Error::DestinationUnavailable { path: root.clone(), source })?;
+        }
+        let canonical = root
+            .canonicalize()
+            .map_err(|source| Error::DestinationUnavailable { path: root.clone(), source })?;
+        if !canonical.is_dir() {
+            return Err(Error::DestinationNotDirectory { path: canonical });
+        }
+        Ok(Self { root: canonical })
+    }
+
+    fn path(&self, key: &Path) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+impl StorageBackend for LocalStorage {
+    fn location(&self) -> &Path {
+        &self.root
+    }
+
+    fn put(&self, key: &Path, data: &mut dyn Read) -> io::Result<u64> {
+        let path = self.path(key);
+        create_parent(&path)?;
+        let mut file = File::create(&path)?;
+        io::copy(data, &mut file)
+    }
+
+    fn get(&self, key: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(self.path(key))?))
+    }
+
+    fn stat(&self, key: &Path) -> io::Result<Option<ObjectInfo>> {
+        match fs::metadata(self.path(key)) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(ObjectInfo {
+                key: key.to_path_buf(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<ObjectInfo>> {
+        let dir = self.path(prefix);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut objects = Vec::new();
+        for entry in WalkDir::new(&dir).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            objects.push(ObjectInfo {
+                key: entry.path().strip_prefix(&self.root).expect("walked below root").to_path_buf(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+        Ok(objects)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let to = self.path(to);
+        create_parent(&to)?;
+        fs::rename(self.path(from), to)
+    }
+
+    fn delete(&self, key: &Path) -> io::Result<()> {
+        fs::remove_file(self.path(key))
+    }
+
+    fn lock(&self, key: &Path) -> io::Result<StorageLock> {
+        // The OS drops the lock with the process, so a crashed run never
+        // leaves a stale lock behind
+        let path = self.path(key);
+        create_parent(&path)?;
+        let file = File::options().create(true).truncate(false).write(true).open(&path)?;
+        file.try_lock().map_err(|e| match e {
+            fs::TryLockError::WouldBlock => io::Error::from(io::ErrorKind::WouldBlock),
+            fs::TryLockError::Error(e) => e,
+        })?;
+        Ok(StorageLock::new(file))
+    }
+
+    fn set_modified(&self, key: &Path, modified: SystemTime) -> io::Result<()> {
+        File::options().write(true).open(self.path(key))?.set_modified(modified)
+    }
+
+    fn keeps_modified(&self) -> bool {
+        true
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+}
+
+/// Content and modification time of each object, by key.
+type Objects = BTreeMap<PathBuf, (Vec<u8>, SystemTime)>;
+
+/// A destination that only lives in memory, for tests and dry runs.
+///
+/// Clones share the same objects, so a test can keep one to inspect what a
+/// backup stored.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    location: PathBuf,
+    objects: Arc<Mutex<Objects>>,
+    locks: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl MemoryStorage {
+    /// An empty storage whose keys are reported below `location`.
+    pub fn new(location: impl Into<PathBuf>) -> Self {
+        Self {
+            location: location.into(),
+            objects: Arc::default(),
+            locks: Arc::default(),
+        }
+    }
+
+    /// Content of the object `key`.
+    pub fn read(&self, key: impl AsRef<Path>) -> Option<Vec<u8>> {
+        self.objects.lock().unwrap().get(key.as_ref()).map(|(data, _)| data.clone())
+    }
+
+    /// Keys of all stored objects, sorted.
+    pub fn keys(&self) -> Vec<PathBuf> {
+        self.objects.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+fn not_found(key: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no object {}", key.display()))
+}
+
+impl StorageBackend for MemoryStorage {
+    fn location(&self) -> &Path {
+        &self.location
+    }
+
+    fn put(&self, key: &Path, data: &mut dyn Read) -> io::Result<u64> {
+        let mut buf = Vec::new();
+        data.read_to_end(&mut buf)?;
+        let len = buf.len() as u64;
+        self.objects.lock().unwrap().insert(key.to_path_buf(), (buf, SystemTime::now()));
+        Ok(len)
+    }
+
+    fn get(&self, key: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let data = self.read(key).ok_or_else(|| not_found(key))?;
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    fn stat(&self, key: &Path) -> io::Result<Option<ObjectInfo>> {
+        Ok(self.objects.lock().unwrap().get(key).map(|(data, modified)| ObjectInfo {
+            key: key.to_path_buf(),
+            size: data.len() as u64,
+            modified: *modified,
+        }))
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<ObjectInfo>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (data, modified))| ObjectInfo {
+                key: key.clone(),
+                size: data.len() as u64,
+                modified: *modified,
+            })
+            .collect())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut objects = self.objects.lock().unwrap();
+        let object = objects.remove(from).ok_or_else(|| not_found(from))?;
+        objects.insert(to.to_path_buf(), object);
+        Ok(())
+    }
+
+    fn delete(&self, key: &Path) -> io::Result<()> {
+        self.objects.lock().unwrap().remove(key).map(|_| ()).ok_or_else(|| not_found(key))
+    }
+
+    fn lock(&self, key: &Path) -> io::Result<StorageLock> {
+        if !self.locks.lock().unwrap().insert(key.to_path_buf()) {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        Ok(StorageLock::new(MemoryLock {
+            locks: Arc::clone(&self.locks),
+            key: key.to_path_buf(),
+        }))
+    }
+
+    fn set_modified(&self, key: &Path, modified: SystemTime) -> io::Result<()> {
+        let mut objects = self.objects.lock().unwrap();
+        objects.get_mut(key).ok_or_else(|| not_found(key))?.1 = modified;
+        Ok(())
+    }
+
+    fn keeps_modified(&self) -> bool {
+        true
+    }
+}
+
+struct MemoryLock {
+    locks: Arc<Mutex<HashSet<PathBuf>>>,
+    key: PathBuf,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        self.locks.lock().unwrap().remove(&self.key);
+    }
+}
@@ -1,7 +1,43 @@
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use regex::Regex;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 /// Normalize path separators so `\` becomes `/`.
 pub fn normalize_path(path: &Path) -> PathBuf {
     PathBuf::from(path.to_string_lossy().replace('\\', "/"))
 }
 
+/// Split a `History` file name of the form `name_<timestamp>.ext` into the
+/// original file name (`name.ext`) and the timestamp it was archived at.
+///
+/// Versions stored as deltas (`name_<timestamp>.ext.rbdelta`) map to the same
+/// original name. Returns `None` if the name does not carry a history
+/// timestamp or belongs to an unfinished `.part` file.
+pub fn parse_history_name(file_name: &str) -> Option<(String, DateTime<Local>)> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        Regex::new(r"^(.*)_((?:\d{4}-\d{2}-\d{2}T\d{2}-\d{2}-\d{2}))(\..+)?$").unwrap()
+    });
+
+    if file_name.ends_with(".part") {
+        return None;
+    }
+    let file_name = file_name.strip_suffix(crate::history::DELTA_SUFFIX).unwrap_or(file_name);
+    let caps = re.captures(file_name)?;
+    let base = caps.get(1)?.as_str();
+    let ts_str = caps.get(2)?.as_str();
+    let ext = caps.get(3).map(|e| e.as_str()).unwrap_or("");
+
+    let naive = NaiveDateTime::parse_from_str(ts_str, "%Y-%m-%dT%H-%M-%S").ok()?;
+    let local_dt = Local.from_local_datetime(&naive).earliest()?;
+    Some((format!("{}{}", base, ext), local_dt))
+}
+
+/// Split a snapshot folder name of the form `<id>-<timestamp>` into the
+/// snapshot id and the time the snapshot was taken.
+pub fn parse_snapshot_name(name: &str) -> Option<(u64, DateTime<Local>)> {
+    let (id, ts_str) = name.split_once('-')?;
+    let naive = NaiveDateTime::parse_from_str(ts_str, "%Y-%m-%dT%H-%M-%S").ok()?;
+    Some((id.parse().ok()?, Local.from_local_datetime(&naive).earliest()?))
+}
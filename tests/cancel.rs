@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::tempdir;
+use walkdir::WalkDir;
+use rustybackup::{BackupEngine, BackupObserver, CancellationToken, Error, config::{Config, BackupPaths, BackupOptions}};
+
+/// Cancels the run when the second file starts copying.
+struct CancelOnSecondFile {
+    token: CancellationToken,
+    started: AtomicUsize,
+}
+
+impl BackupObserver for CancelOnSecondFile {
+    fn file_started(&self, _path: &Path) {
+        if self.started.fetch_add(1, Ordering::SeqCst) == 1 {
+            self.token.cancel();
+        }
+    }
+}
+
+#[test]
+fn cancelled_backup_saves_progress_and_resumes() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        fs::write(src.join(name), name.as_bytes()).unwrap();
+    }
+    let dest = tmp.path().join("dest");
+    let config = Config {
+        paths: BackupPaths {
+            include: vec![src.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: dest.to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let token = CancellationToken::new();
+    let observer = CancelOnSecondFile {
+        token: token.clone(),
+        started: AtomicUsize::new(0),
+    };
+    let err = BackupEngine::new(&config)
+        .with_observer(&observer)
+        .with_cancellation(token)
+        .backup()
+        .unwrap_err();
+    assert!(matches!(err, Error::Cancelled));
+
+    // Progress is kept and no partial copy is left behind
+    assert!(dest.join(".incomplete").exists());
+    let parts = WalkDir::new(&dest)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "part"))
+        .count();
+    assert_eq!(parts, 0);
+
+    let report = BackupEngine::new(&config).backup().unwrap();
+    assert!(report.resumed);
+    assert_eq!(report.files_copied, 2);
+    assert!(!dest.join(".incomplete").exists());
+
+    let label = src.to_string_lossy().replace(':', "").replace(['\\', '/'], "-");
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        assert_eq!(fs::read(dest.join(&label).join(name)).unwrap(), name.as_bytes());
+    }
+}
+
+#[test]
+fn cancelled_token_stops_scan() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a.txt"), b"a").unwrap();
+    let config = Config {
+        paths: BackupPaths {
+            include: vec![src.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: tmp.path().join("dest").to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let token = CancellationToken::new();
+    token.cancel();
+    let err = BackupEngine::new(&config).with_cancellation(token).scan(false).unwrap_err();
+    assert!(matches!(err, Error::Cancelled));
+}
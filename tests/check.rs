@@ -0,0 +1,156 @@
+use std::fs;
+use std::process::Command;
+use tempfile::tempdir;
+use rustybackup::check::{check_file, Severity};
+use rustybackup::{Config, Error};
+
+fn binary() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_rustybackup"))
+}
+
+#[test]
+fn unknown_keys_are_rejected_with_suggestions() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    let path = tmp.path().join("config.toml");
+    fs::write(
+        &path,
+        format!(
+            "[paths]\ninclude = [{{ path = {:?}, lable = \"docs\" }}]\nexclude = []\n\n[backup]\ndestination = {:?}\nmax_version = 3\n\n[loging]\nfile = true\n",
+            src.to_string_lossy(),
+            tmp.path().join("dest").to_string_lossy()
+        ),
+    )
+    .unwrap();
+
+    let report = check_file(&path).unwrap();
+    let found: Vec<_> = report
+        .problems
+        .iter()
+        .filter(|p| p.severity == Severity::Error)
+        .map(|p| (p.key.as_deref().unwrap(), p.line.unwrap(), p.message.as_str()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("paths.include[0].lable", 2, "unknown key, did you mean `label`?"),
+            ("backup.max_version", 7, "unknown key, did you mean `max_versions`?"),
+            ("loging", 9, "unknown key, did you mean `logging`?"),
+        ]
+    );
+
+    match Config::load(&path).unwrap_err() {
+        Error::InvalidConfig { message, .. } => {
+            assert!(message.contains("line 7: backup.max_version: unknown key, did you mean `max_versions`?"), "{message}")
+        }
+        other => panic!("unexpected error: {other:?}"),
+    }
+}
+
+#[test]
+fn check_reports_paths_patterns_and_overlaps() {
+    let tmp = tempdir().unwrap();
+    let home = tmp.path().join("home");
+    fs::create_dir_all(home.join("projects")).unwrap();
+    let path = tmp.path().join("config.toml");
+    fs::write(
+        &path,
+        format!(
+            "[paths]\ninclude = [{home:?}, {projects:?}, {missing:?}]\nexclude = [\"[\"]\n\n[backup]\ndestination = {dest:?}\n",
+            home = home.to_string_lossy(),
+            projects = home.join("projects").to_string_lossy(),
+            missing = tmp.path().join("missing").to_string_lossy(),
+            dest = home.join("backups").to_string_lossy(),
+        ),
+    )
+    .unwrap();
+
+    let report = check_file(&path).unwrap();
+    let keys = |severity: Severity| -> Vec<&str> {
+        report
+            .problems
+            .iter()
+            .filter(|p| p.severity == severity)
+            .map(|p| p.key.as_deref().unwrap())
+            .collect()
+    };
+    assert_eq!(keys(Severity::Error), vec!["paths.include[2]", "paths.exclude[0]"]);
+    assert_eq!(
+        keys(Severity::Warning),
+        vec!["paths.include[1]", "backup.destination", "backup.destination"]
+    );
+
+    // Loading only rejects what is wrong with the file itself
+    assert!(matches!(Config::load(&path), Err(Error::InvalidConfig { .. })));
+
+    let output = binary().arg("--config").arg(&path).args(["config", "check"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("error: paths.exclude[0]: invalid glob"), "{stdout}");
+    assert!(stdout.contains("2 errors, 3 warnings"), "{stdout}");
+}
+
+#[test]
+fn type_errors_and_valid_configs() {
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("config.toml");
+    fs::write(&path, "[paths]\ninclude = []\nexclude = []\n\n[backup]\ndestination = \"d\"\nmax_versions = \"five\"\n").unwrap();
+    assert!(matches!(Config::load(&path), Err(Error::ConfigParse { .. })));
+    let report = check_file(&path).unwrap();
+    assert_eq!(report.errors(), 1);
+    assert_eq!(report.problems[0].line, Some(7));
+
+    fs::create_dir_all(tmp.path().join("dest")).unwrap();
+    fs::write(
+        &path,
+        format!("[paths]\ninclude = []\nexclude = []\n\n[backup]\ndestination = {:?}\n", tmp.path().join("dest").to_string_lossy()),
+    )
+    .unwrap();
+    let output = binary().arg("--config").arg(&path).args(["config", "check"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("config OK"));
+}
+
+#[test]
+fn semantic_errors_are_each_reported_at_their_key() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    let path = tmp.path().join("config.toml");
+    fs::write(
+        &path,
+        format!(
+            "[paths]\ninclude = [\n  {src:?},\n  {src:?},\n  {{ path = {other:?}, label = \"History\" }},\n]\nexclude = []\n\n[backup]\ndestination = {dest:?}\nkeep_snapshots = 0\ncopy_buffer_size = 0\n",
+            src = src.to_string_lossy(),
+            other = tmp.path().join("other").to_string_lossy(),
+            dest = tmp.path().join("dest").to_string_lossy(),
+        ),
+    )
+    .unwrap();
+
+    let report = check_file(&path).unwrap();
+    let found: Vec<_> = report
+        .problems
+        .iter()
+        .filter(|p| p.severity == Severity::Error && !p.message.contains("does not exist"))
+        .map(|p| (p.key.as_deref().unwrap(), p.line.unwrap()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("paths.include[1]", 4),
+            ("paths.include[2].label", 5),
+            ("backup.keep_snapshots", 11),
+            ("backup.copy_buffer_size", 12),
+        ]
+    );
+
+    match Config::load(&path).unwrap_err() {
+        Error::InvalidConfig { message, .. } => {
+            assert!(message.contains("line 4: paths.include[1]: include"), "{message}");
+            assert!(message.contains("line 12: backup.copy_buffer_size: copy_buffer_size must be at least 1 byte"), "{message}");
+        }
+        other => panic!("unexpected error: {other:?}"),
+    }
+}
@@ -13,13 +13,16 @@ fn removed_files_are_moved_to_history() {
 
     let config = Config {
         paths: BackupPaths {
-            include: vec![src.to_string_lossy().to_string()],
+            include: vec![src.to_string_lossy().to_string().into()],
             exclude: vec![],
+            ..Default::default()
         },
         backup: BackupOptions {
             destination: dest.to_string_lossy().to_string(),
             max_versions: Some(1),
+            ..Default::default()
         },
+        ..Default::default()
     };
 
     backup::run_backup(&config).unwrap();
@@ -28,7 +31,7 @@ fn removed_files_are_moved_to_history() {
 
     backup::run_backup(&config).unwrap();
 
-    let label = src.to_string_lossy().replace(':', "").replace('\\', "-").replace('/', "-");
+    let label = src.to_string_lossy().replace(':', "").replace(['\\', '/'], "-");
     let primary = dest.join(&label).join("file.txt");
     assert!(!primary.exists());
 
@@ -40,9 +40,7 @@ fn scan_subcommand_runs() {
         .expect("run binary");
     assert!(output.status.success());
     let stdout = String::from_utf8_lossy(&output.stdout);
-    let mut iter = stdout.lines();
-    iter.next(); // skip config dump
-    let mut lines: Vec<String> = iter.map(|s| s.to_string()).collect();
+    let mut lines: Vec<String> = stdout.lines().map(|s| s.to_string()).collect();
     let final_summary = lines.pop().unwrap_or_default();
     assert!(final_summary.starts_with("Scan complete"));
     lines.sort();
@@ -120,3 +118,92 @@ fn status_subcommand_runs() {
         .expect("run binary");
     assert!(output.status.success());
 }
+
+#[test]
+fn scan_json_output_is_a_single_document() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a.txt"), b"abc").unwrap();
+    let dest = tmp.path().join("dest");
+    let config_path = tmp.path().join("config.toml");
+    let content = format!(
+        "[paths]\ninclude=[\"{}\"]\nexclude=[]\n\n[backup]\ndestination=\"{}\"\n",
+        src.display(),
+        dest.display()
+    );
+    fs::write(&config_path, content).unwrap();
+
+    let output = binary()
+        .args(["--config", config_path.to_str().unwrap(), "--output", "json", "scan"])
+        .output()
+        .expect("run binary");
+    assert!(output.status.success());
+    let doc: serde_json::Value = serde_json::from_slice(&output.stdout).expect("valid json");
+    assert_eq!(doc["schema_version"], 1);
+    assert_eq!(doc["command"], "scan");
+    assert_eq!(doc["ok"], true);
+    assert_eq!(doc["result"]["total_files"], 1);
+    assert_eq!(doc["result"]["total_bytes"], 3);
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn backup_json_reports_per_file_results() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a.txt"), b"abc").unwrap();
+    let dest = tmp.path().join("dest");
+    let config_path = tmp.path().join("config.toml");
+    let content = format!(
+        "[paths]\ninclude=[\"{}\"]\nexclude=[]\n\n[backup]\ndestination=\"{}\"\n",
+        src.display(),
+        dest.display()
+    );
+    fs::write(&config_path, content).unwrap();
+
+    let output = binary()
+        .args(["--config", config_path.to_str().unwrap(), "backup", "--output", "json"])
+        .output()
+        .expect("run binary");
+    assert!(output.status.success());
+    let doc: serde_json::Value = serde_json::from_slice(&output.stdout).expect("valid json");
+    assert_eq!(doc["command"], "backup");
+    assert_eq!(doc["result"]["files_copied"], 1);
+    assert_eq!(doc["result"]["files"][0]["action"], "copied");
+    assert_eq!(doc["result"]["files"][0]["bytes"], 3);
+}
+
+#[test]
+fn json_errors_are_reported_as_documents() {
+    let tmp = tempdir().unwrap();
+    let output = binary()
+        .args(["--config", tmp.path().join("missing.toml").to_str().unwrap(), "--output", "json", "status"])
+        .output()
+        .expect("run binary");
+    assert!(!output.status.success());
+    let doc: serde_json::Value = serde_json::from_slice(&output.stdout).expect("valid json");
+    assert_eq!(doc["ok"], false);
+    assert!(doc["error"].is_string());
+}
+
+#[test]
+fn cat_writes_a_stored_version() {
+    let tmp = tempdir().unwrap();
+    let stored = tmp.path().join("notes_2024-01-02T03-04-05.txt");
+    fs::write(&stored, "old notes").unwrap();
+
+    // `cat` needs no config file
+    let output = binary()
+        .args(["--config", "missing.toml", "cat", stored.to_str().unwrap()])
+        .output()
+        .expect("run binary");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"old notes");
+
+    let broken = tmp.path().join("notes_2024-01-01T03-04-05.txt.rbdelta");
+    fs::write(&broken, "not a delta").unwrap();
+    let output = binary().args(["cat", broken.to_str().unwrap()]).output().expect("run binary");
+    assert_eq!(output.status.code(), Some(4));
+}
@@ -1,3 +1,6 @@
+// These structs only exist to exercise `Debug` output.
+#![allow(dead_code)]
+
 #[derive(Debug)]
 struct Config {
     paths: BackupPaths,
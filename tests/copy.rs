@@ -0,0 +1,286 @@
+use std::fs;
+use tempfile::tempdir;
+use rustybackup::config::{BackupOptions, BackupPaths, Config};
+use rustybackup::copy::{copy_file, CopyMethod, CopyOptions, CopyStrategy};
+use rustybackup::{BackupEngine, BackupState, CancellationToken};
+
+fn data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+#[test]
+fn every_strategy_copies_the_exact_data() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("big.bin");
+    let content = data(3 * 1024 * 1024 + 17);
+    fs::write(&src, &content).unwrap();
+
+    for strategy in [CopyStrategy::Auto, CopyStrategy::CopyFileRange, CopyStrategy::Buffered] {
+        if cfg!(not(target_os = "linux")) && strategy == CopyStrategy::CopyFileRange {
+            continue;
+        }
+        let dst = tmp.path().join(format!("{:?}.bin", strategy));
+        let options = CopyOptions { strategy, buffer_size: 64 * 1024 };
+        let copied = copy_file(&src, &dst, &options, &CancellationToken::new()).unwrap();
+        let method = copied.method;
+        assert_eq!(copied.bytes, content.len() as u64);
+        assert_eq!(copied.logical_bytes, content.len() as u64);
+        assert_eq!(fs::read(&dst).unwrap(), content);
+        match strategy {
+            CopyStrategy::CopyFileRange => assert_eq!(method, CopyMethod::CopyFileRange),
+            CopyStrategy::Buffered => assert_eq!(method, CopyMethod::Buffered),
+            _ => {}
+        }
+    }
+
+    // A cancelled copy stops with an error instead of finishing the file
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    let options = CopyOptions { strategy: CopyStrategy::Buffered, buffer_size: 4096 };
+    assert!(copy_file(&src, &tmp.path().join("cancelled.bin"), &options, &cancel).is_err());
+}
+
+#[test]
+fn forced_strategy_is_reported_in_stats() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a.txt"), "alpha").unwrap();
+    fs::write(src.join("b.txt"), data(100_000)).unwrap();
+
+    let config_path = tmp.path().join("config.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "[paths]\ninclude = [{:?}]\nexclude = []\n[backup]\ndestination = {:?}\ncopy_strategy = \"buffered\"\ncopy_buffer_size = \"8KiB\"\n",
+            src.to_string_lossy(),
+            tmp.path().join("dest").to_string_lossy()
+        ),
+    )
+    .unwrap();
+    let config = Config::load(&config_path).unwrap();
+    assert_eq!(config.backup.copy_options().buffer_size, 8 * 1024);
+
+    let report = BackupEngine::new(&config).backup().unwrap();
+    assert_eq!(report.copy_methods.buffered, 2);
+    assert_eq!(report.copy_methods.reflink + report.copy_methods.copy_file_range, 0);
+
+    let state = BackupState::load(&tmp.path().join("dest").join("state.toml")).unwrap();
+    assert_eq!(state.stats[0].copy_methods.buffered, 2);
+
+    // The default picks a faster method where one is available, and every
+    // copied file is counted once
+    let auto = Config {
+        paths: BackupPaths {
+            include: vec![src.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: tmp.path().join("auto").to_string_lossy().to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let stats = BackupEngine::new(&auto).backup().unwrap().copy_methods;
+    assert_eq!(stats.reflink + stats.copy_file_range + stats.buffered, 2);
+}
+
+#[test]
+fn sparse_files_stay_sparse() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    let image = src.join("disk.img");
+    let len = 64 * 1024 * 1024;
+    let mut file = fs::File::create(&image).unwrap();
+    file.set_len(len).unwrap();
+    for offset in [1024 * 1024, len - 4096] {
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[7u8; 4096]).unwrap();
+    }
+    drop(file);
+
+    #[cfg(unix)]
+    let allocated = |p: &std::path::Path| {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(p).unwrap().blocks() * 512
+    };
+    #[cfg(unix)]
+    let holes_supported = allocated(&image) < len;
+    #[cfg(not(unix))]
+    let holes_supported = false;
+
+    for (dest, strategy) in [("auto", "auto"), ("buffered", "buffered")] {
+        let dest = tmp.path().join(dest);
+        let config_path = tmp.path().join("config.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[paths]\ninclude = [{:?}]\nexclude = []\n[backup]\ndestination = {:?}\ncopy_strategy = \"{}\"\n",
+                src.to_string_lossy(),
+                dest.to_string_lossy(),
+                strategy
+            ),
+        )
+        .unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let report = BackupEngine::new(&config).backup().unwrap();
+
+        let folder = config.paths.include[0].proposed_folder();
+        let stored = dest.join(folder).join("disk.img");
+        assert_eq!(fs::read(&stored).unwrap(), fs::read(&image).unwrap());
+        assert_eq!(report.logical_bytes, len);
+        let state = BackupState::load(&dest.join("state.toml")).unwrap();
+        assert_eq!(state.stats[0].logical_bytes, len);
+
+        if holes_supported {
+            assert!(report.bytes_copied < len / 4, "copied {} bytes", report.bytes_copied);
+            #[cfg(unix)]
+            assert!(allocated(&stored) < len / 4);
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn hard_links_in_the_source_are_stored_once() {
+    use std::os::unix::fs::MetadataExt;
+    use std::time::{Duration, SystemTime};
+    use rustybackup::backup::FileAction;
+
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("a.txt"), "shared").unwrap();
+    fs::hard_link(src.join("a.txt"), src.join("sub").join("b.txt")).unwrap();
+    fs::write(src.join("c.txt"), "single").unwrap();
+
+    let config = Config {
+        paths: BackupPaths {
+            include: vec![src.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: tmp.path().join("dest").to_string_lossy().to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mirror = tmp.path().join("dest").join(config.paths.include[0].proposed_folder());
+    let inode = |p: &std::path::Path| fs::metadata(p).unwrap().ino();
+
+    let report = BackupEngine::new(&config).backup().unwrap();
+    assert_eq!(report.files_copied, 2);
+    assert_eq!(report.files_linked, 1);
+    assert_eq!(report.files.iter().filter(|f| f.action == FileAction::Linked).count(), 1);
+    assert_eq!(inode(&mirror.join("a.txt")), inode(&mirror.join("sub").join("b.txt")));
+    assert_ne!(inode(&mirror.join("a.txt")), inode(&mirror.join("c.txt")));
+    assert_eq!(fs::read_to_string(mirror.join("sub").join("b.txt")).unwrap(), "shared");
+
+    // Changing the data through one name updates the whole group
+    fs::write(src.join("a.txt"), "changed").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(src.join("a.txt"))
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+    let report = BackupEngine::new(&config).backup().unwrap();
+    assert_eq!((report.files_copied, report.files_linked), (1, 1));
+    assert_eq!(inode(&mirror.join("a.txt")), inode(&mirror.join("sub").join("b.txt")));
+    assert_eq!(fs::read_to_string(mirror.join("sub").join("b.txt")).unwrap(), "changed");
+
+    // A name added to the group later is linked to the copy of an earlier run
+    fs::File::options()
+        .write(true)
+        .open(src.join("a.txt"))
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(3600))
+        .unwrap();
+    fs::hard_link(src.join("a.txt"), src.join("d.txt")).unwrap();
+    let report = BackupEngine::new(&config).backup().unwrap();
+    assert_eq!((report.files_copied, report.files_linked), (0, 1));
+    assert_eq!(inode(&mirror.join("a.txt")), inode(&mirror.join("d.txt")));
+
+    let target = tmp.path().join("restored");
+    let report = BackupEngine::new(&config).restore(&target, None).unwrap();
+    assert_eq!((report.files_restored, report.files_linked), (2, 2));
+    let restored = target.join(config.paths.include[0].proposed_folder());
+    assert_eq!(inode(&restored.join("a.txt")), inode(&restored.join("sub").join("b.txt")));
+    assert_eq!(inode(&restored.join("a.txt")), inode(&restored.join("d.txt")));
+    assert_eq!(fs::read_to_string(restored.join("d.txt")).unwrap(), "changed");
+    assert_eq!(fs::read_to_string(restored.join("c.txt")).unwrap(), "single");
+}
+
+#[test]
+fn delta_transfer_sends_only_changed_blocks() {
+    use std::time::{Duration, SystemTime};
+
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    let db = src.join("app.sqlite");
+    let mut content: Vec<u8> = (0..2_000_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    fs::write(&db, &content).unwrap();
+
+    let modify = |content: &mut Vec<u8>, at: usize| {
+        content[at..at + 16].copy_from_slice(b"changed in place");
+        fs::write(&db, &*content).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&db)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+    };
+
+    for mode in ["versions", "none"] {
+        let dest = tmp.path().join(mode);
+        let signatures = tmp.path().join(format!("{mode}-signatures"));
+        let config_path = tmp.path().join("config.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[paths]\ninclude = [{:?}]\nexclude = []\n[backup]\ndestination = {:?}\nhistory_mode = \"{}\"\ndelta_transfer = true\ndelta_transfer_min_size = \"64KiB\"\nsignature_cache = {:?}\n",
+                src.to_string_lossy(),
+                dest.to_string_lossy(),
+                mode,
+                signatures.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        let config = Config::load(&config_path).unwrap();
+        // Without an earlier copy the file is sent in full
+        let first = BackupEngine::new(&config).backup().unwrap();
+        assert_eq!(first.copy_methods.delta, 0);
+        assert_eq!(first.bytes_copied, content.len() as u64);
+
+        modify(&mut content, 1_000_000);
+        let report = BackupEngine::new(&config).backup().unwrap();
+        assert_eq!(report.copy_methods.delta, 1);
+        assert!(report.bytes_copied < 100_000, "sent {} bytes", report.bytes_copied);
+        assert_eq!(report.bytes_saved + report.bytes_copied, content.len() as u64);
+        let folder = config.paths.include[0].proposed_folder();
+        assert_eq!(fs::read(dest.join(&folder).join("app.sqlite")).unwrap(), content);
+
+        let state = BackupState::load(&dest.join("state.toml")).unwrap();
+        assert_eq!(state.stats[0].bytes_saved, report.bytes_saved);
+        assert_eq!(state.stats[0].copy_methods.delta, 1);
+
+        // Without the kept signature the earlier copy is not read, the file
+        // is sent in full
+        fs::remove_dir_all(&signatures).unwrap();
+        modify(&mut content, 500_000);
+        let report = BackupEngine::new(&config).backup().unwrap();
+        assert_eq!(report.copy_methods.delta, 0);
+        assert_eq!((report.bytes_copied, report.bytes_saved), (content.len() as u64, 0));
+        assert_eq!(fs::read(dest.join(&folder).join("app.sqlite")).unwrap(), content);
+        modify(&mut content, 1_500_000);
+        assert_eq!(BackupEngine::new(&config).backup().unwrap().copy_methods.delta, 1);
+        assert_eq!(fs::read(dest.join(&folder).join("app.sqlite")).unwrap(), content);
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tempfile::tempdir;
+use rustybackup::backup::{FileAction, FileResult};
+use rustybackup::{BackupEngine, BackupObserver, BackupPlan, config::{Config, BackupPaths, BackupOptions}};
+
+#[derive(Default)]
+struct Recorder {
+    events: Mutex<Vec<String>>,
+}
+
+impl Recorder {
+    fn push(&self, event: String) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl BackupObserver for Recorder {
+    fn scan_started(&self, _root: &Path, total: u64) {
+        self.push(format!("scan_started {}", total));
+    }
+
+    fn backup_started(&self, plan: &BackupPlan) {
+        self.push(format!("backup_started {} {}", plan.changed.len(), plan.removed.len()));
+    }
+
+    fn file_started(&self, path: &Path) {
+        self.push(format!("file_started {}", path.file_name().unwrap().to_string_lossy()));
+    }
+
+    fn bytes_copied(&self, _path: &Path, bytes: u64) {
+        self.push(format!("bytes_copied {}", bytes));
+    }
+
+    fn history_moved(&self, from: &Path, _to: &Path) {
+        self.push(format!("history_moved {}", from.file_name().unwrap().to_string_lossy()));
+    }
+
+    fn file_finished(&self, result: &FileResult) {
+        self.push(format!("file_finished {:?}", result.action));
+    }
+}
+
+fn config_for(src: &Path, dest: &Path) -> Config {
+    Config {
+        paths: BackupPaths {
+            include: vec![src.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: dest.to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[test]
+fn engine_reports_events_to_observer() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a.txt"), b"hello").unwrap();
+    let dest = tmp.path().join("dest");
+    let config = config_for(&src, &dest);
+
+    let recorder = Recorder::default();
+    let report = BackupEngine::new(&config)
+        .with_observer(&recorder)
+        .backup()
+        .unwrap();
+    assert_eq!(report.files_copied, 1);
+    assert_eq!(report.bytes_copied, 5);
+    assert_eq!(report.files[0].action, FileAction::Copied);
+
+    let events = recorder.events.lock().unwrap().clone();
+    assert_eq!(
+        events,
+        vec![
+            "scan_started 1",
+            "backup_started 1 0",
+            "file_started a.txt",
+            "bytes_copied 5",
+            "file_finished Copied",
+        ]
+    );
+}
+
+#[test]
+fn engine_reports_removed_files_as_history_moves() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    let file = src.join("gone.txt");
+    fs::write(&file, b"bye").unwrap();
+    let dest = tmp.path().join("dest");
+    let config = config_for(&src, &dest);
+
+    let engine = BackupEngine::new(&config);
+    engine.backup().unwrap();
+    fs::remove_file(&file).unwrap();
+
+    let recorder = Recorder::default();
+    let report = BackupEngine::new(&config)
+        .with_observer(&recorder)
+        .backup()
+        .unwrap();
+    assert_eq!(report.files_removed, 1);
+
+    let events = recorder.events.lock().unwrap().clone();
+    assert!(events.contains(&"history_moved gone.txt".to_string()));
+    assert!(events.contains(&"file_finished Removed".to_string()));
+
+    let scan = engine.scan(true).unwrap();
+    assert!(scan.files.is_empty());
+    let found: Vec<PathBuf> = engine
+        .find(&Default::default())
+        .unwrap()
+        .into_iter()
+        .map(|v| v.source)
+        .collect();
+    assert_eq!(found, vec![file]);
+}
@@ -0,0 +1,94 @@
+use std::fs;
+use std::process::Command;
+use tempfile::tempdir;
+use rustybackup::{BackupEngine, Error, config::{Config, BackupPaths, BackupOptions}};
+
+fn binary() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_rustybackup"))
+}
+
+fn config_for(tmp: &std::path::Path) -> Config {
+    let src = tmp.join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a.txt"), b"a").unwrap();
+    Config {
+        paths: BackupPaths {
+            include: vec![src.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: tmp.join("dest").to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[test]
+fn corrupt_state_is_reported() {
+    let tmp = tempdir().unwrap();
+    let config = config_for(tmp.path());
+    let dest = tmp.path().join("dest");
+    fs::create_dir_all(&dest).unwrap();
+    fs::write(dest.join("state.toml"), "not [valid toml").unwrap();
+
+    let err = BackupEngine::new(&config).backup().unwrap_err();
+    match err {
+        Error::StateCorrupt { path, .. } => assert!(path.ends_with("state.toml")),
+        other => panic!("unexpected error: {other:?}"),
+    }
+}
+
+#[test]
+fn destination_file_is_not_a_directory() {
+    let tmp = tempdir().unwrap();
+    let config = config_for(tmp.path());
+    fs::write(tmp.path().join("dest"), b"").unwrap();
+
+    let err = BackupEngine::new(&config).backup().unwrap_err();
+    assert!(matches!(err, Error::DestinationNotDirectory { .. }));
+}
+
+#[test]
+fn invalid_exclude_pattern_is_reported() {
+    let tmp = tempdir().unwrap();
+    let mut config = config_for(tmp.path());
+    config.paths.exclude = vec!["[".into()];
+
+    let err = BackupEngine::new(&config).scan(false).unwrap_err();
+    assert!(matches!(err, Error::InvalidPattern { ref pattern, .. } if pattern == "["));
+}
+
+#[test]
+fn exit_codes_follow_error_kind() {
+    let tmp = tempdir().unwrap();
+
+    let missing = tmp.path().join("missing.toml");
+    let output = binary()
+        .args(["--config", missing.to_str().unwrap(), "scan"])
+        .output()
+        .expect("run binary");
+    assert_eq!(output.status.code(), Some(2));
+
+    let config = config_for(tmp.path());
+    let dest = tmp.path().join("dest");
+    fs::create_dir_all(&dest).unwrap();
+    fs::write(dest.join("state.toml"), "not [valid toml").unwrap();
+    let config_path = tmp.path().join("config.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "[paths]\ninclude=[{:?}]\nexclude=[]\n\n[backup]\ndestination={:?}\n",
+            config.paths.include[0].path, config.backup.destination
+        ),
+    )
+    .unwrap();
+    let output = binary()
+        .args(["--config", config_path.to_str().unwrap(), "backup"])
+        .output()
+        .expect("run binary");
+    assert_eq!(output.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is corrupt"));
+}
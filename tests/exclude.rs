@@ -71,3 +71,313 @@ fn test_nested_children_excluded() {
     assert!(stdout.contains("file.txt"));
     assert!(!stdout.contains("very_secret.txt"));
 }
+
+fn ignore_tree(root: &std::path::Path) {
+    for dir in ["build", "docs/private", "notes"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    for file in [
+        "main.rs",
+        "debug.log",
+        "keep.log",
+        "build/out.bin",
+        "docs/readme.md",
+        "docs/private/diary.txt",
+        "notes/todo.txt",
+        "notes/draft.tmp",
+    ] {
+        File::create(root.join(file)).unwrap();
+    }
+    fs::write(root.join("docs").join(".backupignore"), "private/\n").unwrap();
+    fs::write(root.join("notes").join(".gitignore"), "*.tmp\n").unwrap();
+}
+
+fn ignore_config(root: &std::path::Path, use_gitignore: bool) -> rustybackup::Config {
+    use rustybackup::config::{BackupOptions, BackupPaths, Config};
+    Config {
+        paths: BackupPaths {
+            include: vec![root.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ignore: vec!["/build/".into(), "*.log".into(), "!keep.log".into()],
+            use_gitignore,
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: root.parent().unwrap().join("dest").to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+fn scanned(config: &rustybackup::Config) -> Vec<String> {
+    let root = std::path::PathBuf::from(&config.paths.include[0].path);
+    let mut files: Vec<String> = rustybackup::BackupEngine::new(config)
+        .scan(false)
+        .unwrap()
+        .files
+        .iter()
+        .map(|f| f.path.strip_prefix(&root).unwrap().to_string_lossy().replace('\\', "/"))
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn gitignore_rules_and_ignore_files() {
+    let tmp = tempdir().expect("tempdir");
+    let root = tmp.path().join("inc");
+    ignore_tree(&root);
+
+    assert_eq!(
+        scanned(&ignore_config(&root, false)),
+        vec![
+            "docs/.backupignore",
+            "docs/readme.md",
+            "keep.log",
+            "main.rs",
+            "notes/.gitignore",
+            "notes/draft.tmp",
+            "notes/todo.txt",
+        ]
+    );
+
+    let with_gitignore = scanned(&ignore_config(&root, true));
+    assert!(!with_gitignore.contains(&"notes/draft.tmp".to_string()));
+    assert!(with_gitignore.contains(&"notes/todo.txt".to_string()));
+}
+
+#[test]
+fn explain_names_the_deciding_rule() {
+    let tmp = tempdir().expect("tempdir");
+    let root = tmp.path().join("inc");
+    ignore_tree(&root);
+    let engine_config = ignore_config(&root, false);
+    let engine = rustybackup::BackupEngine::new(&engine_config);
+
+    let diary = engine.explain(&root.join("docs/private/diary.txt")).unwrap();
+    assert!(diary.excluded);
+    let rule = diary.rule.unwrap();
+    assert_eq!(rule.pattern, "private/");
+    assert!(rule.source.ends_with(".backupignore"));
+
+    let keep = engine.explain(&root.join("keep.log")).unwrap();
+    assert!(!keep.excluded);
+    assert!(keep.rule.unwrap().negated);
+
+    let main = engine.explain(&root.join("main.rs")).unwrap();
+    assert!(!main.excluded && main.rule.is_none());
+
+    // The CLI reports the same decision
+    let config_content = format!(
+        "[paths]\ninclude=[{:?}]\nexclude=[]\nignore=[\"/build/\"]\n\n[backup]\ndestination={:?}\n",
+        root.to_string_lossy(),
+        tmp.path().join("dest").to_string_lossy()
+    );
+    let config_path = tmp.path().join("config.toml");
+    fs::write(&config_path, config_content).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rustybackup"))
+        .arg("--config")
+        .arg(&config_path)
+        .args(["scan", "--explain"])
+        .arg(root.join("build/out.bin"))
+        .output()
+        .expect("run binary");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("excluded by '/build/' from paths.ignore"), "{stdout}");
+
+    // Relative paths are resolved against the working directory
+    let output = Command::new(env!("CARGO_BIN_EXE_rustybackup"))
+        .current_dir(root.join("docs"))
+        .arg("--config")
+        .arg(&config_path)
+        .args(["scan", "--explain", "private/diary.txt"])
+        .output()
+        .expect("run binary");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("excluded by 'private/'"), "{stdout}");
+}
+
+#[test]
+fn size_age_and_marker_criteria() {
+    use rustybackup::config::{BackupOptions, BackupPaths, Config};
+    use std::time::{Duration, SystemTime};
+
+    let tmp = tempdir().expect("tempdir");
+    let root = tmp.path().join("inc");
+    for dir in ["cache", "fake-cache", "scratch"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    fs::write(root.join("small.txt"), b"hi").unwrap();
+    fs::write(root.join("normal.txt"), vec![b'x'; 100]).unwrap();
+    fs::write(root.join("huge.iso"), vec![0u8; 5000]).unwrap();
+    fs::write(root.join("ancient.txt"), vec![b'x'; 100]).unwrap();
+    File::options()
+        .write(true)
+        .open(root.join("ancient.txt"))
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(400 * 24 * 60 * 60))
+        .unwrap();
+    fs::write(
+        root.join("cache/CACHEDIR.TAG"),
+        "Signature: 8a477f597d28d172789f06886806bc55\n# a cache\n",
+    )
+    .unwrap();
+    fs::write(root.join("cache/blob"), vec![0u8; 300]).unwrap();
+    fs::write(root.join("fake-cache/CACHEDIR.TAG"), "not a cache tag\n").unwrap();
+    fs::write(root.join("fake-cache/data.txt"), vec![b'x'; 100]).unwrap();
+    File::create(root.join("scratch/.nobackup")).unwrap();
+    fs::write(root.join("scratch/tmp.bin"), vec![0u8; 200]).unwrap();
+
+    let paths: BackupPaths = toml::from_str(&format!(
+        "include = [{:?}]\nexclude = []\nmax_file_size = \"4K\"\nmin_file_size = 10\n\
+         skip_unmodified_days = 365\nexclude_caches = true\nmarker_files = [\".nobackup\"]\n",
+        root.to_string_lossy()
+    ))
+    .unwrap();
+    assert_eq!(paths.max_file_size, Some(4096));
+    let config = Config {
+        paths,
+        backup: BackupOptions {
+            destination: tmp.path().join("dest").to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    assert_eq!(
+        scanned(&config),
+        vec!["fake-cache/CACHEDIR.TAG", "fake-cache/data.txt", "normal.txt"]
+    );
+
+    let report = rustybackup::BackupEngine::new(&config).scan(false).unwrap();
+    assert_eq!(report.skipped_files, 5);
+    // 2 + 5000 + 100 bytes of files plus the 300 + 54 and 200 + 0 byte folders
+    assert_eq!(report.skipped_bytes, 2 + 5000 + 100 + 300 + 54 + 200);
+    let by = |source: &str| report.skipped_by_source.iter().find(|s| s.source == source).unwrap().bytes;
+    assert_eq!(by("paths.max_file_size"), 5000);
+    assert_eq!(by("paths.exclude_caches"), 354);
+    assert_eq!(by("paths.marker_files"), 200);
+
+    let engine = rustybackup::BackupEngine::new(&config);
+    let blob = engine.explain(&root.join("cache/blob")).unwrap();
+    assert_eq!(blob.rule.unwrap().source, "paths.exclude_caches");
+    let ancient = engine.explain(&root.join("ancient.txt")).unwrap();
+    assert!(ancient.excluded);
+    assert_eq!(ancient.rule.unwrap().source, "paths.skip_unmodified_days");
+}
+
+#[test]
+fn presets_combine_with_own_rules_and_report_savings() {
+    use rustybackup::config::{BackupOptions, BackupPaths, Config};
+
+    let tmp = tempdir().expect("tempdir");
+    let root = tmp.path().join("inc");
+    for dir in ["app/node_modules/left-pad", "app/src", ".git/objects"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    fs::write(root.join("app/node_modules/left-pad/index.js"), vec![b'x'; 400]).unwrap();
+    fs::write(root.join("app/src/main.js"), b"main").unwrap();
+    fs::write(root.join(".git/objects/pack"), vec![0u8; 200]).unwrap();
+    fs::write(root.join(".DS_Store"), vec![0u8; 30]).unwrap();
+    fs::write(root.join("Thumbs.db"), vec![0u8; 20]).unwrap();
+    fs::write(root.join("notes.bak"), b"old").unwrap();
+
+    let config = Config {
+        paths: BackupPaths {
+            include: vec![root.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ignore: vec!["*.bak".into(), "!Thumbs.db".into()],
+            exclude_presets: vec!["dev-build-artifacts".into(), "os-metadata".into(), "vcs-internals".into()],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: tmp.path().join("dest").to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    assert_eq!(scanned(&config), vec!["Thumbs.db", "app/src/main.js"]);
+
+    let report = rustybackup::BackupEngine::new(&config).scan(false).unwrap();
+    let by = |source: &str| report.skipped_by_source.iter().find(|s| s.source == source).map(|s| s.bytes);
+    assert_eq!(by("preset:dev-build-artifacts"), Some(400));
+    assert_eq!(by("preset:vcs-internals"), Some(200));
+    assert_eq!(by("preset:os-metadata"), Some(30));
+    assert_eq!(by("paths.ignore"), Some(3));
+
+    // Unknown presets are rejected when the config is loaded
+    let config_path = tmp.path().join("config.toml");
+    fs::write(
+        &config_path,
+        "[paths]\ninclude = []\nexclude = []\nexclude_presets = [\"junk\"]\n[backup]\ndestination = \"d\"\n",
+    )
+    .unwrap();
+    let err = rustybackup::Config::load(&config_path).unwrap_err();
+    assert!(matches!(err, rustybackup::Error::InvalidConfig { .. }));
+    assert!(err.to_string().contains("os-metadata"), "{err}");
+}
+
+#[test]
+fn one_file_system_keeps_the_walk_on_the_root_device() {
+    use rustybackup::config::{BackupOptions, BackupPaths, Config};
+
+    let tmp = tempdir().expect("tempdir");
+    let root = tmp.path().join("inc");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("sub/file.txt"), b"data").unwrap();
+
+    let paths: BackupPaths = toml::from_str(&format!(
+        "include = [{{ path = {:?}, one_file_system = false }}, \"/\"]\nexclude = []\none_file_system = true\n",
+        root.to_string_lossy()
+    ))
+    .unwrap();
+    assert!(!paths.include[0].one_file_system(paths.one_file_system));
+    assert!(paths.include[1].one_file_system(paths.one_file_system));
+
+    // A tree on a single file system is walked as before
+    let config = Config {
+        paths: BackupPaths {
+            include: vec![root.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            one_file_system: true,
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: tmp.path().join("dest").to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert_eq!(scanned(&config), vec!["sub/file.txt"]);
+    let report = rustybackup::BackupEngine::new(&config).scan(false).unwrap();
+    assert!(report.mount_points.is_empty());
+
+    // Virtual file systems such as /proc are not entered when including /
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let root_dev = fs::metadata("/").unwrap().dev();
+        if fs::metadata("/proc").is_ok_and(|m| m.dev() != root_dev) {
+            let paths = BackupPaths {
+                include: vec!["/".into()],
+                exclude: vec![],
+                one_file_system: true,
+                ..Default::default()
+            };
+            let proc = rustybackup::exclude::explain(&paths, &tmp.path().join("dest"), std::path::Path::new("/proc/self/status")).unwrap();
+            assert!(proc.excluded);
+            assert_eq!(proc.rule.unwrap().source, rustybackup::exclude::ONE_FILE_SYSTEM);
+
+            let paths = BackupPaths { one_file_system: false, ..paths };
+            assert!(!rustybackup::exclude::explain(&paths, &tmp.path().join("dest"), std::path::Path::new("/proc/self/status")).unwrap().excluded);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::time::Duration;
+use tempfile::tempdir;
+use rustybackup::{backup, find::{self, FindQuery}, config::{Config, BackupPaths, BackupOptions}};
+
+#[test]
+fn finds_live_and_history_versions() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(src.join("reports")).unwrap();
+    let dest = tmp.path().join("dest");
+    let budget = src.join("reports").join("budget-2024.xlsx");
+    fs::write(&budget, b"v1").unwrap();
+    fs::write(src.join("notes.txt"), b"hello").unwrap();
+
+    let config = Config {
+        paths: BackupPaths {
+            include: vec![src.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: dest.to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    backup::run_backup(&config).unwrap();
+    std::thread::sleep(Duration::from_millis(1100));
+    fs::write(&budget, b"version two").unwrap();
+    backup::run_backup(&config).unwrap();
+
+    let query = FindQuery {
+        name: Some("budget*.xlsx".into()),
+        ..Default::default()
+    };
+    let found = find::find_versions(&config, &query).unwrap();
+    assert_eq!(found.len(), 2);
+    assert!(found.iter().all(|v| v.source == budget));
+    assert!(found[0].archived.is_none());
+    assert_eq!(found[0].size, 11);
+    assert!(found[1].archived.is_some());
+    assert_eq!(fs::read(&found[1].stored).unwrap(), b"v1");
+
+    let query = FindQuery {
+        min_size: Some(5),
+        max_size: Some(5),
+        ..Default::default()
+    };
+    let found = find::find_versions(&config, &query).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].source, src.join("notes.txt"));
+}
+
+#[test]
+fn parses_sizes_and_dates() {
+    assert_eq!(find::parse_size("512").unwrap(), 512);
+    assert_eq!(find::parse_size("10K").unwrap(), 10 * 1024);
+    assert_eq!(find::parse_size("1.5M").unwrap(), 3 * 512 * 1024);
+    assert!(find::parse_size("3X").is_err());
+
+    let date = find::parse_date("2024-03-01").unwrap();
+    assert_eq!(date.format("%Y-%m-%d").to_string(), "2024-03-01");
+    assert!(find::parse_date("yesterday").is_err());
+}
+
+#[test]
+fn filters_on_the_source_modification_time() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    let old = src.join("old.txt");
+    fs::write(&old, b"old").unwrap();
+    let modified = find::parse_date("2020-01-01").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&old)
+        .unwrap()
+        .set_modified(modified.into())
+        .unwrap();
+    fs::write(src.join("new.txt"), b"new").unwrap();
+
+    let config = Config {
+        paths: BackupPaths {
+            include: vec![src.to_string_lossy().to_string().into()],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: tmp.path().join("dest").to_string_lossy().to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    backup::run_backup(&config).unwrap();
+
+    let query = FindQuery {
+        modified_before: Some(find::parse_date("2021-01-01").unwrap()),
+        ..Default::default()
+    };
+    let found = find::find_versions(&config, &query).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].source, old);
+    assert_eq!(found[0].modified, modified);
+    // Backed up by this run, not in 2020
+    assert!(found[0].backed_up > find::parse_date("2021-01-01").unwrap());
+
+    let query = FindQuery {
+        modified_after: Some(find::parse_date("2021-01-01").unwrap()),
+        ..Default::default()
+    };
+    let found = find::find_versions(&config, &query).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].source, src.join("new.txt"));
+}
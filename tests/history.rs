@@ -0,0 +1,347 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tempfile::tempdir;
+use rustybackup::config::{BackupOptions, BackupPaths, Config, HistoryMode};
+use rustybackup::{BackupEngine, BackupState, Error};
+
+fn config_for(tmp: &Path, keep_versions: Option<bool>, history_mode: Option<HistoryMode>) -> Config {
+    Config {
+        paths: BackupPaths {
+            include: vec![tmp.join("src").to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: tmp.join("dest").to_string_lossy().to_string(),
+            keep_versions,
+            history_mode,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Write `content` to `path` with a modification time after the last backup.
+fn change(path: &Path, content: &str) {
+    fs::write(path, content).unwrap();
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+}
+
+fn setup(tmp: &Path) -> std::path::PathBuf {
+    let src = tmp.join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("doc.txt"), "v1").unwrap();
+    fs::write(src.join("old.txt"), "old").unwrap();
+    fs::write(src.join("same.txt"), "same").unwrap();
+    src
+}
+
+#[test]
+fn keep_versions_false_is_a_pure_mirror() {
+    let tmp = tempdir().unwrap();
+    let src = setup(tmp.path());
+    let config = config_for(tmp.path(), Some(false), None);
+    assert_eq!(config.backup.history_mode(), HistoryMode::None);
+
+    BackupEngine::new(&config).backup().unwrap();
+    change(&src.join("doc.txt"), "v2");
+    fs::remove_file(src.join("old.txt")).unwrap();
+    let report = BackupEngine::new(&config).backup().unwrap();
+    assert_eq!(report.files_copied, 1);
+    assert_eq!(report.files_removed, 1);
+
+    let dest = tmp.path().join("dest");
+    let folder = config.paths.include[0].proposed_folder();
+    assert_eq!(fs::read_to_string(dest.join(&folder).join("doc.txt")).unwrap(), "v2");
+    assert!(!dest.join(&folder).join("old.txt").exists());
+    assert!(!dest.join("History").exists());
+}
+
+#[test]
+fn hardlink_snapshots_keep_every_run_browsable() {
+    let tmp = tempdir().unwrap();
+    let src = setup(tmp.path());
+    let config = config_for(tmp.path(), None, Some(HistoryMode::HardlinkSnapshots));
+    let folder = config.paths.include[0].proposed_folder();
+
+    let first = BackupEngine::new(&config).backup().unwrap().snapshot_path.unwrap();
+    change(&src.join("doc.txt"), "v2");
+    fs::remove_file(src.join("old.txt")).unwrap();
+    let second = BackupEngine::new(&config).backup().unwrap().snapshot_path.unwrap();
+
+    let dest = tmp.path().join("dest");
+    assert!(first.starts_with(dest.join("snapshots")));
+    assert!(first.file_name().unwrap().to_string_lossy().starts_with("1-"));
+    assert_eq!(fs::read_to_string(first.join(&folder).join("doc.txt")).unwrap(), "v1");
+    assert_eq!(fs::read_to_string(first.join(&folder).join("old.txt")).unwrap(), "old");
+    assert_eq!(fs::read_to_string(second.join(&folder).join("doc.txt")).unwrap(), "v2");
+    assert!(!second.join(&folder).join("old.txt").exists());
+    assert!(!dest.join("History").exists());
+
+    // Unchanged files share their data between snapshots
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let inode = |p: &Path| fs::metadata(p).unwrap().ino();
+        assert_eq!(inode(&first.join(&folder).join("same.txt")), inode(&second.join(&folder).join("same.txt")));
+        assert_ne!(inode(&first.join(&folder).join("doc.txt")), inode(&second.join(&folder).join("doc.txt")));
+    }
+}
+
+fn files_below(dir: &Path) -> Vec<std::path::PathBuf> {
+    walkdir::WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .map(|e| e.unwrap().into_path())
+        .filter(|p| p.is_file())
+        .collect()
+}
+
+#[test]
+fn switching_modes_is_detected() {
+    let tmp = tempdir().unwrap();
+    let src = setup(tmp.path());
+    let versions = config_for(tmp.path(), Some(true), None);
+    let report = BackupEngine::new(&versions).backup().unwrap();
+    assert_eq!(report.history_mode, HistoryMode::Versions);
+    assert_eq!(report.previous_history_mode, None);
+    change(&src.join("doc.txt"), "v2");
+    BackupEngine::new(&versions).backup().unwrap();
+    let history = tmp.path().join("dest").join("History");
+    let archived = files_below(&history);
+    assert_eq!(archived.len(), 1);
+
+    change(&src.join("doc.txt"), "v3");
+    let mirror = config_for(tmp.path(), None, Some(HistoryMode::None));
+    let report = BackupEngine::new(&mirror).backup().unwrap();
+    assert_eq!(report.previous_history_mode, Some(HistoryMode::Versions));
+
+    let state = BackupState::load(&tmp.path().join("dest").join("state.toml")).unwrap();
+    assert_eq!(state.history_mode, Some(HistoryMode::None));
+    // The History written before the switch is left alone, and the mirror
+    // adds nothing to it
+    assert_eq!(fs::read(&archived[0]).unwrap(), b"v1");
+    let after = files_below(&history);
+    assert_eq!(after, archived);
+
+    let report = BackupEngine::new(&mirror).backup().unwrap();
+    assert_eq!(report.previous_history_mode, None);
+}
+
+#[test]
+fn contradicting_keep_versions_is_rejected() {
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("config.toml");
+    fs::write(
+        &path,
+        "[paths]\ninclude = []\nexclude = []\n[backup]\ndestination = \"d\"\nkeep_versions = false\nhistory_mode = \"versions\"\n",
+    )
+    .unwrap();
+    assert!(matches!(Config::load(&path), Err(Error::InvalidConfig { .. })));
+
+    fs::write(&path, "[paths]\ninclude = []\nexclude = []\n[backup]\ndestination = \"d\"\nhistory_mode = \"hardlink-snapshots\"\n").unwrap();
+    let config = Config::load(&path).unwrap();
+    assert_eq!(config.backup.history_mode(), HistoryMode::HardlinkSnapshots);
+}
+
+#[test]
+fn vacuum_deletes_whole_snapshots_by_retention() {
+    let tmp = tempdir().unwrap();
+    let src = setup(tmp.path());
+    let mut config = config_for(tmp.path(), None, Some(HistoryMode::HardlinkSnapshots));
+    let snapshots = tmp.path().join("dest").join("snapshots");
+
+    // A snapshot from an earlier era, older than any age limit
+    fs::create_dir_all(snapshots.join("0-2020-01-01T00-00-00").join("x")).unwrap();
+    let mut taken = Vec::new();
+    for content in ["v2", "v3", "v4"] {
+        taken.push(BackupEngine::new(&config).backup().unwrap().snapshot_path.unwrap());
+        change(&src.join("doc.txt"), content);
+    }
+
+    // Without retention settings snapshots are never touched
+    let report = BackupEngine::new(&config).vacuum().unwrap();
+    assert!(report.snapshots_removed.is_empty());
+
+    config.backup.max_snapshot_age_days = Some(365);
+    let report = BackupEngine::new(&config).vacuum().unwrap();
+    assert_eq!(report.snapshots_removed, vec![snapshots.join("0-2020-01-01T00-00-00")]);
+
+    config.backup.keep_snapshots = Some(2);
+    let report = BackupEngine::new(&config).vacuum().unwrap();
+    assert_eq!(report.snapshots_removed, vec![taken[0].clone()]);
+    assert!(!taken[0].exists());
+    assert!(taken[1].exists() && taken[2].exists());
+
+    // Deleting a snapshot leaves the data of the others intact
+    let folder = config.paths.include[0].proposed_folder();
+    assert_eq!(fs::read_to_string(taken[1].join(&folder).join("doc.txt")).unwrap(), "v2");
+    assert_eq!(fs::read_to_string(taken[2].join(&folder).join("same.txt")).unwrap(), "same");
+}
+
+/// Pseudo-random but reproducible content, so deltas only find real matches.
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+#[test]
+fn deltas_rebuild_the_target_exactly() {
+    use rustybackup::delta;
+
+    let tmp = tempdir().unwrap();
+    let base = noise(300_000, 1);
+    let mut target = base.clone();
+    target.splice(1000..1000, b"inserted".iter().copied());
+    target.drain(150_000..150_100);
+    target[250_000..250_010].copy_from_slice(b"0123456789");
+    target.extend_from_slice(b"tail");
+
+    fs::write(tmp.path().join("base"), &base).unwrap();
+    fs::write(tmp.path().join("target"), &target).unwrap();
+    let (header, size) =
+        delta::write_delta(&tmp.path().join("base"), &tmp.path().join("target"), &tmp.path().join("d")).unwrap();
+    assert_eq!(header.target_len, target.len() as u64);
+    assert!(size < 30_000, "delta is {} bytes", size);
+
+    let mut rebuilt = Vec::new();
+    let mut base_file = fs::File::open(tmp.path().join("base")).unwrap();
+    delta::apply_delta(fs::File::open(tmp.path().join("d")).unwrap(), &mut base_file, &mut rebuilt).unwrap();
+    assert_eq!(rebuilt, target);
+
+    // A different base is detected instead of producing garbage
+    fs::write(tmp.path().join("base"), noise(300_000, 2)).unwrap();
+    let mut other = fs::File::open(tmp.path().join("base")).unwrap();
+    let err = delta::apply_delta(fs::File::open(tmp.path().join("d")).unwrap(), &mut other, &mut Vec::new());
+    assert_eq!(err.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn history_versions_are_stored_as_reverse_deltas() {
+    use rustybackup::find::{self, FindQuery};
+    use rustybackup::history;
+
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    let mut config = config_for(tmp.path(), None, None);
+    config.backup.delta_history = true;
+    config.backup.delta_min_size = Some(1024);
+    config.backup.max_delta_chain = Some(2);
+
+    // Each version appends a little to a large file, like a mailbox
+    let mut contents = vec![noise(200_000, 7)];
+    for i in 0..4u8 {
+        let mut next = contents.last().unwrap().clone();
+        next.extend_from_slice(&[i; 500]);
+        contents.push(next);
+    }
+    for content in &contents {
+        fs::write(src.join("mail.mbox"), content).unwrap();
+        BackupEngine::new(&config).backup().unwrap();
+        // History names have a resolution of one second
+        std::thread::sleep(Duration::from_millis(1100));
+    }
+
+    let query = FindQuery {
+        name: Some("mail.mbox".into()),
+        ..Default::default()
+    };
+    let found = find::find_versions(&config, &query).unwrap();
+    assert_eq!(found.len(), 5);
+    assert!(found[0].archived.is_none());
+    // The newest History version stays full. The one before it is kept in
+    // full as well, because turning it into a delta would make the chain of
+    // the two older deltas longer than 2
+    let deltas: Vec<bool> = found[1..].iter().map(|v| v.delta).collect();
+    assert_eq!(deltas, vec![false, false, true, true]);
+    for (version, content) in found.iter().zip(contents.iter().rev()) {
+        assert_eq!(version.size, content.len() as u64);
+        let mut rebuilt = Vec::new();
+        history::read_version(&version.stored, &mut rebuilt).unwrap();
+        assert_eq!(&rebuilt, content);
+    }
+    let stored: u64 = found[1..].iter().map(|v| fs::metadata(&v.stored).unwrap().len()).sum();
+    assert!(stored < 2 * 200_000 + 20_000, "History takes {} bytes", stored);
+
+    // Damage the delta the oldest version is rebuilt through
+    fs::write(&found[3].stored, b"garbage").unwrap();
+    let err = history::read_version(&found[4].stored, &mut Vec::new()).unwrap_err();
+    assert!(matches!(err, Error::CorruptVersion { .. }), "{err:?}");
+}
+
+#[test]
+fn pruning_a_delta_base_rebases_the_older_version() {
+    use rustybackup::find::{self, FindQuery};
+    use rustybackup::history;
+
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    let mut config = config_for(tmp.path(), None, None);
+    config.backup.delta_history = true;
+    config.backup.delta_min_size = Some(1024);
+
+    let mut contents = vec![noise(200_000, 3)];
+    for i in 0..4u8 {
+        let mut next = contents.last().unwrap().clone();
+        next.extend_from_slice(&[i; 500]);
+        contents.push(next);
+    }
+    for content in &contents {
+        fs::write(src.join("mail.mbox"), content).unwrap();
+        BackupEngine::new(&config).backup().unwrap();
+        std::thread::sleep(Duration::from_millis(1100));
+    }
+    let query = FindQuery {
+        name: Some("mail.mbox".into()),
+        ..Default::default()
+    };
+    let versions = |config: &Config| -> Vec<_> {
+        find::find_versions(config, &query).unwrap().into_iter().skip(1).collect()
+    };
+    let found = versions(&config);
+    let deltas: Vec<bool> = found.iter().map(|v| v.delta).collect();
+    assert_eq!(deltas, vec![false, true, true, true]);
+
+    // The second History version is the base of the third one
+    let options = config.backup.delta_options();
+    history::prune_version(&found[1].stored, options.as_ref()).unwrap();
+    let found = versions(&config);
+    let deltas: Vec<bool> = found.iter().map(|v| v.delta).collect();
+    assert_eq!(deltas, vec![false, true, true]);
+    let expected = [&contents[3], &contents[1], &contents[0]];
+    for (version, content) in found.iter().zip(expected) {
+        let mut rebuilt = Vec::new();
+        history::read_version(&version.stored, &mut rebuilt).unwrap();
+        assert_eq!(&rebuilt, content);
+    }
+    let leftovers: Vec<_> = fs::read_dir(found[0].stored.parent().unwrap())
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|name| name.ends_with(".part"))
+        .collect();
+    assert!(leftovers.is_empty(), "{leftovers:?}");
+
+    // Vacuum prunes the oldest versions first and leaves the newest readable
+    config.backup.max_versions = Some(1);
+    BackupEngine::new(&config).vacuum().unwrap();
+    let found = versions(&config);
+    assert_eq!(found.len(), 1);
+    let mut rebuilt = Vec::new();
+    history::read_version(&found[0].stored, &mut rebuilt).unwrap();
+    assert_eq!(rebuilt, contents[3]);
+}
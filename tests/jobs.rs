@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::tempdir;
+use rustybackup::{BackupEngine, BackupState, Config, Error};
+
+fn binary() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_rustybackup"))
+}
+
+/// Write a config with jobs `docs` and `photos` sharing one destination.
+fn write_jobs_config(root: &Path) -> std::path::PathBuf {
+    for (dir, file) in [("docs", "report.txt"), ("photos", "cat.jpg")] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+        fs::write(root.join(dir).join(file), file.as_bytes()).unwrap();
+    }
+    let dest = root.join("dest");
+    let config_path = root.join("config.toml");
+    let content = format!(
+        "[[job]]\nname = \"docs\"\n[job.paths]\ninclude = [{docs:?}]\nexclude = []\n[job.backup]\ndestination = {dest:?}\nmax_versions = 5\n\n\
+         [[job]]\nname = \"photos\"\n[job.paths]\ninclude = [{photos:?}]\nexclude = []\n[job.backup]\ndestination = {dest:?}\n",
+        docs = root.join("docs").to_string_lossy(),
+        photos = root.join("photos").to_string_lossy(),
+        dest = dest.to_string_lossy(),
+    );
+    fs::write(&config_path, content).unwrap();
+    config_path
+}
+
+#[test]
+fn jobs_are_parsed_and_resolved() {
+    let tmp = tempdir().unwrap();
+    let config = Config::load(&write_jobs_config(tmp.path())).unwrap();
+    assert_eq!(config.job_names(), vec!["docs", "photos"]);
+
+    let docs = config.job("docs").unwrap();
+    assert_eq!(docs.job.as_deref(), Some("docs"));
+    assert_eq!(docs.backup.max_versions, Some(5));
+    assert!(docs.paths.include[0].path.ends_with("docs"));
+
+    assert!(matches!(config.job("music"), Err(Error::InvalidArgument(_))));
+    assert_eq!(config.all_jobs().len(), 2);
+}
+
+#[test]
+fn duplicate_job_names_are_rejected() {
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("config.toml");
+    fs::write(
+        &path,
+        "[[job]]\nname = \"a\"\n[job.paths]\ninclude = []\nexclude = []\n[job.backup]\ndestination = \"d\"\n\n\
+         [[job]]\nname = \"a\"\n[job.paths]\ninclude = []\nexclude = []\n[job.backup]\ndestination = \"d\"\n",
+    )
+    .unwrap();
+    assert!(matches!(Config::load(&path), Err(Error::InvalidConfig { .. })));
+}
+
+#[test]
+fn jobs_sharing_a_destination_keep_separate_state() {
+    let tmp = tempdir().unwrap();
+    let config = Config::load(&write_jobs_config(tmp.path())).unwrap();
+    let docs = config.job("docs").unwrap();
+    let photos = config.job("photos").unwrap();
+
+    let report = BackupEngine::new(&docs).backup().unwrap();
+    assert_eq!(report.snapshot_id, 1);
+    assert_eq!(report.files_copied, 1);
+
+    // The photos job has not run yet, so it copies its file despite the
+    // newer docs backup in the same state.toml
+    let report = BackupEngine::new(&photos).backup().unwrap();
+    assert_eq!(report.snapshot_id, 1);
+    assert_eq!(report.files_copied, 1);
+
+    let state = BackupState::load(&tmp.path().join("dest").join("state.toml")).unwrap();
+    assert_eq!(state.jobs.len(), 2);
+    assert_eq!(state.namespace(Some("docs")).unwrap().stats.len(), 1);
+    assert_eq!(state.namespace(Some("photos")).unwrap().latest.snapshot_id, "1");
+
+    let status = BackupEngine::new(&docs).status(0).unwrap();
+    assert_eq!(status.latest.unwrap().snapshot_id, "1");
+}
+
+#[test]
+fn cli_requires_job_selection_for_several_jobs() {
+    let tmp = tempdir().unwrap();
+    let config_path = write_jobs_config(tmp.path());
+    let config = config_path.to_str().unwrap();
+
+    let output = binary().args(["--config", config, "backup"]).output().expect("run binary");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--job NAME or --all"));
+
+    let output = binary()
+        .args(["--config", config, "--job", "docs", "--output", "json", "backup"])
+        .output()
+        .expect("run binary");
+    assert!(output.status.success());
+    let doc: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(doc["result"]["files_copied"], 1);
+
+    let output = binary()
+        .args(["--config", config, "--all", "--output", "json", "status"])
+        .output()
+        .expect("run binary");
+    assert!(output.status.success());
+    let doc: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let results = doc["result"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0]["job"], "docs");
+    assert_eq!(results[0]["result"]["latest"]["snapshot_id"], "1");
+    assert!(results[1]["result"]["latest"].is_null());
+}
+
+#[test]
+fn a_failed_job_keeps_the_results_of_earlier_ones() {
+    let tmp = tempdir().unwrap();
+    let root = tmp.path();
+    fs::create_dir_all(root.join("docs")).unwrap();
+    fs::write(root.join("docs").join("report.txt"), b"report").unwrap();
+    // The second job's destination is a file, so that job cannot run
+    fs::write(root.join("blocked"), b"").unwrap();
+    let config_path = root.join("config.toml");
+    let content = format!(
+        "[[job]]\nname = \"docs\"\n[job.paths]\ninclude = [{docs:?}]\nexclude = []\n[job.backup]\ndestination = {dest:?}\n\n\
+         [[job]]\nname = \"broken\"\n[job.paths]\ninclude = [{docs:?}]\nexclude = []\n[job.backup]\ndestination = {blocked:?}\n",
+        docs = root.join("docs").to_string_lossy(),
+        dest = root.join("dest").to_string_lossy(),
+        blocked = root.join("blocked").to_string_lossy(),
+    );
+    fs::write(&config_path, content).unwrap();
+
+    let output = binary()
+        .args(["--config", config_path.to_str().unwrap(), "--all", "--output", "json", "backup"])
+        .output()
+        .expect("run binary");
+    assert_eq!(output.status.code(), Some(3));
+    let doc: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(doc["ok"], false);
+    assert!(doc["error"].as_str().unwrap().contains("Job broken failed"), "{doc}");
+    let results = doc["result"].as_array().unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0]["job"], "docs");
+    assert_eq!(results[0]["result"]["files_copied"], 1);
+}
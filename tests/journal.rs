@@ -18,7 +18,7 @@ fn file_included_when_missing_in_destination() {
     let dest_dir = tmp.path().join("dest");
     fs::create_dir_all(&dest_dir).unwrap();
 
-    let changed = journal::changed_files(since, &[include_dir.clone()], &[], &dest_dir, true).unwrap();
+    let changed = journal::changed_files(since, std::slice::from_ref(&include_dir), &[], &dest_dir, true).unwrap();
     assert_eq!(changed, vec![src_file]);
 }
 
@@ -32,7 +32,7 @@ fn file_skipped_when_present_and_unchanged() {
 
     let dest_dir = tmp.path().join("dest");
     // replicate backup layout
-    let root_label = include_dir.to_string_lossy().replace(':', "").replace('\\', "-").replace('/', "-");
+    let root_label = include_dir.to_string_lossy().replace(':', "").replace(['\\', '/'], "-");
     let dest_file = dest_dir.join(root_label).join("b.txt");
     fs::create_dir_all(dest_file.parent().unwrap()).unwrap();
     File::create(&dest_file).unwrap();
@@ -40,6 +40,6 @@ fn file_skipped_when_present_and_unchanged() {
     std::thread::sleep(Duration::from_millis(10));
     let since = SystemTime::now();
 
-    let changed = journal::changed_files(since, &[include_dir.clone()], &[], &dest_dir, true).unwrap();
+    let changed = journal::changed_files(since, std::slice::from_ref(&include_dir), &[], &dest_dir, true).unwrap();
     assert!(changed.is_empty());
 }
@@ -0,0 +1,93 @@
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+use log::LevelFilter;
+use tempfile::tempdir;
+use rustybackup::config::{Config, LoggingOptions, DEFAULT_KEEP_RUNS};
+use rustybackup::storage::StorageBackend;
+use rustybackup::{logging, MemoryStorage};
+
+fn binary() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_rustybackup"))
+}
+
+#[test]
+fn verbosity_flags_adjust_level() {
+    assert_eq!(logging::level_from_verbosity(LevelFilter::Info, 0, 0), LevelFilter::Info);
+    assert_eq!(logging::level_from_verbosity(LevelFilter::Info, 1, 0), LevelFilter::Debug);
+    assert_eq!(logging::level_from_verbosity(LevelFilter::Info, 5, 0), LevelFilter::Trace);
+    assert_eq!(logging::level_from_verbosity(LevelFilter::Info, 0, 2), LevelFilter::Error);
+    assert_eq!(logging::level_from_verbosity(LevelFilter::Info, 0, 9), LevelFilter::Off);
+}
+
+#[test]
+fn backup_writes_rotated_run_logs() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a.txt"), b"abc").unwrap();
+    let dest = tmp.path().join("dest");
+    let config_path = tmp.path().join("config.toml");
+    let content = format!(
+        "[paths]\ninclude=[\"{}\"]\nexclude=[]\n\n[backup]\ndestination=\"{}\"\n\n[logging]\nfile=true\nkeep_runs=2\n",
+        src.display(),
+        dest.display()
+    );
+    fs::write(&config_path, content).unwrap();
+
+    for _ in 0..3 {
+        let output = binary()
+            .args(["--config", config_path.to_str().unwrap(), "-q", "backup"])
+            .output()
+            .expect("run binary");
+        assert!(output.status.success());
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let logs = dest.join("logs");
+    let mut names: Vec<String> = fs::read_dir(&logs)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["2.log", "3.log"]);
+
+    let log = fs::read_to_string(logs.join("3.log")).unwrap();
+    assert!(log.contains("INFO  Backup 3 completed"));
+
+    let output = binary()
+        .args(["--config", config_path.to_str().unwrap(), "status", "--tail", "5"])
+        .output()
+        .expect("run binary");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Backup 3 completed"));
+}
+
+#[test]
+fn keep_runs_zero_or_false_keeps_every_log() {
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("config.toml");
+    let load = |keep_runs: &str| {
+        let content = format!(
+            "[paths]\ninclude = []\nexclude = []\n[backup]\ndestination = \"d\"\n[logging]\nkeep_runs = {keep_runs}\n"
+        );
+        fs::write(&path, content).unwrap();
+        Config::load(&path).unwrap().logging.keep_runs
+    };
+    assert_eq!(load("0"), None);
+    assert_eq!(load("false"), None);
+    assert_eq!(load("true"), Some(DEFAULT_KEEP_RUNS));
+    assert_eq!(load("5"), Some(5));
+
+    let storage = MemoryStorage::new("memory");
+    for id in 1..=3 {
+        storage.put(std::path::Path::new(&format!("logs/{id}.log")), &mut &b"run"[..]).unwrap();
+    }
+    let options = LoggingOptions {
+        keep_runs: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(logging::rotate_logs(&storage, None, &options).unwrap(), 0);
+    assert_eq!(storage.keys().len(), 3);
+}
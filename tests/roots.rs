@@ -0,0 +1,265 @@
+use std::fs;
+use tempfile::tempdir;
+use rustybackup::{BackupEngine, Error, RootRegistry};
+use rustybackup::config::{BackupOptions, BackupPaths, Config, IncludeRoot};
+use rustybackup::find::FindQuery;
+
+#[test]
+fn per_root_label_exclude_and_retention() {
+    let tmp = tempdir().unwrap();
+    let docs = tmp.path().join("docs");
+    let other = tmp.path().join("other");
+    for dir in [&docs, &other] {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("keep.txt"), b"keep").unwrap();
+        fs::write(dir.join("scratch.tmp"), b"tmp").unwrap();
+    }
+    let dest = tmp.path().join("dest");
+    let config = Config {
+        paths: BackupPaths {
+            include: vec![
+                IncludeRoot {
+                    path: docs.to_string_lossy().to_string(),
+                    label: Some("documents".into()),
+                    exclude: vec!["*.tmp".into()],
+                    max_versions: Some(1),
+                    ..Default::default()
+                },
+                other.to_string_lossy().to_string().into(),
+            ],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: dest.to_string_lossy().to_string(),
+            max_versions: Some(3),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    BackupEngine::new(&config).backup().unwrap();
+
+    // The explicit label names the folder and the root's exclude only applies there
+    assert!(dest.join("documents").join("keep.txt").exists());
+    assert!(!dest.join("documents").join("scratch.tmp").exists());
+    let other_label = config.paths.include[1].proposed_folder();
+    assert!(dest.join(&other_label).join("scratch.tmp").exists());
+
+    // Five archived versions per root; vacuum keeps 1 for docs and the global 3 otherwise
+    for label in ["documents", other_label.as_str()] {
+        let hist = dest.join("History").join(label);
+        fs::create_dir_all(&hist).unwrap();
+        for day in 1..=5 {
+            fs::write(hist.join(format!("keep_2024-01-0{day}T10-00-00.txt")), b"old").unwrap();
+        }
+    }
+    let report = BackupEngine::new(&config).vacuum().unwrap();
+    assert_eq!(report.files_removed, 4 + 2);
+
+    let count = |label: &str| fs::read_dir(dest.join("History").join(label)).unwrap().count();
+    assert_eq!(count("documents"), 1);
+    assert_eq!(count(&other_label), 3);
+}
+
+fn config_with(roots: Vec<IncludeRoot>, dest: &std::path::Path) -> Config {
+    Config {
+        paths: BackupPaths {
+            include: roots,
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: dest.to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[test]
+fn colliding_roots_get_distinct_folders() {
+    let tmp = tempdir().unwrap();
+    let dashed = tmp.path().join("a-b");
+    let nested = tmp.path().join("a").join("b");
+    for dir in [&dashed, &nested] {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("file.txt"), dir.to_string_lossy().as_bytes()).unwrap();
+    }
+    let dest = tmp.path().join("dest");
+    let config = config_with(
+        vec![
+            dashed.to_string_lossy().to_string().into(),
+            nested.to_string_lossy().to_string().into(),
+        ],
+        &dest,
+    );
+
+    BackupEngine::new(&config).backup().unwrap();
+
+    let registry = RootRegistry::load(&dest).unwrap();
+    let dashed_folder = registry.folder_of(&dashed.to_string_lossy()).unwrap().to_string();
+    let nested_folder = registry.folder_of(&nested.to_string_lossy()).unwrap().to_string();
+    assert_eq!(dashed_folder, config.paths.include[0].proposed_folder());
+    assert_eq!(nested_folder, format!("{}-2", dashed_folder));
+    for (dir, folder) in [(&dashed, &dashed_folder), (&nested, &nested_folder)] {
+        let stored = fs::read(dest.join(folder).join("file.txt")).unwrap();
+        assert_eq!(stored, dir.to_string_lossy().as_bytes());
+    }
+
+    // The mapping is stable when the order of the roots changes
+    let reordered = config_with(config.paths.include.iter().rev().cloned().collect(), &dest);
+    BackupEngine::new(&reordered).backup().unwrap();
+    let registry = RootRegistry::load(&dest).unwrap();
+    assert_eq!(registry.roots.len(), 2);
+    assert_eq!(registry.folder_of(&nested.to_string_lossy()), Some(nested_folder.as_str()));
+}
+
+#[test]
+fn explicit_label_conflicts_are_rejected() {
+    let tmp = tempdir().unwrap();
+    let one = tmp.path().join("one");
+    let two = tmp.path().join("two");
+    fs::create_dir_all(&one).unwrap();
+    fs::create_dir_all(&two).unwrap();
+    let dest = tmp.path().join("dest");
+    let labelled = |path: &std::path::Path, label: &str| IncludeRoot {
+        path: path.to_string_lossy().to_string(),
+        label: Some(label.into()),
+        ..Default::default()
+    };
+
+    let config = config_with(vec![labelled(&one, "shared"), labelled(&two, "shared")], &dest);
+    let err = BackupEngine::new(&config).backup().unwrap_err();
+    assert!(matches!(err, Error::RootConflict { ref folder, .. } if folder == "shared"));
+
+    let config = config_with(vec![labelled(&one, "first")], &dest);
+    BackupEngine::new(&config).backup().unwrap();
+    let renamed = config_with(vec![labelled(&one, "renamed")], &dest);
+    let err = BackupEngine::new(&renamed).scan(false).unwrap_err();
+    assert!(matches!(err, Error::RootConflict { .. }));
+}
+
+#[test]
+fn find_covers_roots_removed_from_config() {
+    let tmp = tempdir().unwrap();
+    let old = tmp.path().join("old");
+    let new = tmp.path().join("new");
+    fs::create_dir_all(&old).unwrap();
+    fs::create_dir_all(&new).unwrap();
+    fs::write(old.join("letter.txt"), b"old").unwrap();
+    let dest = tmp.path().join("dest");
+
+    let config = config_with(vec![old.to_string_lossy().to_string().into()], &dest);
+    BackupEngine::new(&config).backup().unwrap();
+
+    let config = config_with(vec![new.to_string_lossy().to_string().into()], &dest);
+    let found = BackupEngine::new(&config).find(&FindQuery::default()).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].source, old.join("letter.txt"));
+}
+
+#[test]
+fn destination_and_nested_roots_inside_a_source_are_not_stored_twice() {
+    let tmp = tempdir().unwrap();
+    let home = tmp.path().join("home");
+    let projects = home.join("projects");
+    fs::create_dir_all(&projects).unwrap();
+    fs::write(home.join("notes.txt"), b"notes").unwrap();
+    fs::write(projects.join("main.rs"), b"fn main() {}").unwrap();
+    // The destination lives inside the home root
+    let dest = home.join("backups");
+    let config = Config {
+        paths: BackupPaths {
+            include: vec![
+                home.to_string_lossy().to_string().into(),
+                IncludeRoot {
+                    path: projects.to_string_lossy().to_string(),
+                    label: Some("projects".into()),
+                    ..Default::default()
+                },
+            ],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: dest.to_string_lossy().to_string(),
+            max_versions: None,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let report = BackupEngine::new(&config).backup().unwrap();
+    assert_eq!(report.files_copied, 2);
+    let home_folder = config.paths.include[0].proposed_folder();
+    assert!(dest.join(&home_folder).join("notes.txt").exists());
+    assert!(!dest.join(&home_folder).join("projects").exists());
+    assert!(dest.join("projects").join("main.rs").exists());
+
+    // The second run does not pick up the first run's mirror or state
+    fs::write(home.join("notes.txt"), b"more notes").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(home.join("notes.txt"))
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60))
+        .unwrap();
+    let scan = BackupEngine::new(&config).scan(true).unwrap();
+    let names: Vec<_> = scan.files.iter().map(|f| f.path.clone()).collect();
+    assert_eq!(names, vec![home.join("notes.txt")]);
+    let report = BackupEngine::new(&config).backup().unwrap();
+    assert_eq!(report.files_copied, 1);
+    assert!(!dest.join(&home_folder).join("backups").exists());
+
+    let explained = BackupEngine::new(&config).explain(&dest.join("state.toml")).unwrap();
+    assert!(explained.excluded);
+    assert_eq!(explained.rule.unwrap().source, rustybackup::exclude::DESTINATION);
+    let nested = BackupEngine::new(&config).explain(&projects.join("main.rs")).unwrap();
+    assert_eq!(nested.root.as_deref(), Some(projects.to_str().unwrap()));
+    assert!(!nested.excluded);
+}
+
+#[test]
+fn adding_a_nested_root_moves_its_earlier_copies() {
+    let tmp = tempdir().unwrap();
+    let home = tmp.path().join("home");
+    let projects = home.join("projects");
+    fs::create_dir_all(projects.join("app")).unwrap();
+    fs::write(home.join("notes.txt"), b"notes").unwrap();
+    fs::write(projects.join("app").join("main.rs"), b"fn main() {}").unwrap();
+    fs::write(projects.join("README"), b"readme").unwrap();
+    let dest = tmp.path().join("dest");
+    let mut config = Config {
+        paths: BackupPaths {
+            include: vec![home.to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: dest.to_string_lossy().to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    BackupEngine::new(&config).backup().unwrap();
+    let home_folder = config.paths.include[0].proposed_folder();
+    assert!(dest.join(&home_folder).join("projects/app/main.rs").exists());
+
+    // A copy the nested root already holds is not overwritten
+    fs::create_dir_all(dest.join("projects")).unwrap();
+    fs::write(dest.join("projects/README"), b"newer").unwrap();
+    config.paths.include.push(IncludeRoot {
+        path: projects.to_string_lossy().to_string(),
+        label: Some("projects".into()),
+        ..Default::default()
+    });
+    let report = BackupEngine::new(&config).backup().unwrap();
+    assert_eq!(report.files_copied, 0, "the earlier copies are moved, not sent again");
+    assert_eq!(fs::read(dest.join("projects/app/main.rs")).unwrap(), b"fn main() {}");
+    assert!(!dest.join(&home_folder).join("projects/app/main.rs").exists());
+    assert_eq!(fs::read(dest.join("projects/README")).unwrap(), b"newer");
+    assert!(dest.join(&home_folder).join("projects/README").exists());
+    assert!(dest.join(&home_folder).join("notes.txt").exists());
+}
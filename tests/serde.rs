@@ -1,5 +1,4 @@
 use rustybackup::config::Config;
-use toml; // might require network access to fetch dependencies
 
 #[test]
 fn test_parse_config() {
@@ -17,3 +16,32 @@ fn test_parse_config() {
     assert_eq!(config.backup.destination, "Z:/Backups");
     assert_eq!(config.paths.include.len(), 1);
 }
+
+#[test]
+fn include_entries_may_be_tables() {
+    let sample = r#"
+        [paths]
+        include = [
+            "/data/plain",
+            { path = "/home/me/Documents", label = "docs", exclude = ["*.tmp"], max_versions = 20, follow_symlinks = true },
+        ]
+        exclude = []
+
+        [backup]
+        destination = "/backup"
+        max_versions = 5
+    "#;
+
+    let config: Config = toml::from_str(sample).expect("Failed to parse config");
+    let plain = &config.paths.include[0];
+    assert_eq!(plain.proposed_folder(), "-data-plain");
+    assert_eq!(plain.max_versions(config.backup.max_versions), Some(5));
+    assert!(!plain.follow_symlinks);
+
+    let docs = &config.paths.include[1];
+    assert_eq!(docs.path, "/home/me/Documents");
+    assert_eq!(docs.proposed_folder(), "docs");
+    assert_eq!(docs.exclude, vec!["*.tmp".to_string()]);
+    assert_eq!(docs.max_versions(config.backup.max_versions), Some(20));
+    assert!(docs.follow_symlinks);
+}
@@ -0,0 +1,435 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use ssh2::{ErrorCode, FileStat};
+use tempfile::tempdir;
+use rustybackup::sftp::{SftpOptions, SftpSession, SftpStorage, SftpTarget};
+use rustybackup::storage::StorageBackend;
+use rustybackup::{BackupEngine, Config, Error};
+
+const FX_NO_SUCH_FILE: i32 = 2;
+const FX_FAILURE: i32 = 4;
+/// `LIBSSH2_ERROR_SOCKET_SEND`, a broken connection
+const SOCKET_SEND: i32 = -7;
+
+enum Node {
+    Dir,
+    File { data: Vec<u8>, mtime: u64 },
+}
+
+/// An in-memory SFTP version 3 server: renames never overwrite and exclusive
+/// creation fails on an existing file.
+#[derive(Default)]
+struct FakeServer {
+    tree: Arc<Mutex<BTreeMap<PathBuf, Node>>>,
+    /// Requests to fail once, as (request, path, matching requests to let pass first, code)
+    failures: Mutex<Vec<(&'static str, PathBuf, usize, ErrorCode)>>,
+    connects: AtomicUsize,
+}
+
+impl FakeServer {
+    fn new() -> Arc<Self> {
+        let server = FakeServer::default();
+        server.tree.lock().unwrap().insert(PathBuf::from("/"), Node::Dir);
+        Arc::new(server)
+    }
+
+    /// Storage on `/srv/backups` that opens a new session on this server
+    /// whenever it connects.
+    fn storage(self: &Arc<Self>) -> SftpStorage {
+        let server = Arc::clone(self);
+        SftpStorage::with_session("sftp://test@fake/srv/backups", move || {
+            server.connects.fetch_add(1, Ordering::SeqCst);
+            let session: Arc<dyn SftpSession> = server.clone();
+            Ok(session)
+        })
+        .unwrap()
+    }
+
+    fn fail(&self, request: &'static str, path: &str, skip: usize, code: ErrorCode) {
+        self.failures.lock().unwrap().push((request, PathBuf::from(path), skip, code));
+    }
+
+    fn check(&self, request: &'static str, path: &Path) -> Result<(), ssh2::Error> {
+        let mut failures = self.failures.lock().unwrap();
+        let Some(i) = failures.iter().position(|f| f.0 == request && f.1 == path) else {
+            return Ok(());
+        };
+        if failures[i].2 > 0 {
+            failures[i].2 -= 1;
+            return Ok(());
+        }
+        Err(ssh2::Error::new(failures.remove(i).3, "injected failure"))
+    }
+
+    fn write(&self, path: &str, data: &str, mtime: u64) {
+        let node = Node::File { data: data.as_bytes().to_vec(), mtime };
+        self.tree.lock().unwrap().insert(PathBuf::from(path), node);
+    }
+
+    fn read(&self, path: &str) -> Option<String> {
+        match self.tree.lock().unwrap().get(Path::new(path)) {
+            Some(Node::File { data, .. }) => Some(String::from_utf8(data.clone()).unwrap()),
+            _ => None,
+        }
+    }
+}
+
+fn status(code: i32) -> ssh2::Error {
+    ssh2::Error::new(ErrorCode::SFTP(code), "request failed")
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn stat_of(node: &Node) -> FileStat {
+    let (size, perm, mtime) = match node {
+        Node::Dir => (0, 0o040755, 0),
+        Node::File { data, mtime } => (data.len() as u64, 0o100644, *mtime),
+    };
+    FileStat { size: Some(size), uid: None, gid: None, perm: Some(perm), atime: Some(mtime), mtime: Some(mtime) }
+}
+
+/// Appends to a file of the server.
+struct FakeFile {
+    tree: Arc<Mutex<BTreeMap<PathBuf, Node>>>,
+    path: PathBuf,
+}
+
+impl Write for FakeFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.tree.lock().unwrap().get_mut(&self.path) {
+            Some(Node::File { data, .. }) => data.extend_from_slice(buf),
+            _ => return Err(io::ErrorKind::NotFound.into()),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SftpSession for FakeServer {
+    fn stat(&self, path: &Path) -> Result<FileStat, ssh2::Error> {
+        self.check("stat", path)?;
+        self.tree.lock().unwrap().get(path).map(stat_of).ok_or(status(FX_NO_SUCH_FILE))
+    }
+
+    fn readdir(&self, dir: &Path) -> Result<Vec<(PathBuf, FileStat)>, ssh2::Error> {
+        self.check("readdir", dir)?;
+        let tree = self.tree.lock().unwrap();
+        if !matches!(tree.get(dir), Some(Node::Dir)) {
+            return Err(status(FX_NO_SUCH_FILE));
+        }
+        Ok(tree
+            .iter()
+            .filter(|(path, _)| path.parent() == Some(dir))
+            .map(|(path, node)| (path.clone(), stat_of(node)))
+            .collect())
+    }
+
+    fn mkdir(&self, dir: &Path) -> Result<(), ssh2::Error> {
+        self.check("mkdir", dir)?;
+        let mut tree = self.tree.lock().unwrap();
+        if tree.contains_key(dir) {
+            return Err(status(FX_FAILURE));
+        }
+        if !matches!(dir.parent().and_then(|p| tree.get(p)), Some(Node::Dir)) {
+            return Err(status(FX_NO_SUCH_FILE));
+        }
+        tree.insert(dir.to_path_buf(), Node::Dir);
+        Ok(())
+    }
+
+    fn create(&self, path: &Path, exclusive: bool) -> Result<Box<dyn Write + Send>, ssh2::Error> {
+        self.check("create", path)?;
+        let mut tree = self.tree.lock().unwrap();
+        if exclusive && tree.contains_key(path) {
+            return Err(status(FX_FAILURE));
+        }
+        if !matches!(path.parent().and_then(|p| tree.get(p)), Some(Node::Dir)) {
+            return Err(status(FX_NO_SUCH_FILE));
+        }
+        tree.insert(path.to_path_buf(), Node::File { data: Vec::new(), mtime: now() });
+        Ok(Box::new(FakeFile { tree: Arc::clone(&self.tree), path: path.to_path_buf() }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Read + Send>, ssh2::Error> {
+        self.check("open", path)?;
+        match self.tree.lock().unwrap().get(path) {
+            Some(Node::File { data, .. }) => Ok(Box::new(io::Cursor::new(data.clone()))),
+            _ => Err(status(FX_NO_SUCH_FILE)),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), ssh2::Error> {
+        self.check("rename", from)?;
+        let mut tree = self.tree.lock().unwrap();
+        if tree.contains_key(to) {
+            return Err(status(FX_FAILURE));
+        }
+        let node = tree.remove(from).ok_or(status(FX_NO_SUCH_FILE))?;
+        tree.insert(to.to_path_buf(), node);
+        Ok(())
+    }
+
+    fn unlink(&self, path: &Path) -> Result<(), ssh2::Error> {
+        self.check("unlink", path)?;
+        match self.tree.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(status(FX_NO_SUCH_FILE)),
+        }
+    }
+
+    fn setstat(&self, path: &Path, stat: FileStat) -> Result<(), ssh2::Error> {
+        self.check("setstat", path)?;
+        match self.tree.lock().unwrap().get_mut(path) {
+            Some(Node::File { mtime, .. }) => {
+                *mtime = stat.mtime.unwrap_or(*mtime);
+                Ok(())
+            }
+            _ => Err(status(FX_NO_SUCH_FILE)),
+        }
+    }
+}
+
+fn change(path: &Path, content: &str) {
+    fs::write(path, content).unwrap();
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+}
+
+#[test]
+fn destination_urls_are_parsed() {
+    let target = SftpTarget::parse("sftp://backup@nas.local:2222/srv/backups/").unwrap();
+    assert_eq!(target.user, "backup");
+    assert_eq!(target.host, "nas.local");
+    assert_eq!(target.port, 2222);
+    assert_eq!(target.path, PathBuf::from("/srv/backups"));
+
+    let target = SftpTarget::parse("sftp://me@[::1]/~/backups").unwrap();
+    assert_eq!(target.host, "::1");
+    assert_eq!(target.port, 22);
+    assert_eq!(target.path, PathBuf::from("backups"));
+
+    for invalid in ["sftp://host", "sftp://me@host:port/x", "sftp://@host/x", "ssh://me@host/x"] {
+        assert!(matches!(SftpTarget::parse(invalid), Err(Error::InvalidArgument(_))), "{invalid}");
+    }
+}
+
+#[test]
+fn sftp_settings_need_an_sftp_destination() {
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("config.toml");
+    fs::write(&path, "[backup]\ndestination = \"/backup\"\n[backup.sftp]\nidentity_file = \"/k\"\n").unwrap();
+    assert!(matches!(Config::load(&path), Err(Error::InvalidConfig { .. })));
+
+    fs::write(&path, "[backup]\ndestination = \"sftp://me@host/b\"\n[backup.sftp]\nidentity_file = \"/k\"\n").unwrap();
+    Config::load(&path).unwrap();
+
+    let storage = SftpStorage::new("sftp://me@127.0.0.1:1/backups", &SftpOptions::default()).unwrap();
+    assert_eq!(storage.location(), Path::new("sftp://me@127.0.0.1:1/backups"));
+    assert!(matches!(storage.connect(), Err(Error::DestinationUnavailable { .. })));
+}
+
+#[test]
+fn renames_move_an_existing_target_aside_and_put_it_back_on_failure() {
+    let server = FakeServer::new();
+    let storage = server.storage();
+    storage.put(Path::new("dir/a.part"), &mut &b"new"[..]).unwrap();
+    storage.put(Path::new("dir/a"), &mut &b"old"[..]).unwrap();
+    storage.rename(Path::new("dir/a.part"), Path::new("dir/a")).unwrap();
+    assert_eq!(server.read("/srv/backups/dir/a").as_deref(), Some("new"));
+    assert!(server.read("/srv/backups/dir/a.part").is_none());
+    assert!(server.read("/srv/backups/dir/a.rbold").is_none());
+
+    // An aside left by an interrupted attempt is replaced
+    server.write("/srv/backups/dir/a.rbold", "older", 0);
+    storage.put(Path::new("dir/a.part"), &mut &b"newer"[..]).unwrap();
+    storage.rename(Path::new("dir/a.part"), Path::new("dir/a")).unwrap();
+    assert_eq!(server.read("/srv/backups/dir/a").as_deref(), Some("newer"));
+    assert!(server.read("/srv/backups/dir/a.rbold").is_none());
+
+    // If the new file cannot take the place, the old one is put back
+    storage.put(Path::new("dir/a.part"), &mut &b"newest"[..]).unwrap();
+    server.fail("rename", "/srv/backups/dir/a.part", 1, ErrorCode::SFTP(FX_FAILURE));
+    assert!(storage.rename(Path::new("dir/a.part"), Path::new("dir/a")).is_err());
+    assert_eq!(server.read("/srv/backups/dir/a").as_deref(), Some("newer"));
+    assert_eq!(server.read("/srv/backups/dir/a.part").as_deref(), Some("newest"));
+    assert!(server.read("/srv/backups/dir/a.rbold").is_none());
+}
+
+#[test]
+fn a_file_left_aside_is_restored_and_never_listed() {
+    let server = FakeServer::new();
+    let storage = server.storage();
+    storage.put(Path::new("dir/b"), &mut &b"b"[..]).unwrap();
+    // Interrupted between moving the target aside and renaming onto it
+    server.write("/srv/backups/dir/a.rbold", "old", 1_000);
+
+    let listed = storage.list(Path::new("dir")).unwrap();
+    assert_eq!(listed.iter().map(|o| o.key.clone()).collect::<Vec<_>>(), vec![PathBuf::from("dir/b")]);
+    assert!(storage.list(Path::new("missing")).unwrap().is_empty());
+
+    let info = storage.stat(Path::new("dir/a")).unwrap().unwrap();
+    assert_eq!((info.size, info.modified), (3, SystemTime::UNIX_EPOCH + Duration::from_secs(1_000)));
+    assert!(server.read("/srv/backups/dir/a.rbold").is_none());
+
+    server.write("/srv/backups/dir/c.rbold", "old", 0);
+    let mut data = String::new();
+    storage.get(Path::new("dir/c")).unwrap().read_to_string(&mut data).unwrap();
+    assert_eq!(data, "old");
+    assert!(storage.stat(Path::new("dir/missing")).unwrap().is_none());
+    assert!(storage.get(Path::new("dir/missing")).is_err());
+}
+
+#[test]
+fn locks_are_exclusive_and_stale_ones_are_taken_over() {
+    let server = FakeServer::new();
+    let storage = server.storage();
+    let held = |storage: &SftpStorage| matches!(storage.lock(Path::new(".lock")), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+
+    let lock = storage.lock(Path::new(".lock")).unwrap();
+    assert!(server.read("/srv/backups/.lock").unwrap().contains(&format!("pid = {}", std::process::id())));
+    assert!(held(&storage));
+    drop(lock);
+    assert!(server.read("/srv/backups/.lock").is_none());
+
+    // Judged by the recorded start rather than the server's clock
+    let started = chrono::Local::now() - chrono::Duration::hours(25);
+    server.write("/srv/backups/.lock", &format!("pid = 1\nstarted = \"{}\"\n", started.to_rfc3339()), now());
+    drop(storage.lock(Path::new(".lock")).unwrap());
+    let started = chrono::Local::now() - chrono::Duration::hours(1);
+    server.write("/srv/backups/.lock", &format!("pid = 1\nstarted = \"{}\"\n", started.to_rfc3339()), 0);
+    assert!(held(&storage));
+
+    // Unreadable contents fall back to the file's age
+    server.write("/srv/backups/.lock", "garbage", now());
+    assert!(held(&storage));
+    server.write("/srv/backups/.lock", "garbage", now() - 25 * 60 * 60);
+    drop(storage.lock(Path::new(".lock")).unwrap());
+    assert!(server.read("/srv/backups/.lock").is_none());
+}
+
+#[test]
+fn a_broken_session_is_replaced_by_the_next_operation() {
+    let server = FakeServer::new();
+    let storage = server.storage();
+    storage.connect().unwrap();
+    assert_eq!(server.connects.load(Ordering::SeqCst), 1);
+
+    // Errors of a request keep the session
+    assert!(storage.stat(Path::new("missing")).unwrap().is_none());
+    server.fail("unlink", "/srv/backups/a", 0, ErrorCode::SFTP(FX_FAILURE));
+    assert!(storage.delete(Path::new("a")).is_err());
+    assert_eq!(server.connects.load(Ordering::SeqCst), 1);
+
+    server.fail("create", "/srv/backups/sub/a", 0, ErrorCode::Session(SOCKET_SEND));
+    assert!(storage.put(Path::new("sub/a"), &mut &b"a"[..]).is_err());
+    storage.put(Path::new("sub/a"), &mut &b"a"[..]).unwrap();
+    assert_eq!(server.connects.load(Ordering::SeqCst), 2);
+    assert_eq!(server.read("/srv/backups/sub/a").as_deref(), Some("a"));
+
+    // The known directories are forgotten with the session and checked again
+    server.fail("stat", "/srv/backups/sub/a", 0, ErrorCode::Session(SOCKET_SEND));
+    assert!(storage.stat(Path::new("sub/a")).is_err());
+    server.tree.lock().unwrap().retain(|path, _| !path.starts_with("/srv/backups/sub"));
+    storage.put(Path::new("sub/b"), &mut &b"b"[..]).unwrap();
+    assert_eq!(server.connects.load(Ordering::SeqCst), 3);
+}
+
+/// Needs a real server, so it only runs when asked for, with
+/// `RUSTYBACKUP_SFTP_TEST_URL` naming an empty directory on it. For example
+/// with an OpenSSH container:
+///
+/// ```text
+/// docker run -d -p 2222:22 -e PUBLIC_KEY="$(cat ~/.ssh/id_ed25519.pub)" -e USER_NAME=test linuxserver/openssh-server
+/// ssh-keyscan -p 2222 127.0.0.1 >> ~/.ssh/known_hosts
+/// RUSTYBACKUP_SFTP_TEST_URL=sftp://test@127.0.0.1:2222/~/backups cargo test --test sftp -- --ignored
+/// ```
+#[test]
+#[ignore = "needs an SFTP server, see RUSTYBACKUP_SFTP_TEST_URL"]
+fn backup_history_and_vacuum_over_sftp() {
+    let url = std::env::var("RUSTYBACKUP_SFTP_TEST_URL").expect("RUSTYBACKUP_SFTP_TEST_URL is not set");
+    backup_history_and_vacuum(&url, None);
+}
+
+#[test]
+fn backup_history_and_vacuum_on_an_in_memory_server() {
+    let server = FakeServer::new();
+    backup_history_and_vacuum("sftp://test@fake/srv/backups", Some(Arc::new(server.storage())));
+}
+
+/// A backup, a second one with a change and a removal, a vacuum, and the
+/// storage operations on `url`, through `storage` if given.
+fn backup_history_and_vacuum(url: &str, storage: Option<Arc<dyn StorageBackend>>) {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("doc.txt"), "v1").unwrap();
+    fs::write(src.join("sub/old.txt"), "old").unwrap();
+    let path = tmp.path().join("config.toml");
+    fs::write(
+        &path,
+        format!(
+            "[paths]\ninclude = [{:?}]\nexclude = []\n[backup]\ndestination = {:?}\nmax_versions = 0\n",
+            src.to_string_lossy(),
+            url
+        ),
+    )
+    .unwrap();
+    let config = Config::load(&path).unwrap();
+    let folder = PathBuf::from(config.paths.include[0].proposed_folder());
+    let engine = match storage {
+        Some(storage) => BackupEngine::new(&config).with_storage(storage),
+        None => BackupEngine::new(&config),
+    };
+    let storage = engine.storage(true).unwrap();
+    let read = |key: PathBuf| {
+        let mut data = String::new();
+        storage.get(&key).unwrap().read_to_string(&mut data).unwrap();
+        data
+    };
+
+    assert_eq!(engine.backup().unwrap().files_copied, 2);
+    assert_eq!(read(folder.join("doc.txt")), "v1");
+    assert!(storage.stat(Path::new("state.toml")).unwrap().is_some());
+    assert!(storage.stat(Path::new(".lock")).unwrap().is_none());
+
+    change(&src.join("doc.txt"), "v2");
+    fs::remove_file(src.join("sub/old.txt")).unwrap();
+    let report = engine.backup().unwrap();
+    assert_eq!((report.files_copied, report.files_removed), (1, 1));
+    assert_eq!(read(folder.join("doc.txt")), "v2");
+    assert!(storage.stat(&folder.join("sub/old.txt")).unwrap().is_none());
+    let history = storage.list(Path::new("History")).unwrap();
+    assert_eq!(history.len(), 2, "{:?}", history);
+    assert!(history.iter().all(|o| !o.key.to_string_lossy().ends_with(".part")));
+
+    assert_eq!(engine.vacuum().unwrap().files_removed, 2);
+    assert!(storage.list(Path::new("History")).unwrap().is_empty());
+
+    // Renames replace an existing file without leaving the old one aside
+    storage.put(Path::new("a.part"), &mut &b"new"[..]).unwrap();
+    storage.put(Path::new("a"), &mut &b"old"[..]).unwrap();
+    storage.rename(Path::new("a.part"), Path::new("a")).unwrap();
+    assert_eq!(read(PathBuf::from("a")), "new");
+    assert!(storage.stat(Path::new("a.rbold")).unwrap().is_none());
+    storage.delete(Path::new("a")).unwrap();
+
+    let lock = storage.lock(Path::new(".lock")).unwrap();
+    let second = storage.lock(Path::new(".lock"));
+    assert!(matches!(second, Err(e) if e.kind() == std::io::ErrorKind::WouldBlock));
+    drop(lock);
+    assert!(storage.stat(Path::new(".lock")).unwrap().is_none());
+}
@@ -1,4 +1,5 @@
-use rustybackup::state::{BackupState, LatestBackup, BackupStats, load_or_init_state};
+use rustybackup::state::{BackupState, LatestBackup, BackupStats, LinkGroup, load_or_init_state};
+use rustybackup::copy::Extents;
 use chrono::Local;
 use tempfile::NamedTempFile;
 use std::path::PathBuf;
@@ -18,8 +19,19 @@ fn state_roundtrip() {
             snapshot_id: "001".into(),
             files_synced: 1,
             bytes_copied: 2,
+            logical_bytes: 2,
+            bytes_saved: 0,
             duration_ms: 3,
+            copy_methods: Default::default(),
         }],
+        jobs: Default::default(),
+        history_mode: None,
+        links: vec![LinkGroup {
+            device: 1,
+            inode: 2,
+            files: vec![PathBuf::from("docs/a"), PathBuf::from("docs/b")],
+        }],
+        sparse: [(PathBuf::from("docs/disk.img"), Extents { len: 100, data: vec![(10, 20), (50, 60)] })].into(),
     };
 
     state.save(tmp.path()).unwrap();
@@ -32,6 +44,8 @@ fn state_roundtrip() {
     assert_eq!(state.stats[0].duration_ms, loaded.stats[0].duration_ms);
     assert_eq!(state.stats[0].snapshot_id, loaded.stats[0].snapshot_id);
     assert_eq!(state.latest.timestamp.timestamp(), loaded.latest.timestamp.timestamp());
+    assert_eq!(state.links, loaded.links);
+    assert_eq!(state.sparse, loaded.sparse);
 }
 
 #[test]
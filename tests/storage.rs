@@ -0,0 +1,367 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tempfile::tempdir;
+use rustybackup::config::{BackupOptions, BackupPaths, Config, LoggingOptions};
+use rustybackup::find::FindQuery;
+use rustybackup::storage::StorageBackend;
+use rustybackup::{history, logging};
+use rustybackup::{BackupEngine, Error, MemoryStorage};
+
+fn config_for(tmp: &Path) -> Config {
+    Config {
+        paths: BackupPaths {
+            include: vec![tmp.join("src").to_string_lossy().to_string().into()],
+            exclude: vec![],
+            ..Default::default()
+        },
+        backup: BackupOptions {
+            destination: tmp.join("dest").to_string_lossy().to_string(),
+            max_versions: Some(0),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+fn change(path: &Path, content: &str) {
+    fs::write(path, content).unwrap();
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+}
+
+#[test]
+fn backup_and_vacuum_run_against_memory_storage() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("doc.txt"), "v1").unwrap();
+    fs::write(src.join("sub/old.txt"), "old").unwrap();
+    let config = config_for(tmp.path());
+    let folder = PathBuf::from(config.paths.include[0].proposed_folder());
+
+    let storage = MemoryStorage::new("memory");
+    let engine = BackupEngine::new(&config).with_storage(Arc::new(storage.clone()));
+    let report = engine.backup().unwrap();
+    assert_eq!(report.files_copied, 2);
+    assert_eq!(storage.read(folder.join("doc.txt")).unwrap(), b"v1");
+    assert_eq!(storage.read(folder.join("sub/old.txt")).unwrap(), b"old");
+    assert!(storage.read("state.toml").is_some());
+    assert!(storage.read(".incomplete").is_none());
+    // Nothing is written to the configured directory
+    assert!(!tmp.path().join("dest").exists());
+
+    change(&src.join("doc.txt"), "v2");
+    fs::remove_file(src.join("sub/old.txt")).unwrap();
+    let report = engine.backup().unwrap();
+    assert_eq!(report.files_copied, 1);
+    assert_eq!(report.files_removed, 1);
+    assert_eq!(storage.read(folder.join("doc.txt")).unwrap(), b"v2");
+    assert!(storage.read(folder.join("sub/old.txt")).is_none());
+
+    let history: Vec<PathBuf> = storage.keys().into_iter().filter(|k| k.starts_with("History")).collect();
+    assert_eq!(history.len(), 2, "{:?}", history);
+    assert!(history.iter().all(|k| !k.to_string_lossy().ends_with(".part")));
+
+    let report = engine.vacuum().unwrap();
+    assert_eq!(report.files_removed, 2);
+    assert!(report.pruned.iter().all(|p| p.starts_with("memory/History")));
+    assert!(storage.keys().iter().all(|k| !k.starts_with("History")));
+    assert!(engine.status(0).unwrap().latest.is_some());
+}
+
+#[cfg(unix)]
+#[test]
+fn hard_links_are_uploaded_once_and_linked_on_restore() {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("a.txt"), "shared").unwrap();
+    fs::hard_link(src.join("a.txt"), src.join("sub/b.txt")).unwrap();
+    let config = config_for(tmp.path());
+    let folder = PathBuf::from(config.paths.include[0].proposed_folder());
+
+    let storage = MemoryStorage::new("memory");
+    let engine = BackupEngine::new(&config).with_storage(Arc::new(storage.clone()));
+    let report = engine.backup().unwrap();
+    assert_eq!((report.files_copied, report.files_linked), (1, 1));
+    // Either name may be walked first and hold the data
+    let (first, other) = match storage.read(folder.join("a.txt")) {
+        Some(_) => ("a.txt", "sub/b.txt"),
+        None => ("sub/b.txt", "a.txt"),
+    };
+    assert_eq!(storage.read(folder.join(first)).unwrap(), b"shared");
+    assert!(storage.read(folder.join(other)).is_none());
+
+    // The unstored name is not taken for a missing file by the next run
+    let report = engine.backup().unwrap();
+    assert_eq!((report.files_copied, report.files_linked), (0, 0));
+
+    let target = tmp.path().join("restored");
+    let report = engine.restore(&target, None).unwrap();
+    assert_eq!((report.files_restored, report.files_linked), (1, 1));
+    let inode = |p: &Path| fs::metadata(p).unwrap().ino();
+    assert_eq!(inode(&target.join(&folder).join("a.txt")), inode(&target.join(&folder).join("sub/b.txt")));
+    assert_eq!(fs::read_to_string(target.join(&folder).join("sub/b.txt")).unwrap(), "shared");
+    // Restoring never overwrites files
+    assert!(engine.restore(&target, None).is_err());
+
+    // Once the name holding the data is gone, the other one is stored itself
+    fs::remove_file(src.join(first)).unwrap();
+    let report = engine.backup().unwrap();
+    assert_eq!((report.files_copied, report.files_removed), (1, 1));
+    assert_eq!(storage.read(folder.join(other)).unwrap(), b"shared");
+}
+
+#[cfg(unix)]
+#[test]
+fn restoring_one_root_fetches_links_held_by_another() {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp = tempdir().unwrap();
+    let (one, two) = (tmp.path().join("one"), tmp.path().join("two"));
+    fs::create_dir_all(&one).unwrap();
+    fs::create_dir_all(&two).unwrap();
+    fs::write(one.join("a.txt"), "shared").unwrap();
+    fs::hard_link(one.join("a.txt"), two.join("b.txt")).unwrap();
+    fs::hard_link(one.join("a.txt"), two.join("c.txt")).unwrap();
+    let mut config = config_for(tmp.path());
+    config.paths.include = vec![one.to_string_lossy().to_string().into(), two.to_string_lossy().to_string().into()];
+    let folder = PathBuf::from(config.paths.include[1].proposed_folder());
+
+    let storage = MemoryStorage::new("memory");
+    let engine = BackupEngine::new(&config).with_storage(Arc::new(storage.clone()));
+    let report = engine.backup().unwrap();
+    assert_eq!((report.files_copied, report.files_linked), (1, 2));
+    // The first root is walked first and holds the data
+    assert!(storage.read(folder.join("b.txt")).is_none());
+    assert!(storage.read(folder.join("c.txt")).is_none());
+
+    let target = tmp.path().join("restored");
+    let report = engine.restore(&target, Some(two.to_str().unwrap())).unwrap();
+    assert_eq!((report.files_restored, report.files_linked), (1, 1));
+    let (b, c) = (target.join(&folder).join("b.txt"), target.join(&folder).join("c.txt"));
+    assert_eq!(fs::read_to_string(&b).unwrap(), "shared");
+    assert_eq!(fs::metadata(&b).unwrap().ino(), fs::metadata(&c).unwrap().ino());
+    assert!(!target.join(config.paths.include[0].proposed_folder()).exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn sparse_files_are_stored_without_holes_and_restored_with_them() {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    let image = src.join("disk.img");
+    let len = 16 * 1024 * 1024;
+    let mut file = fs::File::create(&image).unwrap();
+    file.set_len(len).unwrap();
+    file.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+    file.write_all(&[7u8; 4096]).unwrap();
+    drop(file);
+    let allocated = |p: &Path| fs::metadata(p).unwrap().blocks() * 512;
+    if allocated(&image) >= len {
+        // The temp directory's file system cannot hold holes
+        return;
+    }
+    let config = config_for(tmp.path());
+    let key = PathBuf::from(config.paths.include[0].proposed_folder()).join("disk.img");
+
+    let storage = MemoryStorage::new("memory");
+    let engine = BackupEngine::new(&config).with_storage(Arc::new(storage.clone()));
+    let report = engine.backup().unwrap();
+    assert_eq!(report.logical_bytes, len);
+    assert!(report.bytes_copied < len / 4, "copied {} bytes", report.bytes_copied);
+    assert_eq!(storage.read(&key).unwrap().len() as u64, report.bytes_copied);
+
+    let found = engine.find(&FindQuery::default()).unwrap();
+    assert_eq!(found[0].size, len);
+    let mut content = Vec::new();
+    history::read_stored(&storage, &key, &mut content).unwrap();
+    assert_eq!(content, fs::read(&image).unwrap());
+
+    let target = tmp.path().join("restored");
+    engine.restore(&target, None).unwrap();
+    let restored = target.join(&key);
+    assert_eq!(fs::read(&restored).unwrap(), content);
+    assert!(allocated(&restored) < len / 4);
+
+    // The extents move to History with the stored data
+    change(&image, "no longer sparse");
+    engine.backup().unwrap();
+    let versions = engine.find(&FindQuery::default()).unwrap();
+    let archived = versions.iter().find(|v| v.archived.is_some()).unwrap();
+    assert_eq!(archived.size, len);
+    let archived_key = archived.stored.strip_prefix("memory").unwrap();
+    let mut content = Vec::new();
+    history::read_stored(&storage, archived_key, &mut content).unwrap();
+    assert_eq!(content.len() as u64, len);
+}
+
+#[test]
+fn a_second_run_on_a_locked_destination_fails() {
+    let tmp = tempdir().unwrap();
+    fs::create_dir_all(tmp.path().join("src")).unwrap();
+    fs::write(tmp.path().join("src/a.txt"), "a").unwrap();
+    let config = config_for(tmp.path());
+
+    let storage = MemoryStorage::new("memory");
+    let lock = storage.lock(Path::new(".lock")).unwrap();
+    let engine = BackupEngine::new(&config).with_storage(Arc::new(storage.clone()));
+    let err = engine.backup().unwrap_err();
+    assert!(matches!(err, Error::DestinationLocked { .. }), "{err:?}");
+    assert!(storage.keys().is_empty());
+
+    drop(lock);
+    engine.backup().unwrap();
+}
+
+#[test]
+fn snapshots_need_a_local_destination() {
+    let tmp = tempdir().unwrap();
+    fs::create_dir_all(tmp.path().join("src")).unwrap();
+    let mut config = config_for(tmp.path());
+    config.backup.history_mode = Some(rustybackup::HistoryMode::HardlinkSnapshots);
+
+    let engine = BackupEngine::new(&config).with_storage(Arc::new(MemoryStorage::new("memory")));
+    assert!(matches!(engine.backup(), Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn find_and_cat_read_through_the_storage() {
+    let tmp = tempdir().unwrap();
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("doc.txt"), "v1").unwrap();
+    let config = config_for(tmp.path());
+
+    let storage = MemoryStorage::new("memory");
+    let engine = BackupEngine::new(&config).with_storage(Arc::new(storage.clone()));
+    engine.backup().unwrap();
+    change(&src.join("doc.txt"), "v2");
+    engine.backup().unwrap();
+
+    let found = engine.find(&FindQuery::default()).unwrap();
+    assert_eq!(found.len(), 2, "{:?}", found);
+    assert!(found.iter().all(|v| v.stored.starts_with("memory") && v.source == src.join("doc.txt")));
+    let mut contents = Vec::new();
+    for version in &found {
+        let key = version.stored.strip_prefix("memory").unwrap();
+        let mut data = Vec::new();
+        history::read_stored(&storage, key, &mut data).unwrap();
+        contents.push(String::from_utf8(data).unwrap());
+    }
+    assert_eq!(contents, ["v2", "v1"]);
+}
+
+#[test]
+fn run_logs_are_rotated_in_the_storage() {
+    let storage = MemoryStorage::new("memory");
+    for id in 1..=4 {
+        storage.put(&Path::new("logs").join(format!("{id}.log")), &mut &b"line"[..]).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    storage.put(Path::new("logs/nightly/1.log"), &mut &b"job"[..]).unwrap();
+    let options = LoggingOptions {
+        keep_runs: Some(2),
+        ..Default::default()
+    };
+    assert_eq!(logging::rotate_logs(&storage, None, &options).unwrap(), 2);
+    let keys = storage.keys();
+    assert!(keys.contains(&PathBuf::from("logs/4.log")) && keys.contains(&PathBuf::from("logs/nightly/1.log")));
+    assert!(!keys.contains(&PathBuf::from("logs/1.log")));
+    assert_eq!(logging::tail(&storage, None, 4, 5).unwrap(), ["line"]);
+    assert!(logging::tail(&storage, None, 9, 5).unwrap().is_empty());
+}
+
+/// Memory storage whose `stat` always fails, and optionally its `list`.
+struct FlakyStorage {
+    inner: MemoryStorage,
+    fail_list: bool,
+}
+
+impl StorageBackend for FlakyStorage {
+    fn location(&self) -> &Path {
+        self.inner.location()
+    }
+    fn put(&self, key: &Path, data: &mut dyn std::io::Read) -> std::io::Result<u64> {
+        self.inner.put(key, data)
+    }
+    fn get(&self, key: &Path) -> std::io::Result<Box<dyn std::io::Read + Send>> {
+        self.inner.get(key)
+    }
+    fn stat(&self, _key: &Path) -> std::io::Result<Option<rustybackup::storage::ObjectInfo>> {
+        Err(std::io::Error::from(std::io::ErrorKind::TimedOut))
+    }
+    fn list(&self, prefix: &Path) -> std::io::Result<Vec<rustybackup::storage::ObjectInfo>> {
+        if self.fail_list {
+            return Err(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        }
+        self.inner.list(prefix)
+    }
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        self.inner.rename(from, to)
+    }
+    fn delete(&self, key: &Path) -> std::io::Result<()> {
+        self.inner.delete(key)
+    }
+    fn lock(&self, key: &Path) -> std::io::Result<rustybackup::storage::StorageLock> {
+        self.inner.lock(key)
+    }
+}
+
+#[test]
+fn modification_filters_are_refused_where_the_upload_time_is_kept() {
+    let tmp = tempdir().unwrap();
+    fs::create_dir_all(tmp.path().join("src")).unwrap();
+    fs::write(tmp.path().join("src/a.txt"), "a").unwrap();
+    let config = config_for(tmp.path());
+    let storage = MemoryStorage::new("memory");
+    BackupEngine::new(&config).with_storage(Arc::new(storage.clone())).backup().unwrap();
+
+    // Like S3, this backend does not override `keeps_modified`
+    let flaky = FlakyStorage { inner: storage, fail_list: false };
+    let engine = BackupEngine::new(&config).with_storage(Arc::new(flaky));
+    let found = engine.find(&FindQuery::default()).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].backed_up, found[0].modified);
+    let query = FindQuery {
+        modified_after: Some(rustybackup::find::parse_date("2020-01-01").unwrap()),
+        ..Default::default()
+    };
+    let err = engine.find(&query).unwrap_err();
+    assert!(matches!(err, Error::InvalidArgument(_)), "{err:?}");
+}
+
+#[test]
+fn fullscan_lists_the_storage_once_and_reports_errors() {
+    let tmp = tempdir().unwrap();
+    fs::create_dir_all(tmp.path().join("src")).unwrap();
+    fs::write(tmp.path().join("src/a.txt"), "a").unwrap();
+    fs::write(tmp.path().join("src/b.txt"), "b").unwrap();
+    let config = config_for(tmp.path());
+    let storage = MemoryStorage::new("memory");
+    BackupEngine::new(&config).with_storage(Arc::new(storage.clone())).backup().unwrap();
+    storage.delete(&PathBuf::from(config.paths.include[0].proposed_folder()).join("b.txt")).unwrap();
+
+    // Stored files are found in the listing without a `stat` per file
+    let flaky = FlakyStorage { inner: storage.clone(), fail_list: false };
+    let report = BackupEngine::new(&config).with_storage(Arc::new(flaky)).scan(true).unwrap();
+    let changed: Vec<PathBuf> = report.files.iter().map(|f| f.path.clone()).collect();
+    assert_eq!(changed, [tmp.path().join("src/b.txt")]);
+
+    // A failed listing is an error, not a reason to send everything again
+    let flaky = FlakyStorage { inner: storage, fail_list: true };
+    let err = BackupEngine::new(&config).with_storage(Arc::new(flaky)).scan(true).unwrap_err();
+    assert!(matches!(err, Error::Io { .. }), "{err:?}");
+}